@@ -0,0 +1,3 @@
+-- Files uploaded before the introduction of the segmented format
+-- were encrypted in a single AES-GCM operation (format version 1).
+ALTER TABLE uploaded_files ADD COLUMN format_version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,15 @@
+-- Resumable uploads (tus) that have been created but not necessarily completed yet.
+-- Once an upload completes, efd_sha256sum is filled in and the row is kept until it expires
+-- so that clients can still learn the file's hash should the final response get lost.
+CREATE TABLE IF NOT EXISTS upload_sessions
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  upload_id_sha256sum TEXT,
+  admin_key_sha256sum TEXT,
+  upload_metadata TEXT,
+  upload_length INTEGER,
+  upload_ip TEXT,
+  created_ts TEXT,
+  expiry_ts TEXT,
+  efd_sha256sum TEXT
+) STRICT;
@@ -0,0 +1,8 @@
+-- Bundles group several files under a single link.
+-- The bundle itself is a parent row without any filedata of its own. Its efd_sha256sum
+-- is a random identifier and its filesize the sum of all its members' filesizes.
+-- Members are regular rows referencing their bundle through parent_id.
+ALTER TABLE uploaded_files ADD COLUMN parent_id INTEGER REFERENCES uploaded_files(id);
+ALTER TABLE uploaded_files ADD COLUMN is_bundle INTEGER NOT NULL DEFAULT 0;
+-- Resumable uploads may be destined to become members of a bundle.
+ALTER TABLE upload_sessions ADD COLUMN parent_id INTEGER;
@@ -0,0 +1,4 @@
+-- Optional limit on how often a file may be downloaded before it is removed.
+-- NULL means the file can be downloaded any number of times until it expires.
+-- Bundles store the limit that applies to each of their members.
+ALTER TABLE uploaded_files ADD COLUMN max_downloads INTEGER;
@@ -0,0 +1,6 @@
+-- Optional passphrase protecting a file on top of its link key.
+-- The link then holds the file key wrapped with a key derived from the passphrase using
+-- PBKDF2-HMAC-SHA256, with the salt and iteration count stored here. Both are NULL without one.
+-- Members of a bundle are covered by their bundle's passphrase and always store NULL.
+ALTER TABLE uploaded_files ADD COLUMN kdf_salt BLOB;
+ALTER TABLE uploaded_files ADD COLUMN kdf_iterations INTEGER;
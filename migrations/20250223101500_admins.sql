@@ -0,0 +1,14 @@
+-- Individual accounts for site-wide administrators, replacing the single shared password.
+-- The role is one of 'viewer', 'deleter' or 'owner', each including the rights of the former.
+CREATE TABLE IF NOT EXISTS admins
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  username TEXT NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL,
+  role TEXT NOT NULL,
+  created_ts TEXT NOT NULL
+) STRICT;
+-- Sessions now belong to an admin and end along with their account.
+-- Existing sessions can't be attributed to anyone, so they are logged out.
+DELETE FROM admin_sessions;
+ALTER TABLE admin_sessions ADD COLUMN admin_id INTEGER REFERENCES admins(id) ON DELETE CASCADE;
@@ -0,0 +1,15 @@
+-- Optional second factor for admin logins using time-based one-time passwords (RFC 6238).
+-- totp_secret is NULL unless enabled, while totp_pending_secret holds a secret awaiting
+-- confirmation. totp_last_step is the last time-step a code was used for, so no code works twice.
+ALTER TABLE admins ADD COLUMN totp_secret BLOB;
+ALTER TABLE admins ADD COLUMN totp_pending_secret BLOB;
+ALTER TABLE admins ADD COLUMN totp_last_step INTEGER;
+-- Single-use recovery codes in case the authenticator gets lost, stored as sha256sums.
+CREATE TABLE IF NOT EXISTS admin_recovery_codes
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  admin_id INTEGER NOT NULL REFERENCES admins(id) ON DELETE CASCADE,
+  code_sha256sum TEXT NOT NULL
+) STRICT;
+-- Sessions of admins with TOTP enabled remain pending until the second factor has been checked.
+ALTER TABLE admin_sessions ADD COLUMN second_factor_pending INTEGER NOT NULL DEFAULT 0;
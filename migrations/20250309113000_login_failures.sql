@@ -0,0 +1,11 @@
+-- Failed admin logins, counted per IpPrefix and once more across all clients under 'global'.
+-- The counter restarts once the last failure lies a day in the past.
+-- Exceeding the free attempts locks the client out until locked_until_ts.
+CREATE TABLE IF NOT EXISTS login_failures
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  client TEXT NOT NULL UNIQUE,
+  failures INTEGER NOT NULL,
+  last_failure_ts TEXT NOT NULL,
+  locked_until_ts TEXT
+) STRICT;
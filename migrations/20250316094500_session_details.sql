@@ -0,0 +1,6 @@
+-- Details shown in the dashboard's session list, so that admins can tell their sessions apart
+-- and revoke the ones they don't recognize. Older sessions simply lack them.
+ALTER TABLE admin_sessions ADD COLUMN created_ts TEXT;
+ALTER TABLE admin_sessions ADD COLUMN last_seen_ts TEXT;
+ALTER TABLE admin_sessions ADD COLUMN ip_prefix TEXT;
+ALTER TABLE admin_sessions ADD COLUMN user_agent TEXT;
@@ -0,0 +1,15 @@
+-- Reports of illegal or abusive content, filed by recipients of a download link.
+-- Reports stay 'open' until an admin sets them to 'dismissed' or 'taken_down',
+-- after which they're kept for a while as a record of how the notice was handled.
+CREATE TABLE IF NOT EXISTS abuse_reports
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  efd_sha256sum TEXT NOT NULL,
+  category TEXT NOT NULL,
+  description TEXT NOT NULL,
+  reporter_ip TEXT NOT NULL,
+  report_ts TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'open',
+  resolved_ts TEXT,
+  resolved_by TEXT
+) STRICT;
@@ -0,0 +1,27 @@
+-- Append-only record of administrative actions and file lifecycle events.
+-- The actor is either 'admin', identified by username and session, 'uploader',
+-- authorized by the per-file admin key, or 'system' for automatic cleanups.
+CREATE TABLE IF NOT EXISTS audit_events
+(
+  id INTEGER PRIMARY KEY NOT NULL,
+  event_ts TEXT NOT NULL,
+  event TEXT NOT NULL,
+  actor_kind TEXT NOT NULL,
+  admin_username TEXT,
+  session_id INTEGER,
+  target_hash TEXT,
+  details TEXT
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS audit_events_target_hash ON audit_events (target_hash);
+
+-- Neither the application nor a stray query may rewrite history.
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+  SELECT RAISE(ABORT, 'audit events are append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+  SELECT RAISE(ABORT, 'audit events are append-only');
+END;
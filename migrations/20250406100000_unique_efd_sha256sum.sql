@@ -0,0 +1,5 @@
+-- Filedata is stored under its efd_sha256sum, so two rows with the same one would share
+-- a single object and deleting either would remove the other's data as well.
+-- Should identical ciphertext have been uploaded more than once, only the oldest row is kept.
+DELETE FROM uploaded_files WHERE id NOT IN (SELECT MIN(id) FROM uploaded_files GROUP BY efd_sha256sum);
+CREATE UNIQUE INDEX IF NOT EXISTS uploaded_files_efd_sha256sum ON uploaded_files (efd_sha256sum);
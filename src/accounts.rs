@@ -0,0 +1,344 @@
+//! Accounts of site-wide administrators, their roles and the subcommands managing them
+
+use std::process::ExitCode;
+
+use anyhow::anyhow;
+use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use chrono::Utc;
+use clap::{Subcommand, ValueEnum};
+use inquire::Password;
+use serde::Serialize;
+use sqlx::{migrate::MigrateDatabase, FromRow, Sqlite, SqlitePool};
+
+use crate::*;
+
+/// What an administrator is allowed to do, each role including the rights of the former
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    /// May view the dashboard and its statistics
+    Viewer,
+    /// May additionally delete files before they expire
+    Deleter,
+    /// May additionally see all other administrators
+    Owner,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Role::Viewer => "viewer",
+            Role::Deleter => "deleter",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+/// Username of the account created from the 'admin_password_hash' in the config.toml
+const INITIAL_ADMIN_USERNAME: &str = "admin";
+
+/// Management of the administrator accounts, performed directly on the database
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// List all administrators and their roles
+    List,
+
+    /// Add a new administrator, prompting for their password
+    Add {
+        username: String,
+
+        /// What the administrator is allowed to do
+        #[arg(long, value_enum, default_value_t = Role::Viewer)]
+        role: Role,
+    },
+
+    /// Remove an administrator, ending all of their sessions
+    Remove { username: String },
+
+    /// Set a new password for an administrator, ending all of their sessions
+    ResetPassword { username: String },
+
+    /// Change what an administrator is allowed to do
+    SetRole {
+        username: String,
+
+        #[arg(value_enum)]
+        role: Role,
+    },
+
+    /// Require a TOTP code on login, printing the secret to set up an authenticator app with
+    ///
+    /// Any previous secret and recovery codes are replaced.
+    TotpEnable {
+        username: String,
+
+        /// Name the authenticator app lists the account under, usually the app name
+        #[arg(long, default_value = "FerriShare")]
+        issuer: String,
+    },
+
+    /// Stop requiring a TOTP code on login, e.g. after the authenticator was lost
+    TotpDisable { username: String },
+}
+
+/// Run one of the admin subcommands and report any error on stderr.
+pub async fn run(command: AdminCommand) -> ExitCode {
+    let result = match open_database().await {
+        Ok(db) => match command {
+            AdminCommand::List => list(&db).await,
+            AdminCommand::Add { username, role } => add(&db, &username, role).await,
+            AdminCommand::Remove { username } => remove(&db, &username).await,
+            AdminCommand::ResetPassword { username } => reset_password(&db, &username).await,
+            AdminCommand::SetRole { username, role } => set_role(&db, &username, role).await,
+            AdminCommand::TotpEnable { username, issuer } => {
+                totp_enable(&db, &username, &issuer).await
+            }
+            AdminCommand::TotpDisable { username } => totp_disable(&db, &username).await,
+        },
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Hash a password with argon2id for storage in the database.
+///
+/// Uses 32MB of memory and 4 iterations. That's a little stronger than the default parameters.
+pub fn hash_password(password: &str) -> Result<String, anyhow::Error> {
+    Ok(Argon2::new(
+        argon2::Algorithm::default(),
+        argon2::Version::default(),
+        argon2::Params::new(32768, 4, 1, None).map_err(|e| anyhow!(e.to_string()))?,
+    )
+    .hash_password(
+        password.as_bytes(),
+        &SaltString::generate(&mut argon2::password_hash::rand_core::OsRng),
+    )
+    .map_err(|e| anyhow!(e.to_string()))?
+    .to_string())
+}
+
+/// Create the first administrator from the config's password hash if there are none yet.
+///
+/// This carries the single shared password of older installations over
+/// into an owner account, which can then be used to log in as before.
+pub async fn seed_initial_admin(aps: &AppState) -> Result<(), anyhow::Error> {
+    let admin_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM admins;")
+        .fetch_one(&aps.db)
+        .await?;
+    if admin_count > 0 {
+        return Ok(());
+    }
+
+    match &aps.conf().admin_password_hash {
+        Some(password_hash) => {
+            sqlx::query(
+                "INSERT INTO admins (username, password_hash, role, created_ts) VALUES (?, ?, ?, ?);",
+            )
+            .bind(INITIAL_ADMIN_USERNAME)
+            .bind(password_hash)
+            .bind(Role::Owner)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&aps.db)
+            .await?;
+            tracing::info!(
+                "created owner account '{INITIAL_ADMIN_USERNAME}' from the configured admin password"
+            );
+        }
+        None => {
+            tracing::warn!("no administrators exist yet, add one with the 'admin add' subcommand");
+        }
+    }
+
+    Ok(())
+}
+
+/// Open the database for the admin subcommands, creating and migrating it as required.
+async fn open_database() -> Result<SqlitePool, anyhow::Error> {
+    std::fs::create_dir_all(DATA_PATH)?;
+    if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
+        Sqlite::create_database(DB_URL).await?;
+    }
+    let db = SqlitePool::connect(DB_URL).await?;
+    sqlx::migrate!("./migrations").run(&db).await?;
+    Ok(db)
+}
+
+/// Prompt for a new password, asking for it twice to rule out typos.
+fn prompt_password(username: &str) -> Result<String, anyhow::Error> {
+    Ok(Password::new(&format!("Password for '{username}':"))
+        .with_display_mode(inquire::PasswordDisplayMode::Masked)
+        .with_validator(inquire::validator::MinLengthValidator::new(1))
+        .prompt()?)
+}
+
+/// Look up the id of an administrator, failing if they don't exist.
+async fn existing_id(db: &SqlitePool, username: &str) -> Result<i64, anyhow::Error> {
+    sqlx::query_scalar("SELECT id FROM admins WHERE username = ?;")
+        .bind(username)
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| anyhow!("there is no administrator named '{username}'"))
+}
+
+/// Look up the role of an administrator, failing if they don't exist.
+async fn existing_role(db: &SqlitePool, username: &str) -> Result<Role, anyhow::Error> {
+    sqlx::query_scalar("SELECT role FROM admins WHERE username = ?;")
+        .bind(username)
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| anyhow!("there is no administrator named '{username}'"))
+}
+
+/// Make sure an owner remains after the given administrator stops being one.
+async fn ensure_other_owner(db: &SqlitePool, username: &str) -> Result<(), anyhow::Error> {
+    let other_owners: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM admins WHERE role = ? AND username != ?;")
+            .bind(Role::Owner)
+            .bind(username)
+            .fetch_one(db)
+            .await?;
+    if other_owners == 0 {
+        return Err(anyhow!("'{username}' is the last remaining owner"));
+    }
+    Ok(())
+}
+
+async fn list(db: &SqlitePool) -> Result<(), anyhow::Error> {
+    #[derive(FromRow)]
+    struct AdminRow {
+        username: String,
+        role: Role,
+        created_ts: String,
+    }
+
+    let admins: Vec<AdminRow> =
+        sqlx::query_as("SELECT username, role, created_ts FROM admins ORDER BY username;")
+            .fetch_all(db)
+            .await?;
+
+    if admins.is_empty() {
+        eprintln!("There are no administrators yet.");
+    }
+    for admin in admins {
+        println!(
+            "{:<24} {:<8} created {}",
+            admin.username, admin.role, admin.created_ts
+        );
+    }
+    Ok(())
+}
+
+async fn add(db: &SqlitePool, username: &str, role: Role) -> Result<(), anyhow::Error> {
+    if username.is_empty() || username.chars().any(char::is_whitespace) {
+        return Err(anyhow!("usernames must not be empty or contain whitespace"));
+    }
+    if existing_role(db, username).await.is_ok() {
+        return Err(anyhow!(
+            "there already is an administrator named '{username}'"
+        ));
+    }
+
+    let password_hash = hash_password(&prompt_password(username)?)?;
+    sqlx::query(
+        "INSERT INTO admins (username, password_hash, role, created_ts) VALUES (?, ?, ?, ?);",
+    )
+    .bind(username)
+    .bind(&password_hash)
+    .bind(role)
+    .bind(Utc::now().to_rfc3339())
+    .execute(db)
+    .await?;
+
+    eprintln!("Added {role} '{username}'.");
+    Ok(())
+}
+
+async fn remove(db: &SqlitePool, username: &str) -> Result<(), anyhow::Error> {
+    if existing_role(db, username).await? == Role::Owner {
+        ensure_other_owner(db, username).await?;
+    }
+
+    // Sessions are removed along with the account through the foreign key.
+    sqlx::query("DELETE FROM admins WHERE username = ?;")
+        .bind(username)
+        .execute(db)
+        .await?;
+
+    eprintln!("Removed '{username}'.");
+    Ok(())
+}
+
+async fn reset_password(db: &SqlitePool, username: &str) -> Result<(), anyhow::Error> {
+    existing_role(db, username).await?;
+
+    let password_hash = hash_password(&prompt_password(username)?)?;
+    sqlx::query("UPDATE admins SET password_hash = ? WHERE username = ?;")
+        .bind(&password_hash)
+        .bind(username)
+        .execute(db)
+        .await?;
+
+    // Whoever knew the old password must not stay logged in.
+    let sessions = sqlx::query(
+        "DELETE FROM admin_sessions WHERE admin_id = (SELECT id FROM admins WHERE username = ?);",
+    )
+    .bind(username)
+    .execute(db)
+    .await?;
+
+    eprintln!(
+        "Changed the password of '{username}' and ended {} session(s).",
+        sessions.rows_affected()
+    );
+    Ok(())
+}
+
+async fn set_role(db: &SqlitePool, username: &str, role: Role) -> Result<(), anyhow::Error> {
+    if existing_role(db, username).await? == Role::Owner && role != Role::Owner {
+        ensure_other_owner(db, username).await?;
+    }
+
+    sqlx::query("UPDATE admins SET role = ? WHERE username = ?;")
+        .bind(role)
+        .bind(username)
+        .execute(db)
+        .await?;
+
+    eprintln!("'{username}' is now a {role}.");
+    Ok(())
+}
+
+async fn totp_enable(db: &SqlitePool, username: &str, issuer: &str) -> Result<(), anyhow::Error> {
+    let admin_id = existing_id(db, username).await?;
+
+    // The operator is trusted to pass the secret on, so it takes effect without confirmation.
+    let secret = totp::generate_secret();
+    let recovery_codes = totp::enable(db, admin_id, &secret, None).await?;
+    let uri = totp::otpauth_uri(issuer, username, &secret);
+
+    println!("{}", totp::qr_terminal(&uri)?);
+    println!("Secret:   {}", totp::encode_secret(&secret));
+    println!("URI:      {uri}");
+    println!("\nRecovery codes, each usable once in place of a code:");
+    for code in recovery_codes {
+        println!("  {code}");
+    }
+    eprintln!("\nEnabled TOTP for '{username}'.");
+    Ok(())
+}
+
+async fn totp_disable(db: &SqlitePool, username: &str) -> Result<(), anyhow::Error> {
+    let admin_id = existing_id(db, username).await?;
+    totp::disable(db, admin_id).await?;
+
+    eprintln!("Disabled TOTP for '{username}'.");
+    Ok(())
+}
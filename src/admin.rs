@@ -1,11 +1,12 @@
 //! Handlers and endpoints for site-wide adminstration, including login, logout and dashboard
 
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, str::FromStr, sync::LazyLock};
 
 use argon2::{password_hash::PasswordVerifier, Argon2, PasswordHash};
 use axum::{
     extract::{Query, State},
-    response::{Html, IntoResponse, Redirect},
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
 use axum_extra::extract::CookieJar;
@@ -16,33 +17,96 @@ use minify_html::minify;
 use rand::{prelude::*, rng};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use sqlx::FromRow;
+use sqlx::{FromRow, QueryBuilder, Sqlite};
 
+use crate::accounts::{hash_password, Role};
+use crate::audit::{Actor, AuditEvent};
 use crate::download::pretty_print_delta;
 use crate::*;
 
-/// Handler for the site-wide administration page, serving the login form or admin dashboard.
-pub async fn admin_page(
-    Query(params): Query<HashMap<String, String>>,
-    State(aps): State<AppState>,
-    jar: CookieJar,
-) -> Result<impl IntoResponse, AppError> {
-    // Calculate the base64url-encoded sha256sum of the session cookie, if any.
-    let user_session_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
+/// The administrator a request's session belongs to
+#[derive(Debug, FromRow)]
+pub struct AdminSession {
+    pub session_id: i64,
+    pub admin_id: i64,
+    pub username: String,
+    pub role: Role,
+    pub expiry_ts: String,
+    pub second_factor_pending: bool,
+}
+
+/// Calculate the base64url-encoded sha256sum of the session cookie, if any.
+fn session_sha256sum(jar: &CookieJar) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(
         URL_SAFE_NO_PAD
             .decode(jar.get("id").map_or("", |e| e.value()))
             .unwrap_or_default(),
-    ));
+    ))
+}
 
-    let session_expiry: Option<String> = sqlx::query_scalar(
-        "SELECT expiry_ts FROM admin_sessions WHERE session_id_sha256sum = ? LIMIT 1;",
+/// Look up the session of the request's session cookie, even if it awaits the second factor.
+///
+/// Returns None if there is no session cookie or its session does not exist or has expired.
+async fn find_session(aps: &AppState, jar: &CookieJar) -> Result<Option<AdminSession>, AppError> {
+    let session: Option<AdminSession> = sqlx::query_as(
+        "SELECT s.id AS session_id, a.id AS admin_id, a.username, a.role, s.expiry_ts, s.second_factor_pending FROM admin_sessions s JOIN admins a ON a.id = s.admin_id WHERE s.session_id_sha256sum = ? LIMIT 1;",
     )
-    .bind(&user_session_sha256sum)
+    .bind(session_sha256sum(jar))
     .fetch_optional(&aps.db)
     .await?;
 
+    match session {
+        Some(v) if !has_expired(&v.expiry_ts)? => Ok(Some(v)),
+        _ => Ok(None),
+    }
+}
+
+/// Look up the administrator that is logged in with the request's session cookie.
+///
+/// Sessions that still await the second factor don't count as logged in.
+/// Every successful lookup counts as activity and updates the session's last-seen timestamp.
+pub async fn current_admin(
+    aps: &AppState,
+    jar: &CookieJar,
+) -> Result<Option<AdminSession>, AppError> {
+    let session = find_session(aps, jar)
+        .await?
+        .filter(|v| !v.second_factor_pending);
+
+    if let Some(ref session) = session {
+        sqlx::query("UPDATE admin_sessions SET last_seen_ts = ? WHERE id = ?;")
+            .bind(Utc::now().to_rfc3339())
+            .bind(session.session_id)
+            .execute(&aps.db)
+            .await?;
+    }
+
+    Ok(session)
+}
+
+/// Handler for the site-wide administration page, serving the login form or admin dashboard.
+pub async fn admin_page(
+    Query(params): Query<HashMap<String, String>>,
+    State(aps): State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, AppError> {
     // Only show the admin page if the session exists and has not yet expired.
-    if !session_expiry.map_or(Ok(true), |v| has_expired(&v))? {
+    if let Some(admin) = current_admin(&aps, &jar).await? {
+        let listing = FileListing::from_params(&params);
+
+        // The statistics cover all live files, regardless of the current filters.
+        let (full_file_count, used_quota): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(filesize), 0) FROM uploaded_files WHERE parent_id IS NULL AND julianday(expiry_ts) > julianday('now');",
+        )
+        .fetch_one(&aps.db)
+        .await?;
+
+        let mut count_query = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM uploaded_files f");
+        listing.push_filters(&mut count_query);
+        let matching_file_count: i64 = count_query.build_query_scalar().fetch_one(&aps.db).await?;
+        let page_count = ((matching_file_count + FILES_PER_PAGE - 1) / FILES_PER_PAGE).max(1);
+        let page = listing.page.min(page_count);
+
         #[derive(FromRow)]
         struct FileRow {
             efd_sha256sum: String,
@@ -51,23 +115,28 @@ pub async fn admin_page(
             upload_ts: String,
             expiry_ts: String,
             downloads: i64,
+            is_bundle: bool,
+            member_count: i64,
+            max_downloads: Option<i64>,
         }
-        // Request info about all currently live files.
-        let all_files: Vec<FileRow> = sqlx::query_as(
-            "SELECT efd_sha256sum, filesize, upload_ip, upload_ts, expiry_ts, downloads FROM uploaded_files;",
-        )
-        .fetch_all(&aps.db)
-        .await?;
-
-        // Filter out files that have technically expired but were not yet cleaned up by the
-        // automatic cleanup task.
-        let all_files = all_files
-            .into_iter()
-            .filter(|e| !has_expired(&e.expiry_ts).unwrap_or(false))
-            .collect::<Vec<_>>();
-
-        // Determine how much storage space all uploaded files currently use.
-        let used_quota: u64 = all_files.iter().map(|e| e.filesize as u64).sum();
+        // Request a single page of the live files matching the filters.
+        // Bundles are listed as a whole, summing up the downloads of their members.
+        let mut files_query = QueryBuilder::<Sqlite>::new(
+            "SELECT f.efd_sha256sum, f.filesize, f.upload_ip, f.upload_ts, f.expiry_ts, CASE WHEN f.is_bundle THEN (SELECT COALESCE(SUM(m.downloads), 0) FROM uploaded_files m WHERE m.parent_id = f.id) ELSE f.downloads END AS downloads, f.is_bundle, (SELECT COUNT(*) FROM uploaded_files m WHERE m.parent_id = f.id) AS member_count, f.max_downloads FROM uploaded_files f",
+        );
+        listing.push_filters(&mut files_query);
+        files_query
+            .push(format_args!(
+                " ORDER BY {} {}, f.id {}",
+                listing.sort.column(),
+                listing.order(),
+                listing.order()
+            ))
+            .push(" LIMIT ")
+            .push_bind(FILES_PER_PAGE)
+            .push(" OFFSET ")
+            .push_bind((page - 1) * FILES_PER_PAGE);
+        let all_files: Vec<FileRow> = files_query.build_query_as().fetch_all(&aps.db).await?;
 
         #[derive(Debug, Serialize)]
         struct UploadedFile {
@@ -79,6 +148,9 @@ pub async fn admin_page(
             expiry_ts_pretty: String,
             expiry_ts: String,
             downloads: i64,
+            is_bundle: bool,
+            member_count: i64,
+            max_downloads: Option<i64>,
         }
 
         let now = Utc::now();
@@ -119,6 +191,9 @@ pub async fn admin_page(
                         "(invalid timestamp)".to_string()
                     },
                     downloads: e.downloads,
+                    is_bundle: e.is_bundle,
+                    member_count: e.member_count,
+                    max_downloads: e.max_downloads,
                 }
             })
             .collect::<Vec<_>>();
@@ -126,26 +201,313 @@ pub async fn admin_page(
         // Add the global statistics to the rendering context.
         let mut context = aps.default_context();
         context.insert("files", &ufs);
-        context.insert("full_file_count", &ufs.len());
-        context.insert("maximum_quota", &pretty_print_bytes(aps.conf.maximum_quota));
-        context.insert("used_quota", &pretty_print_bytes(used_quota));
+        context.insert("full_file_count", &full_file_count);
+        context.insert(
+            "maximum_quota",
+            &pretty_print_bytes(aps.conf().maximum_quota),
+        );
+        context.insert("used_quota", &pretty_print_bytes(used_quota as u64));
+        context.insert("matching_file_count", &matching_file_count);
+        context.insert("listing", &listing);
+        context.insert("ip_filter", &listing.ip_prefix.map(|v| v.pretty_print()));
+
+        // Bulk expiry changes offer the same durations as the upload page.
+        context.insert("expiry_options", &aps.conf().labeled_expiry_options());
+        context.insert("page", &page);
+        context.insert("page_count", &page_count);
+        context.insert("prev_page_url", &(page > 1).then(|| listing.url(page - 1)));
+        context.insert(
+            "next_page_url",
+            &(page < page_count).then(|| listing.url(page + 1)),
+        );
+        context.insert("admin_username", &admin.username);
+        context.insert("admin_role", &admin.role);
+        context.insert("can_delete", &(admin.role >= Role::Deleter));
+
+        // Show whether the admin's login is protected by a second factor.
+        let totp_enabled: bool =
+            sqlx::query_scalar("SELECT totp_secret IS NOT NULL FROM admins WHERE id = ?;")
+                .bind(admin.admin_id)
+                .fetch_one(&aps.db)
+                .await?;
+        let recovery_codes_left: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM admin_recovery_codes WHERE admin_id = ?;")
+                .bind(admin.admin_id)
+                .fetch_one(&aps.db)
+                .await?;
+        context.insert("totp_enabled", &totp_enabled);
+        context.insert("recovery_codes_left", &recovery_codes_left);
+        context.insert(
+            "totp_disable_failed",
+            &params
+                .get("status")
+                .is_some_and(|e| e == "totp_disable_failed"),
+        );
+
+        // Reports about abusive content await a decision by an admin.
+        context.insert("reports", &report::open_reports(&aps).await?);
+
+        // Owners get to see who else administers the site.
+        if admin.role >= Role::Owner {
+            #[derive(Serialize, FromRow)]
+            struct AdminRow {
+                username: String,
+                role: Role,
+            }
+            let admins: Vec<AdminRow> =
+                sqlx::query_as("SELECT username, role FROM admins ORDER BY username;")
+                    .fetch_all(&aps.db)
+                    .await?;
+            context.insert("admins", &admins);
+            context.insert("audit_events", &audit::recent_events(&aps).await?);
+        }
+
+        // List the active sessions, so that forgotten or stolen ones can be revoked.
+        // Owners see those of all admins, everybody else only their own.
+        #[derive(FromRow)]
+        struct SessionRow {
+            id: i64,
+            admin_id: i64,
+            username: String,
+            expiry_ts: String,
+            created_ts: Option<String>,
+            last_seen_ts: Option<String>,
+            ip_prefix: Option<String>,
+            user_agent: Option<String>,
+        }
+        let sessions: Vec<SessionRow> = sqlx::query_as(
+            "SELECT s.id, s.admin_id, a.username, s.expiry_ts, s.created_ts, s.last_seen_ts, s.ip_prefix, s.user_agent FROM admin_sessions s JOIN admins a ON a.id = s.admin_id WHERE s.second_factor_pending = 0 AND (s.admin_id = ? OR ?) ORDER BY s.last_seen_ts DESC;",
+        )
+        .bind(admin.admin_id)
+        .bind(admin.role >= Role::Owner)
+        .fetch_all(&aps.db)
+        .await?;
+
+        #[derive(Serialize)]
+        struct ActiveSession {
+            id: i64,
+            username: String,
+            is_current: bool,
+            is_own: bool,
+            created_ts_pretty: String,
+            last_seen_ts_pretty: String,
+            ip_pretty: String,
+            user_agent: String,
+        }
+
+        let pretty_print_ago = |ts: Option<String>| {
+            ts.and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+                .map_or_else(
+                    || "N/A".to_string(),
+                    |v| format!("{} ago", pretty_print_delta(now, v)),
+                )
+        };
+
+        let sessions = sessions
+            .into_iter()
+            .filter(|e| !has_expired(&e.expiry_ts).unwrap_or(true))
+            .map(|e| ActiveSession {
+                id: e.id,
+                username: e.username,
+                is_current: e.id == admin.session_id,
+                is_own: e.admin_id == admin.admin_id,
+                created_ts_pretty: pretty_print_ago(e.created_ts),
+                last_seen_ts_pretty: pretty_print_ago(e.last_seen_ts),
+                ip_pretty: e
+                    .ip_prefix
+                    .and_then(|v| IpPrefix::from_str(&v).ok())
+                    .map_or_else(|| "unknown IP".to_string(), |v| v.pretty_print()),
+                user_agent: e
+                    .user_agent
+                    .unwrap_or_else(|| "Unknown browser".to_string()),
+            })
+            .collect::<Vec<_>>();
+        context.insert(
+            "other_own_sessions",
+            &sessions.iter().any(|e| e.is_own && !e.is_current),
+        );
+        context.insert("sessions", &sessions);
+
         // And actually render.
-        let h = aps.tera.render("admin_overview.html", &context)?;
+        let h = aps.tera().render("admin_overview.html", &context)?;
         Ok(Html(String::from_utf8(minify(h.as_bytes(), &MINIFY_CFG))?))
     } else {
         // Check if this is a normal visit or a Redirect from a failed login-attempt.
-        let failed_login = params.get("status").map_or(false, |e| e == "login_failed");
+        let status = params.get("status").map_or("", |v| v.as_str());
+        let failed_login = status == "login_failed";
+        let failed_second_factor = status == "second_factor_failed";
+        let locked_out = status == "locked_out";
+
+        // Clients whose password has already been accepted are asked for their second factor.
+        let second_factor = find_session(&aps, &jar)
+            .await?
+            .is_some_and(|v| v.second_factor_pending);
 
         // If the client is not logged in, serve the login form.
         let mut context = aps.default_context();
         context.insert("failed_login", &failed_login);
-        let h = aps.tera.render("admin_login.html", &context)?;
+        context.insert("failed_second_factor", &failed_second_factor);
+        context.insert("locked_out", &locked_out);
+        context.insert("second_factor", &second_factor);
+        context.insert("long_login", &params.contains_key("long_login"));
+        let h = aps.tera().render("admin_login.html", &context)?;
         Ok(Html(String::from_utf8(minify(h.as_bytes(), &MINIFY_CFG))?))
     }
 }
 
+/// Number of files shown on a single page of the dashboard
+const FILES_PER_PAGE: i64 = 50;
+
+/// Columns the dashboard's file list can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileSort {
+    Size,
+    Uploaded,
+    Expiry,
+    Downloads,
+}
+
+impl FileSort {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "size" => Some(FileSort::Size),
+            "uploaded" => Some(FileSort::Uploaded),
+            "expiry" => Some(FileSort::Expiry),
+            "downloads" => Some(FileSort::Downloads),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FileSort::Size => "size",
+            FileSort::Uploaded => "uploaded",
+            FileSort::Expiry => "expiry",
+            FileSort::Downloads => "downloads",
+        }
+    }
+
+    /// The SQL expression to sort by, which must never contain any user input.
+    fn column(&self) -> &'static str {
+        match self {
+            FileSort::Size => "f.filesize",
+            FileSort::Uploaded => "julianday(f.upload_ts)",
+            FileSort::Expiry => "julianday(f.expiry_ts)",
+            FileSort::Downloads => "downloads",
+        }
+    }
+}
+
+/// Which files the dashboard lists and in what order, as chosen through the query parameters
+///
+/// Invalid parameters are ignored instead of rejected, falling back to the unfiltered list.
+#[derive(Debug, Serialize)]
+struct FileListing {
+    page: i64,
+    sort: FileSort,
+    descending: bool,
+    /// Prefix of the file hash to search for
+    search: String,
+    /// The IP filter as entered, kept for the form
+    ip: String,
+    #[serde(skip)]
+    ip_prefix: Option<IpPrefix>,
+    /// Size limits in MiB as entered, kept for the form
+    min_size: String,
+    max_size: String,
+}
+
+impl FileListing {
+    fn from_params(params: &HashMap<String, String>) -> Self {
+        let get = |key: &str| params.get(key).map_or("", |v| v.trim()).to_string();
+        let ip = get("ip");
+        Self {
+            page: params
+                .get("page")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1)
+                .max(1),
+            sort: params
+                .get("sort")
+                .and_then(|v| FileSort::parse(v))
+                .unwrap_or(FileSort::Uploaded),
+            descending: params.get("order").is_none_or(|v| v != "asc"),
+            search: get("search"),
+            ip_prefix: IpPrefix::parse_user_input(&ip),
+            ip,
+            min_size: get("min_size"),
+            max_size: get("max_size"),
+        }
+    }
+
+    fn order(&self) -> &'static str {
+        if self.descending {
+            "DESC"
+        } else {
+            "ASC"
+        }
+    }
+
+    /// Convert a size limit in MiB as entered into bytes.
+    fn size_in_bytes(size: &str) -> Option<i64> {
+        size.parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite() && *v >= 0.0)
+            .map(|v| (v * 1_048_576.0) as i64)
+    }
+
+    /// Append the WHERE-clause selecting the live top-level files that match the filters.
+    fn push_filters(&self, query: &mut QueryBuilder<Sqlite>) {
+        query.push(" WHERE f.parent_id IS NULL AND julianday(f.expiry_ts) > julianday('now')");
+        if !self.search.is_empty() {
+            // Hashes are base64url, whose '_' would be a wildcard to LIKE.
+            query
+                .push(" AND substr(f.efd_sha256sum, 1, length(")
+                .push_bind(self.search.clone())
+                .push(")) = ")
+                .push_bind(self.search.clone());
+        }
+        if let Some(ip_prefix) = self.ip_prefix {
+            query
+                .push(" AND f.upload_ip = ")
+                .push_bind(ip_prefix.to_string());
+        }
+        if let Some(min_size) = Self::size_in_bytes(&self.min_size) {
+            query.push(" AND f.filesize >= ").push_bind(min_size);
+        }
+        if let Some(max_size) = Self::size_in_bytes(&self.max_size) {
+            query.push(" AND f.filesize <= ").push_bind(max_size);
+        }
+    }
+
+    /// Link to another page of the same listing.
+    fn url(&self, page: i64) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("page", &page.to_string());
+        query.append_pair("sort", self.sort.name());
+        query.append_pair("order", if self.descending { "desc" } else { "asc" });
+        for (key, value) in [
+            ("search", &self.search),
+            ("ip", &self.ip),
+            ("min_size", &self.min_size),
+            ("max_size", &self.max_size),
+        ] {
+            if !value.is_empty() {
+                query.append_pair(key, value);
+            }
+        }
+        format!("/admin?{}", query.finish())
+    }
+}
+
+/// Hash that failed logins with unknown usernames are verified against
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("").unwrap_or_default());
+
 #[derive(Debug, Deserialize)]
 pub struct AdminLogin {
+    username: String,
     password: String,
     long_login: Option<String>,
 }
@@ -153,13 +515,45 @@ pub struct AdminLogin {
 /// Endpoint allowing a site-wide administrator to login with a POST request
 pub async fn admin_login(
     State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    headers: HeaderMap,
     jar: CookieJar,
     Form(admin_login): Form<AdminLogin>,
 ) -> Result<(CookieJar, Redirect), AppError> {
-    // Parse the config's PasswordHash
-    let admin_pw = PasswordHash::new(&aps.conf.admin_password_hash).map_err(|e| {
+    // Locked out clients don't get their password checked at all, which also spares the CPU.
+    if let Some(locked_until_ts) = lockout::locked_until(&aps, &eip).await? {
+        tracing::warn!(
+            ip = eip.pretty_print(),
+            username = admin_login.username,
+            locked_until_ts,
+            "security event: admin login attempt while locked out"
+        );
+        return Ok((jar, Redirect::to("/admin?status=locked_out")));
+    }
+    lockout::slow_down(&aps).await?;
+
+    #[derive(FromRow)]
+    struct AdminRow {
+        id: i64,
+        password_hash: String,
+        totp_enabled: bool,
+    }
+
+    let admin: Option<AdminRow> = sqlx::query_as(
+        "SELECT id, password_hash, totp_secret IS NOT NULL AS totp_enabled FROM admins WHERE username = ? LIMIT 1;",
+    )
+    .bind(&admin_login.username)
+    .fetch_optional(&aps.db)
+    .await?;
+
+    // Unknown usernames are verified against a dummy hash,
+    // so that response times don't reveal which usernames exist.
+    let password_hash = admin
+        .as_ref()
+        .map_or(DUMMY_PASSWORD_HASH.as_str(), |v| v.password_hash.as_str());
+    let admin_pw = PasswordHash::new(password_hash).map_err(|e| {
         AppError::new500(format!(
-            "the admin password hash in config.toml could not be parsed correctly: {e}"
+            "the password hash of an admin could not be parsed correctly: {e}"
         ))
     })?;
 
@@ -167,10 +561,83 @@ pub async fn admin_login(
     let password_result =
         Argon2::default().verify_password(admin_login.password.as_bytes(), &admin_pw);
 
-    if password_result.is_err() {
+    let Some(admin) = admin.filter(|_| password_result.is_ok()) else {
+        lockout::record_failure(&aps, &eip, &admin_login.username).await?;
         return Ok((jar, Redirect::to("/admin?status=login_failed")));
+    };
+
+    // With TOTP enabled the session only becomes usable once the code has been entered.
+    if admin.totp_enabled {
+        let origin = LoginOrigin::new(eip, &headers);
+        let jar = start_session(
+            &aps,
+            jar,
+            admin.id,
+            &admin_login.username,
+            &origin,
+            false,
+            true,
+        )
+        .await?;
+        let redirect = match admin_login.long_login {
+            Some(_) => "/admin?long_login=on",
+            None => "/admin",
+        };
+        return Ok((jar, Redirect::to(redirect)));
     }
 
+    lockout::record_success(&aps, &eip).await?;
+    let jar = start_session(
+        &aps,
+        jar,
+        admin.id,
+        &admin_login.username,
+        &LoginOrigin::new(eip, &headers),
+        admin_login.long_login.is_some(),
+        false,
+    )
+    .await?;
+    Ok((jar, Redirect::to("/admin")))
+}
+
+/// How long a login may take to provide the second factor after the password was accepted
+const SECOND_FACTOR_TIMEOUT_MINUTES: i64 = 5;
+
+/// Longest user agent that is stored with a session, anything beyond is cut off
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Where a login comes from, recorded with its session for the dashboard's session list
+struct LoginOrigin {
+    ip_prefix: IpPrefix,
+    user_agent: Option<String>,
+}
+
+impl LoginOrigin {
+    fn new(ip_prefix: IpPrefix, headers: &HeaderMap) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        Self {
+            ip_prefix,
+            user_agent,
+        }
+    }
+}
+
+/// Create a new session for an admin and add its cookie to the jar.
+///
+/// Sessions awaiting the second factor only last a couple of minutes
+/// and don't grant access to anything but the second step of the login.
+async fn start_session(
+    aps: &AppState,
+    jar: CookieJar,
+    admin_id: i64,
+    username: &str,
+    origin: &LoginOrigin,
+    long_login: bool,
+    second_factor_pending: bool,
+) -> Result<CookieJar, AppError> {
     // Create a new session.
     let session_id_bytes = rng().random::<[u8; 32]>();
     let session_id = URL_SAFE_NO_PAD.encode(session_id_bytes);
@@ -187,28 +654,120 @@ pub async fn admin_login(
     // If a long session is requested, it will be given 30 days validity.
     // Otherwise, it will be given 24 hours validity and
     // the cookie will be set to expire on closing the browser.
-
-    if admin_login.long_login.is_some() {
+    if long_login {
         session_cookie = session_cookie.max_age(Duration::days(30));
     }
 
     // Session validity is either one or 30 days, depending on the login checkbox.
-    let duration_days = admin_login.long_login.map_or(1, |_| 30);
-    // Calculate the RFC3339 timestamp for session expiry, either in one or 30 days.
-    let expiry_ts = Utc::now()
-        .checked_add_signed(TimeDelta::days(duration_days))
+    let duration_days = if long_login { 30 } else { 1 };
+    let duration = match second_factor_pending {
+        true => TimeDelta::minutes(SECOND_FACTOR_TIMEOUT_MINUTES),
+        false => TimeDelta::days(duration_days),
+    };
+    // Calculate the RFC3339 timestamp for session expiry.
+    let now = Utc::now();
+    let expiry_ts = now
+        .checked_add_signed(duration)
         .ok_or_else(|| AppError::new500("failed to apply duration to current timestamp"))?
         .to_rfc3339();
 
-    sqlx::query("INSERT INTO admin_sessions (session_id_sha256sum, expiry_ts) VALUES (?, ?);")
-        .bind(&session_id_sha256sum)
-        .bind(&expiry_ts)
+    let session_id: i64 = sqlx::query_scalar(
+        "INSERT INTO admin_sessions (session_id_sha256sum, expiry_ts, admin_id, second_factor_pending, created_ts, last_seen_ts, ip_prefix, user_agent) VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING id;",
+    )
+    .bind(&session_id_sha256sum)
+    .bind(&expiry_ts)
+    .bind(admin_id)
+    .bind(second_factor_pending)
+    .bind(now.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .bind(origin.ip_prefix.to_string())
+    .bind(&origin.user_agent)
+    .fetch_one(&aps.db)
+    .await?;
+
+    if second_factor_pending {
+        tracing::info!(
+            session_id_sha256sum,
+            username,
+            "admin password accepted, awaiting second factor"
+        );
+    } else {
+        tracing::info!(
+            session_id_sha256sum,
+            username,
+            duration_days,
+            "new admin login"
+        );
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::AdminLogin,
+            Actor::Admin {
+                username,
+                session_id,
+            },
+            None,
+            Some(&format!("from {}", origin.ip_prefix.pretty_print())),
+        )
+        .await;
+    }
+
+    Ok(jar.add(session_cookie))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminSecondFactor {
+    code: String,
+    long_login: Option<String>,
+}
+
+/// Endpoint completing a login with TOTP enabled, accepting either a code or a recovery code
+pub async fn admin_login_second_factor(
+    State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Form(second_factor): Form<AdminSecondFactor>,
+) -> Result<(CookieJar, Redirect), AppError> {
+    let Some(session) = find_session(&aps, &jar)
+        .await?
+        .filter(|v| v.second_factor_pending)
+    else {
+        return Ok((jar, Redirect::to("/admin?status=login_failed")));
+    };
+
+    // The pending session is used up either way, a wrong code means starting over.
+    // This limits guessing to a single attempt per correctly entered password.
+    sqlx::query("DELETE FROM admin_sessions WHERE session_id_sha256sum = ?;")
+        .bind(session_sha256sum(&jar))
         .execute(&aps.db)
         .await?;
 
-    tracing::info!(session_id_sha256sum, duration_days, "new admin login");
+    // Lockouts of the client that started while the code was being entered apply here as well.
+    // The global slowdown doesn't, the pending session already proves knowledge of the password.
+    if lockout::locked_until(&aps, &eip).await?.is_some() {
+        return Ok((jar.remove("id"), Redirect::to("/admin?status=locked_out")));
+    }
+
+    if !totp::check_second_factor(&aps.db, session.admin_id, &second_factor.code).await? {
+        lockout::record_failure(&aps, &eip, &session.username).await?;
+        return Ok((
+            jar.remove("id"),
+            Redirect::to("/admin?status=second_factor_failed"),
+        ));
+    }
 
-    Ok((jar.add(session_cookie), Redirect::to("/admin")))
+    lockout::record_success(&aps, &eip).await?;
+    let jar = start_session(
+        &aps,
+        jar,
+        session.admin_id,
+        &session.username,
+        &LoginOrigin::new(eip, &headers),
+        second_factor.long_login.is_some(),
+        false,
+    )
+    .await?;
+    Ok((jar, Redirect::to("/admin")))
 }
 
 /// Endpoint allowing a site-wide administrator to manually logout
@@ -216,12 +775,8 @@ pub async fn admin_logout(
     State(aps): State<AppState>,
     jar: CookieJar,
 ) -> Result<(CookieJar, Redirect), AppError> {
-    // Calculate the base64url-encoded sha256sum of the session cookie, if any.
-    let user_session_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
-        URL_SAFE_NO_PAD
-            .decode(jar.get("id").map_or("", |e| e.value()))
-            .unwrap_or_default(),
-    ));
+    let user_session_sha256sum = session_sha256sum(&jar);
+    let session = current_admin(&aps, &jar).await?;
 
     // Remove whatever rows exist with that sha256sum.
     let db_results = sqlx::query("DELETE FROM admin_sessions WHERE session_id_sha256sum = ?;")
@@ -234,6 +789,209 @@ pub async fn admin_logout(
         "logging out {0} admin session(s)",
         db_results.rows_affected()
     );
+    if let Some(ref session) = session {
+        audit::record_or_log(&aps.db, AuditEvent::AdminLogout, session.into(), None, None).await;
+    }
 
     Ok((jar.remove("id"), Redirect::to("/admin")))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct AdminSessionRevoke {
+    session_id: i64,
+}
+
+/// Endpoint ending a single session, either one's own or, for owners, that of any admin
+pub async fn admin_session_revoke(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<AdminSessionRevoke>,
+) -> Result<Redirect, AppError> {
+    let Some(admin) = current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin"));
+    };
+
+    let db_results =
+        sqlx::query("DELETE FROM admin_sessions WHERE id = ? AND (admin_id = ? OR ?);")
+            .bind(form.session_id)
+            .bind(admin.admin_id)
+            .bind(admin.role >= Role::Owner)
+            .execute(&aps.db)
+            .await?;
+
+    tracing::info!(
+        username = admin.username,
+        session_id = form.session_id,
+        "revoked {0} admin session(s)",
+        db_results.rows_affected()
+    );
+    if db_results.rows_affected() > 0 {
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::SessionRevoked,
+            (&admin).into(),
+            None,
+            Some(&format!("session #{}", form.session_id)),
+        )
+        .await;
+    }
+
+    Ok(Redirect::to("/admin"))
+}
+
+/// Endpoint ending all sessions of the logged-in admin except the current one
+pub async fn admin_session_revoke_others(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+) -> Result<Redirect, AppError> {
+    let Some(admin) = current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin"));
+    };
+
+    // Pending logins are included, their password has been entered somewhere else after all.
+    let db_results = sqlx::query("DELETE FROM admin_sessions WHERE admin_id = ? AND id != ?;")
+        .bind(admin.admin_id)
+        .bind(admin.session_id)
+        .execute(&aps.db)
+        .await?;
+
+    tracing::info!(
+        username = admin.username,
+        "revoked {0} other admin session(s)",
+        db_results.rows_affected()
+    );
+    if db_results.rows_affected() > 0 {
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::SessionRevoked,
+            (&admin).into(),
+            None,
+            Some(&format!(
+                "{} other session(s) of {}",
+                db_results.rows_affected(),
+                admin.username
+            )),
+        )
+        .await;
+    }
+
+    Ok(Redirect::to("/admin"))
+}
+
+/// Page for setting up TOTP, showing the QR code to scan and asking for a first code
+pub async fn admin_totp_page(
+    Query(params): Query<HashMap<String, String>>,
+    State(aps): State<AppState>,
+    jar: CookieJar,
+) -> Result<Response, AppError> {
+    let Some(admin) = current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+
+    #[derive(FromRow)]
+    struct TotpRow {
+        totp_secret: Option<Vec<u8>>,
+        totp_pending_secret: Option<Vec<u8>>,
+    }
+
+    let row: TotpRow =
+        sqlx::query_as("SELECT totp_secret, totp_pending_secret FROM admins WHERE id = ?;")
+            .bind(admin.admin_id)
+            .fetch_one(&aps.db)
+            .await?;
+
+    // Already set up? It has to be disabled first to start over.
+    if row.totp_secret.is_some() {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+
+    // Keep showing the same secret on reloads, so that an already scanned QR code stays valid.
+    let secret = match row.totp_pending_secret {
+        Some(v) => v,
+        None => {
+            let secret = totp::generate_secret();
+            sqlx::query("UPDATE admins SET totp_pending_secret = ? WHERE id = ?;")
+                .bind(&secret)
+                .bind(admin.admin_id)
+                .execute(&aps.db)
+                .await?;
+            secret
+        }
+    };
+
+    let uri = totp::otpauth_uri(&aps.conf().app_name, &admin.username, &secret);
+
+    let mut context = aps.default_context();
+    context.insert("qr_svg", &totp::qr_svg(&uri)?);
+    context.insert("secret", &totp::encode_secret(&secret));
+    context.insert(
+        "code_failed",
+        &params.get("status").is_some_and(|e| e == "code_failed"),
+    );
+    let h = aps.tera().render("admin_totp.html", &context)?;
+    Ok(Html(String::from_utf8(minify(h.as_bytes(), &MINIFY_CFG))?).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminTotpCode {
+    code: String,
+}
+
+/// Endpoint confirming the TOTP setup with a first code, then showing the recovery codes
+pub async fn admin_totp_enable(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<AdminTotpCode>,
+) -> Result<Response, AppError> {
+    let Some(admin) = current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+
+    let pending_secret: Option<Vec<u8>> =
+        sqlx::query_scalar("SELECT totp_pending_secret FROM admins WHERE id = ?;")
+            .bind(admin.admin_id)
+            .fetch_one(&aps.db)
+            .await?;
+    let Some(secret) = pending_secret else {
+        return Ok(Redirect::to("/admin_totp").into_response());
+    };
+
+    // A valid code proves the authenticator has been set up correctly.
+    let Some(step) = totp::verify(&secret, &form.code, None) else {
+        return Ok(Redirect::to("/admin_totp?status=code_failed").into_response());
+    };
+    let recovery_codes = totp::enable(&aps.db, admin.admin_id, &secret, Some(step)).await?;
+
+    tracing::info!(username = admin.username, "enabled TOTP for admin");
+
+    // The recovery codes are only stored hashed, so this is the one chance to see them.
+    let mut context = aps.default_context();
+    context.insert("recovery_codes", &recovery_codes);
+    let h = aps.tera().render("admin_totp.html", &context)?;
+    Ok(Html(String::from_utf8(minify(h.as_bytes(), &MINIFY_CFG))?).into_response())
+}
+
+/// Endpoint disabling TOTP for the logged-in admin, which requires a code as well
+pub async fn admin_totp_disable(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<AdminTotpCode>,
+) -> Result<Redirect, AppError> {
+    let Some(admin) = current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin"));
+    };
+
+    // Requiring a code ensures a hijacked session alone can't remove the second factor.
+    if !totp::check_second_factor(&aps.db, admin.admin_id, &form.code).await? {
+        tracing::warn!(
+            username = admin.username,
+            "failed to disable TOTP, wrong code"
+        );
+        return Ok(Redirect::to("/admin?status=totp_disable_failed"));
+    }
+    totp::disable(&aps.db, admin.admin_id).await?;
+
+    tracing::info!(username = admin.username, "disabled TOTP for admin");
+
+    Ok(Redirect::to("/admin"))
+}
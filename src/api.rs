@@ -0,0 +1,425 @@
+//! Versioned REST API for scripted use, described by an OpenAPI document
+//!
+//! All routes live under '/api/v1/'. Apart from the filedata itself, requests and responses
+//! are JSON, and errors are always reported as an [ApiErrorBody] carrying a stable error code.
+//! The API speaks the very same end-to-end encrypted format as the web interface,
+//! so clients encrypt files themselves and the server never learns their key.
+//!
+//! Endpoints that manage a file expect its admin key as a bearer token in the
+//! Authorization header, where it doesn't end up in any logs along the way.
+
+use axum::{
+    extract::{multipart::MultipartRejection, rejection::JsonRejection, Multipart, Path, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::Response,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::audit::{Actor, AuditEvent};
+use crate::error_handling::{ApiError, ApiErrorBody};
+use crate::upload::{self, UploadFileResponse};
+use crate::*;
+
+/// The OpenAPI document describing the entire REST API
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "FerriShare API",
+        description = "Upload, inspect, download and manage end-to-end encrypted files without a browser."
+    ),
+    paths(upload_file, file_metadata, download_file, delete_file, change_expiry),
+    components(schemas(
+        UploadForm,
+        UploadFileResponse,
+        FileMetadata,
+        MemberMetadata,
+        PassphraseMetadata,
+        AdminMetadata,
+        ExpiryRequest,
+        ExpiryResponse,
+        ApiErrorBody
+    )),
+    modifiers(&AdminKeyScheme),
+    tags((name = "files", description = "Uploaded files and bundles"))
+)]
+pub struct ApiDoc;
+
+/// Adds the security scheme for admin keys to the OpenAPI document.
+struct AdminKeyScheme;
+
+impl Modify for AdminKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_key",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// Endpoint serving the OpenAPI document as JSON
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// The multipart form accepted by the upload endpoint, only used to document it
+///
+/// Identical to the form submitted by the upload page. All binary fields are raw bytes.
+/// Several files may be uploaded as a bundle by repeating e_filedata, e_filename, iv_fd and iv_fn.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+struct UploadForm {
+    /// The encrypted filedata
+    #[schema(format = Binary)]
+    e_filedata: Vec<u8>,
+    /// The encrypted filename, at most 8KiB
+    #[schema(format = Binary)]
+    e_filename: Vec<u8>,
+    /// The 12-byte IV the filedata was encrypted with
+    #[schema(format = Binary)]
+    iv_fd: Vec<u8>,
+    /// The 12-byte IV the filename was encrypted with
+    #[schema(format = Binary)]
+    iv_fn: Vec<u8>,
+    /// One of the configured expiry options, e.g. '1d'; mutually exclusive with expiry_ts
+    duration: Option<String>,
+    /// Exact RFC3339 expiry timestamp, if enabled on the server; mutually exclusive with duration
+    expiry_ts: Option<String>,
+    /// 1 for files encrypted in one piece, 2 for the segmented format; defaults to 1
+    format_version: Option<i64>,
+    /// How often each file may be downloaded before it is removed
+    max_downloads: Option<i64>,
+    /// The 16-byte PBKDF2 salt of the passphrase wrapping the link key
+    #[schema(format = Binary)]
+    kdf_salt: Option<Vec<u8>>,
+    /// The PBKDF2 iteration count of the passphrase wrapping the link key
+    kdf_iterations: Option<i64>,
+}
+
+/// Public metadata of a file or bundle, with additional statistics for its owner
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FileMetadata {
+    pub efd_sha256sum: String,
+    pub is_bundle: bool,
+    /// Total size of the encrypted filedata in bytes, summed up over all members of a bundle
+    pub filesize: i64,
+    /// Present if the link key is wrapped with a passphrase
+    pub passphrase: Option<PassphraseMetadata>,
+    /// The files that can be downloaded, which is just the file itself unless it's a bundle
+    pub members: Vec<MemberMetadata>,
+    /// Only present if the request presented the file's admin key
+    pub admin: Option<AdminMetadata>,
+}
+
+/// A single downloadable file
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MemberMetadata {
+    pub efd_sha256sum: String,
+    /// Base64url-encoded encrypted filename
+    pub e_filename: String,
+    /// Base64url-encoded IV of the filedata
+    pub iv_fd: String,
+    /// Base64url-encoded IV of the filename
+    pub iv_fn: String,
+    pub filesize: i64,
+    pub format_version: i64,
+    /// Downloads left before the file is removed, if it has a download limit
+    pub remaining_downloads: Option<i64>,
+}
+
+/// Parameters for deriving the key that unwraps the link key from the passphrase
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PassphraseMetadata {
+    /// Base64url-encoded PBKDF2 salt
+    pub salt: String,
+    pub iterations: i64,
+}
+
+/// Statistics only visible to the owner of a file
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AdminMetadata {
+    /// RFC3339 timestamp of the upload
+    pub upload_ts: String,
+    /// RFC3339 timestamp at which the file expires
+    pub expiry_ts: String,
+    /// How often the file has been downloaded, summed up over all members of a bundle
+    pub downloads: i64,
+}
+
+/// The new expiry of a file, either relative to now or as an exact point in time
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExpiryRequest {
+    /// One of the configured expiry options, e.g. '1d', counted from now
+    ///
+    /// Files are never kept beyond the server's maximum expiry counted from their upload,
+    /// so durations reaching past that point are cut short.
+    pub duration: Option<String>,
+    /// Exact RFC3339 expiry timestamp, if enabled on the server, up to that same point
+    pub expiry_ts: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ExpiryResponse {
+    /// RFC3339 timestamp at which the file now expires
+    pub expiry_ts: String,
+}
+
+/// Extract the bearer token of a request, if any, e.g. the admin key of a file.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+#[derive(Debug, FromRow)]
+struct FileRow {
+    id: i64,
+    admin_key_sha256sum: String,
+    filesize: i64,
+    upload_ts: String,
+    expiry_ts: String,
+    downloads: i64,
+    is_bundle: bool,
+    kdf_salt: Option<Vec<u8>>,
+    kdf_iterations: Option<i64>,
+}
+
+/// Look up a file or bundle by its hash, treating expired files as nonexistent.
+///
+/// Members of a bundle are only ever accessible through their bundle.
+async fn find_file(aps: &AppState, hash: &str) -> Result<FileRow, AppError> {
+    // Do not entertain hashes with invalid length.
+    if hash.len() != 43 {
+        return AppError::err(StatusCode::BAD_REQUEST, "invalid hash length");
+    }
+
+    let row: Option<FileRow> = sqlx::query_as("SELECT id, admin_key_sha256sum, filesize, upload_ts, expiry_ts, downloads, is_bundle, kdf_salt, kdf_iterations FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;")
+        .bind(hash)
+        .fetch_optional(&aps.db)
+        .await?;
+
+    match row {
+        Some(row) if !has_expired(&row.expiry_ts)? => Ok(row),
+        _ => AppError::err(StatusCode::NOT_FOUND, "file not found or expired"),
+    }
+}
+
+/// Upload one or several files, the latter of which are stored as a bundle
+#[utoipa::path(
+    post,
+    path = "/api/v1/files",
+    tag = "files",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The file was stored", body = UploadFileResponse),
+        (status = 400, description = "The form is invalid", body = ApiErrorBody),
+        (status = 409, description = "An identical encrypted file already exists", body = ApiErrorBody),
+        (status = 429, description = "The upload limit has been reached", body = ApiErrorBody),
+        (status = 507, description = "The server has reached its storage quota", body = ApiErrorBody),
+    )
+)]
+pub async fn upload_file(
+    State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<UploadFileResponse>), ApiError> {
+    let response = upload::receive_upload(&aps, &eip, multipart).await?;
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Look up the metadata needed to download and decrypt a file
+///
+/// Presenting the admin key additionally reveals the file's statistics.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{hash}",
+    tag = "files",
+    params(("hash" = String, Path, description = "The file's efd_sha256sum")),
+    security((), ("admin_key" = [])),
+    responses(
+        (status = 200, description = "The file's metadata", body = FileMetadata),
+        (status = 401, description = "The admin key is invalid", body = ApiErrorBody),
+        (status = 404, description = "The file does not exist or has expired", body = ApiErrorBody),
+    )
+)]
+pub async fn file_metadata(
+    State(aps): State<AppState>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<FileMetadata>, ApiError> {
+    let row = find_file(&aps, &hash).await?;
+
+    // Unlike the download page, an invalid admin key is an error instead of being ignored.
+    let is_owner = match bearer_token(&headers) {
+        Some(admin_key) if admin_key_matches(admin_key, &row.admin_key_sha256sum) => true,
+        Some(_) => return Err(AppError::new(StatusCode::UNAUTHORIZED, "unauthorized").into()),
+        None => false,
+    };
+
+    #[derive(FromRow)]
+    struct MemberRow {
+        efd_sha256sum: String,
+        e_filename: Vec<u8>,
+        iv_fd: Vec<u8>,
+        iv_fn: Vec<u8>,
+        filesize: i64,
+        downloads: i64,
+        format_version: i64,
+        max_downloads: Option<i64>,
+    }
+
+    // That's either all of the bundle's members or just the file itself.
+    let members: Vec<MemberRow> = sqlx::query_as("SELECT efd_sha256sum, e_filename, iv_fd, iv_fn, filesize, downloads, format_version, max_downloads FROM uploaded_files WHERE parent_id = ? OR (id = ? AND is_bundle = 0) ORDER BY id;")
+        .bind(row.id)
+        .bind(row.id)
+        .fetch_all(&aps.db)
+        .await
+        .map_err(AppError::from)?;
+
+    // The files of a bundle are downloaded individually, so their counts are summed up.
+    let downloads = if row.is_bundle {
+        members.iter().map(|m| m.downloads).sum()
+    } else {
+        row.downloads
+    };
+
+    let passphrase = match (row.kdf_salt, row.kdf_iterations) {
+        (Some(salt), Some(iterations)) => Some(PassphraseMetadata {
+            salt: URL_SAFE_NO_PAD.encode(salt),
+            iterations,
+        }),
+        _ => None,
+    };
+
+    Ok(Json(FileMetadata {
+        efd_sha256sum: hash,
+        is_bundle: row.is_bundle,
+        filesize: row.filesize,
+        passphrase,
+        members: members
+            .into_iter()
+            .map(|m| MemberMetadata {
+                efd_sha256sum: m.efd_sha256sum,
+                e_filename: URL_SAFE_NO_PAD.encode(m.e_filename),
+                iv_fd: URL_SAFE_NO_PAD.encode(m.iv_fd),
+                iv_fn: URL_SAFE_NO_PAD.encode(m.iv_fn),
+                filesize: m.filesize,
+                format_version: m.format_version,
+                remaining_downloads: m.max_downloads.map(|v| v - m.downloads),
+            })
+            .collect(),
+        admin: is_owner.then_some(AdminMetadata {
+            upload_ts: row.upload_ts,
+            expiry_ts: row.expiry_ts,
+            downloads,
+        }),
+    }))
+}
+
+/// Download the encrypted filedata of a single file
+///
+/// Supports HEAD and single-range requests, just like the download endpoint of the web interface.
+/// Files with a download limit ignore the Range header and are always served in full.
+/// The files of a bundle are downloaded one by one using the hashes listed in its metadata.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{hash}/data",
+    tag = "files",
+    params(("hash" = String, Path, description = "The efd_sha256sum of the file, not the bundle")),
+    responses(
+        (status = 200, description = "The encrypted filedata", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range of the encrypted filedata", content_type = "application/octet-stream"),
+        (status = 400, description = "The hash belongs to a bundle", body = ApiErrorBody),
+        (status = 404, description = "The file does not exist, has expired or reached its download limit", body = ApiErrorBody),
+    )
+)]
+pub async fn download_file(
+    State(aps): State<AppState>,
+    Path(hash): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    Ok(download::serve_file(&aps, &method, &headers, &hash).await?)
+}
+
+/// Delete a file or bundle before it expires
+#[utoipa::path(
+    delete,
+    path = "/api/v1/files/{hash}",
+    tag = "files",
+    params(("hash" = String, Path, description = "The file's efd_sha256sum")),
+    security(("admin_key" = [])),
+    responses(
+        (status = 204, description = "The file was deleted"),
+        (status = 401, description = "The admin key is missing or invalid", body = ApiErrorBody),
+        (status = 404, description = "The file does not exist or has expired", body = ApiErrorBody),
+    )
+)]
+pub async fn delete_file(
+    State(aps): State<AppState>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    // Session cookies are deliberately ignored, the API only accepts the admin key.
+    delete::delete_file(&aps, &hash, bearer_token(&headers), &CookieJar::new()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Change when a file or bundle expires
+///
+/// The new expiry is subject to the same restrictions as the one chosen during the upload.
+#[utoipa::path(
+    put,
+    path = "/api/v1/files/{hash}/expiry",
+    tag = "files",
+    params(("hash" = String, Path, description = "The file's efd_sha256sum")),
+    security(("admin_key" = [])),
+    request_body = ExpiryRequest,
+    responses(
+        (status = 200, description = "The expiry was changed", body = ExpiryResponse),
+        (status = 400, description = "The new expiry is invalid", body = ApiErrorBody),
+        (status = 401, description = "The admin key is missing or invalid", body = ApiErrorBody),
+        (status = 404, description = "The file does not exist or has expired", body = ApiErrorBody),
+    )
+)]
+pub async fn change_expiry(
+    State(aps): State<AppState>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+    req: Result<Json<ExpiryRequest>, JsonRejection>,
+) -> Result<Json<ExpiryResponse>, ApiError> {
+    let Json(req) = req.map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+
+    let row = find_file(&aps, &hash).await?;
+    if !bearer_token(&headers).is_some_and(|v| admin_key_matches(v, &row.admin_key_sha256sum)) {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, "unauthorized").into());
+    }
+
+    let expiry = expiry::parse(
+        req.duration.as_deref(),
+        req.expiry_ts.as_deref(),
+        &aps.conf(),
+    )?;
+    let expiry_ts = expiry::apply(&aps, &mut *aps.db.acquire().await?, &hash, expiry).await?;
+
+    tracing::info!(efd_sha256sum = hash, expiry_ts, "changed expiry of file");
+    audit::record_or_log(
+        &aps.db,
+        AuditEvent::ExpiryChanged,
+        Actor::Uploader,
+        Some(&hash),
+        Some(&expiry_ts),
+    )
+    .await;
+
+    Ok(Json(ExpiryResponse { expiry_ts }))
+}
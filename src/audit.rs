@@ -0,0 +1,230 @@
+//! Append-only audit log of administrative actions and file lifecycle events
+//!
+//! Unlike the tracing output, which is gone with the container, the events are kept in the
+//! database indefinitely, so that questions like "who deleted this file and when" can still be
+//! answered weeks later. Triggers in the database reject any attempt to alter or remove them.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool};
+
+use crate::accounts::Role;
+use crate::admin::AdminSession;
+use crate::download::pretty_print_delta;
+use crate::*;
+
+/// Number of events shown on the admin dashboard, the export contains all of them
+const DASHBOARD_EVENTS: i64 = 50;
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum AuditEvent {
+    AdminLogin,
+    AdminLogout,
+    SessionRevoked,
+    FileUploaded,
+    FileDeleted,
+    FileExpired,
+    DownloadLimitReached,
+    ExpiryChanged,
+    ConfigReloaded,
+}
+
+impl AuditEvent {
+    /// Human-readable description for the admin panel
+    fn label(&self) -> &'static str {
+        match self {
+            AuditEvent::AdminLogin => "Admin login",
+            AuditEvent::AdminLogout => "Admin logout",
+            AuditEvent::SessionRevoked => "Session revoked",
+            AuditEvent::FileUploaded => "File uploaded",
+            AuditEvent::FileDeleted => "File deleted",
+            AuditEvent::FileExpired => "File expired",
+            AuditEvent::DownloadLimitReached => "Download limit reached",
+            AuditEvent::ExpiryChanged => "Expiry changed",
+            AuditEvent::ConfigReloaded => "Configuration reloaded",
+        }
+    }
+}
+
+/// Who caused an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ActorKind {
+    /// A site-wide administrator, identified by their username and session
+    Admin,
+    /// Whoever holds the file's admin key, usually its uploader
+    Uploader,
+    /// The server itself, e.g. when cleaning up expired files
+    System,
+}
+
+/// Who caused an event, along with the identifying details
+#[derive(Debug, Clone, Copy)]
+pub enum Actor<'a> {
+    Admin { username: &'a str, session_id: i64 },
+    Uploader,
+    System,
+}
+
+impl<'a> From<&'a AdminSession> for Actor<'a> {
+    fn from(session: &'a AdminSession) -> Self {
+        Actor::Admin {
+            username: &session.username,
+            session_id: session.session_id,
+        }
+    }
+}
+
+/// Append an event to the audit log.
+///
+/// Accepts any executor, so that events can be recorded as part of the transaction
+/// making the change they describe.
+pub async fn record<'c>(
+    executor: impl Executor<'c, Database = Sqlite>,
+    event: AuditEvent,
+    actor: Actor<'_>,
+    target_hash: Option<&str>,
+    details: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let (actor_kind, admin_username, session_id) = match actor {
+        Actor::Admin {
+            username,
+            session_id,
+        } => (ActorKind::Admin, Some(username), Some(session_id)),
+        Actor::Uploader => (ActorKind::Uploader, None, None),
+        Actor::System => (ActorKind::System, None, None),
+    };
+
+    sqlx::query(
+        "INSERT INTO audit_events (event_ts, event, actor_kind, admin_username, session_id, target_hash, details) VALUES (?, ?, ?, ?, ?, ?, ?);",
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(event)
+    .bind(actor_kind)
+    .bind(admin_username)
+    .bind(session_id)
+    .bind(target_hash)
+    .bind(details)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Append an event to the audit log after the change it describes has already been made.
+///
+/// The change can't be undone at that point, so failing to record it is only logged
+/// instead of turning an otherwise successful request into an error.
+pub async fn record_or_log(
+    db: &SqlitePool,
+    event: AuditEvent,
+    actor: Actor<'_>,
+    target_hash: Option<&str>,
+    details: Option<&str>,
+) {
+    if let Err(e) = record(db, event, actor, target_hash, details).await {
+        tracing::error!(?event, target_hash, "failed to record audit event: {e}");
+    }
+}
+
+/// A single event as stored in the database and exported as JSON
+#[derive(Debug, Serialize, FromRow)]
+pub struct AuditEntry {
+    id: i64,
+    event_ts: String,
+    event: AuditEvent,
+    actor_kind: ActorKind,
+    admin_username: Option<String>,
+    session_id: Option<i64>,
+    target_hash: Option<String>,
+    details: Option<String>,
+}
+
+/// A recent event as listed in the admin panel
+#[derive(Debug, Serialize)]
+pub struct RecentEvent {
+    event: &'static str,
+    actor: String,
+    target_hash: Option<String>,
+    details: Option<String>,
+    event_ts_pretty: String,
+}
+
+/// Look up the most recent events for the admin dashboard, newest first.
+pub async fn recent_events(aps: &AppState) -> Result<Vec<RecentEvent>, AppError> {
+    let entries: Vec<AuditEntry> =
+        sqlx::query_as("SELECT id, event_ts, event, actor_kind, admin_username, session_id, target_hash, details FROM audit_events ORDER BY id DESC LIMIT ?;")
+            .bind(DASHBOARD_EVENTS)
+            .fetch_all(&aps.db)
+            .await?;
+
+    let now = Utc::now();
+    Ok(entries
+        .into_iter()
+        .map(|e| RecentEvent {
+            event: e.event.label(),
+            actor: match (e.actor_kind, e.admin_username, e.session_id) {
+                (ActorKind::Admin, Some(username), Some(session_id)) => {
+                    format!("{username} (session #{session_id})")
+                }
+                (ActorKind::Admin, username, _) => username.unwrap_or_else(|| "admin".into()),
+                (ActorKind::Uploader, _, _) => "uploader".into(),
+                (ActorKind::System, _, _) => "system".into(),
+            },
+            target_hash: e.target_hash,
+            details: e.details,
+            event_ts_pretty: DateTime::parse_from_rfc3339(&e.event_ts)
+                .map(|v| format!("{} ago", pretty_print_delta(now, v)))
+                .unwrap_or_else(|_| "N/A".into()),
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    hash: Option<String>,
+}
+
+/// Endpoint where owners download the audit log as JSON, optionally only the events of one file.
+pub async fn audit_export(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(admin) = admin::current_admin(&aps, &jar).await? else {
+        return AppError::err(StatusCode::UNAUTHORIZED, "unauthorized");
+    };
+    if admin.role < Role::Owner {
+        return AppError::err(
+            StatusCode::FORBIDDEN,
+            "your role does not permit reading the audit log",
+        );
+    }
+
+    let hash = params.hash.filter(|v| !v.is_empty());
+    let entries: Vec<AuditEntry> = sqlx::query_as(
+        "SELECT id, event_ts, event, actor_kind, admin_username, session_id, target_hash, details FROM audit_events WHERE ? IS NULL OR target_hash = ? ORDER BY id;",
+    )
+    .bind(&hash)
+    .bind(&hash)
+    .fetch_all(&aps.db)
+    .await?;
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit_events.json\"",
+        )],
+        Json(entries),
+    ))
+}
@@ -1,15 +1,23 @@
 //! Background async task cleans up expired files and admin sessions
 
+use crate::audit::{Actor, AuditEvent};
+use crate::metrics::Removal;
 use crate::*;
 
+/// Time between two runs of the cleanup cronjob
+pub const CLEANUP_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(900);
+
 /// Async task that cleans up expired files and admin sessions every 15 minutes
 ///
 /// Is started by [main] and then runs indefinitely.
-/// Has three responsibilites:
+/// Has six responsibilites:
 /// 1) Deleting expired files, both from the database and from disk.
 /// 2) Clearing expired admin sessions from the session-db.
 /// 3) Decreasing the accumulated request count from the rate-limiter, thereby
 ///    implementing the leaky-bucket algorithm.
+/// 4) Removing abandoned resumable uploads, both from the database and from disk.
+/// 5) Removing counters of failed admin logins whose window and lockout have passed.
+/// 6) Removing abuse reports that were handled long ago.
 #[tracing::instrument(level = "info", skip(aps))]
 pub async fn cleanup_cronjob(aps: AppState) {
     // Run indefinitely.
@@ -17,7 +25,7 @@ pub async fn cleanup_cronjob(aps: AppState) {
         // It's completely fine if this only runs every 15 minutes.
         // All of the queries are written in a way that they check the expiration
         // time and will refuse to serve resources that still exist but have already expired.
-        tokio::time::sleep(tokio::time::Duration::from_secs(900)).await;
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
 
         // Acquire the rate-limiter.
         let mut rl = aps.rate_limiter.write().await;
@@ -27,7 +35,7 @@ pub async fn cleanup_cronjob(aps: AppState) {
             // Decrease, but ensure no underflows take place.
             // If the value would be below 0, simply insert 0.
             *v = v
-                .checked_sub(std::cmp::max(aps.conf.daily_request_limit_per_ip / 96, 1))
+                .checked_sub(std::cmp::max(aps.conf().daily_request_limit_per_ip / 96, 1))
                 .unwrap_or_default();
         }
 
@@ -46,20 +54,22 @@ pub async fn cleanup_cronjob(aps: AppState) {
             expiry_ts: String,
         }
 
-        let files: Vec<FileRow> =
-            match sqlx::query_as("SELECT efd_sha256sum, expiry_ts FROM uploaded_files;")
-                .fetch_all(&aps.db)
-                .await
-            {
-                Ok(rows) => rows,
-                Err(e) => {
-                    tracing::error!("failed to read files from database: {e}");
-                    // This cronjob must not fail.
-                    // If the db-query failed that's critical (which is why we log it)
-                    // but our best approach is nonetheless to simply try again after a while.
-                    continue;
-                }
-            };
+        // Members of bundles share their expiry and are removed along with their bundle.
+        let files: Vec<FileRow> = match sqlx::query_as(
+            "SELECT efd_sha256sum, expiry_ts FROM uploaded_files WHERE parent_id IS NULL;",
+        )
+        .fetch_all(&aps.db)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::error!("failed to read files from database: {e}");
+                // This cronjob must not fail.
+                // If the db-query failed that's critical (which is why we log it)
+                // but our best approach is nonetheless to simply try again after a while.
+                continue;
+            }
+        };
 
         // Now filter out all files that have not yet expired.
         let files = files
@@ -84,12 +94,21 @@ pub async fn cleanup_cronjob(aps: AppState) {
         // but sqlx currently makes this rather difficult, as I cannot bind a whole Vec
         // in the following query: "DELETE FROM uploaded_files WHERE efd_sha256sum IN (?)".
         for file in files {
-            match delete::cleanup_file(&file.efd_sha256sum, &aps.db).await {
+            match delete::cleanup_file(&file.efd_sha256sum, &aps).await {
                 Ok(_) => {
                     tracing::info!(
                         efd_sha256sum = file.efd_sha256sum,
                         "file expired and was automatically removed"
                     );
+                    metrics::METRICS.count_expiration(Removal::Expired);
+                    audit::record_or_log(
+                        &aps.db,
+                        AuditEvent::FileExpired,
+                        Actor::System,
+                        Some(&file.efd_sha256sum),
+                        None,
+                    )
+                    .await;
                 }
                 Err(e) => {
                     tracing::error!(
@@ -160,5 +179,70 @@ pub async fn cleanup_cronjob(aps: AppState) {
                 }
             };
         }
+
+        // Finally, query all upload sessions and delete the ones that have expired.
+        // Unfinished sessions expire once they've been idle for too long,
+        // finished ones have served their purpose by then as well.
+        #[derive(Debug, FromRow)]
+        struct UploadSessionRow {
+            upload_id_sha256sum: String,
+            expiry_ts: String,
+        }
+
+        let upload_sessions: Vec<UploadSessionRow> =
+            match sqlx::query_as("SELECT upload_id_sha256sum, expiry_ts FROM upload_sessions;")
+                .fetch_all(&aps.db)
+                .await
+            {
+                Ok(rows) => rows,
+                Err(e) => {
+                    tracing::error!("failed to read upload sessions from database: {e}");
+                    // This cronjob must not fail.
+                    // If the db-query failed that's critical (which is why we log it)
+                    // but our best approach is nonetheless to simply try again after a while.
+                    continue;
+                }
+            };
+
+        // Now filter out all upload sessions that have not yet expired.
+        let upload_sessions = upload_sessions
+            .into_iter()
+            .filter(|e| {
+                match has_expired(&e.expiry_ts) {
+                    Ok(exp) => exp,
+                    Err(e) => {
+                        // Parsing errors on the timestamp should never happen.
+                        // If they do, that's an indicator that the databse is corrupt.
+                        // Best we can do here is log the issue and pretend the entry has already
+                        // expired. This may cause uploads to get cleaned up early.
+                        tracing::error!("failed to parse timestamp: {}", e.message);
+                        true
+                    }
+                }
+            })
+            .collect_vec();
+
+        // Delete each upload session one after the other.
+        for upload_session in upload_sessions {
+            match tus::cleanup_session(&upload_session.upload_id_sha256sum, &aps.db).await {
+                Ok(_) => {
+                    tracing::info!("upload session expired and was automatically removed");
+                }
+                Err(e) => {
+                    tracing::error!("failed to delete upload session from database / disk: {e}");
+                }
+            }
+        }
+
+        // Last but not least, forget failed admin logins that no longer matter.
+        if let Err(e) = lockout::cleanup(&aps).await {
+            tracing::error!("failed to clean up failed admin logins: {}", e.message);
+        }
+        if let Err(e) = report::cleanup(&aps).await {
+            tracing::error!("failed to clean up resolved abuse reports: {}", e.message);
+        }
+
+        // Let the readiness probe know that the cronjob is still alive.
+        *aps.last_cleanup.write().await = Utc::now();
     }
 }
@@ -0,0 +1,215 @@
+//! Bulk actions of the admin panel, applied to many files at once in a single transaction
+
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::Role;
+use crate::admin::AdminSession;
+use crate::audit::AuditEvent;
+use crate::upload::Expiry;
+use crate::*;
+
+/// Most files a single bulk action may list explicitly
+const MAX_BULK_FILES: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BulkRequest {
+    /// Delete the selected files
+    Delete { hashes: Vec<String> },
+    /// Let the selected files expire after a duration or at an exact point in time
+    ChangeExpiry {
+        hashes: Vec<String>,
+        duration: Option<String>,
+        expiry_ts: Option<String>,
+    },
+    /// Delete every file uploaded by the given IpPrefix, e.g. after a spam wave
+    DeleteByIp { ip: String },
+}
+
+/// Outcome of a bulk action for a single file
+#[derive(Debug, Serialize)]
+pub struct BulkResult {
+    hash: String,
+    ok: bool,
+    message: String,
+}
+
+impl BulkResult {
+    fn new(hash: &str, ok: bool, message: &str) -> Self {
+        Self {
+            hash: hash.to_string(),
+            ok,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    results: Vec<BulkResult>,
+}
+
+/// Endpoint where the admin panel POSTs bulk actions, reporting the outcome for every file.
+///
+/// Files that don't exist are reported as such, while the action is applied to all others.
+/// Any other error rolls back the whole action.
+pub async fn bulk_endpoint(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Json(req): Json<BulkRequest>,
+) -> Result<Json<BulkResponse>, AppError> {
+    let Some(admin) = admin::current_admin(&aps, &jar).await? else {
+        return AppError::err(StatusCode::UNAUTHORIZED, "unauthorized");
+    };
+    if admin.role < Role::Deleter {
+        return AppError::err(
+            StatusCode::FORBIDDEN,
+            "your role does not permit modifying files",
+        );
+    }
+
+    let results = match req {
+        BulkRequest::Delete { hashes } => {
+            check_hashes(&hashes)?;
+            delete(&aps, &hashes, &admin, None).await?
+        }
+        BulkRequest::ChangeExpiry {
+            hashes,
+            duration,
+            expiry_ts,
+        } => {
+            check_hashes(&hashes)?;
+            let expiry = expiry::parse(duration.as_deref(), expiry_ts.as_deref(), &aps.conf())?;
+            change_expiry(&aps, &hashes, expiry, &admin).await?
+        }
+        BulkRequest::DeleteByIp { ip } => {
+            let Some(ip_prefix) = IpPrefix::parse_user_input(&ip) else {
+                return AppError::err(StatusCode::BAD_REQUEST, "invalid IP address");
+            };
+            let hashes: Vec<String> = sqlx::query_scalar(
+                "SELECT efd_sha256sum FROM uploaded_files WHERE upload_ip = ? AND parent_id IS NULL;",
+            )
+            .bind(ip_prefix.to_string())
+            .fetch_all(&aps.db)
+            .await?;
+            tracing::info!(
+                ip = ip_prefix.pretty_print(),
+                admin = admin.username,
+                "deleting all {} file(s) uploaded by IP",
+                hashes.len()
+            );
+            let details = format!("uploaded by {}", ip_prefix.pretty_print());
+            delete(&aps, &hashes, &admin, Some(&details)).await?
+        }
+    };
+
+    Ok(Json(BulkResponse { results }))
+}
+
+/// Reject selections that are empty or unreasonably large.
+fn check_hashes(hashes: &[String]) -> Result<(), AppError> {
+    if hashes.is_empty() {
+        return AppError::err(StatusCode::BAD_REQUEST, "no files selected");
+    }
+    if hashes.len() > MAX_BULK_FILES {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_BULK_FILES} files can be selected at once"),
+        );
+    }
+    Ok(())
+}
+
+async fn delete(
+    aps: &AppState,
+    hashes: &[String],
+    admin: &AdminSession,
+    details: Option<&str>,
+) -> Result<Vec<BulkResult>, AppError> {
+    let mut results = Vec::with_capacity(hashes.len());
+    let mut filedata = vec![];
+
+    let mut tx = aps.db.begin().await?;
+    for hash in hashes {
+        match delete::delete_rows(&mut tx, hash).await? {
+            Some(v) => {
+                filedata.extend(v);
+                audit::record(
+                    &mut *tx,
+                    AuditEvent::FileDeleted,
+                    admin.into(),
+                    Some(hash),
+                    details,
+                )
+                .await?;
+                results.push(BulkResult::new(hash, true, "deleted"));
+            }
+            None => results.push(BulkResult::new(hash, false, "file not found or expired")),
+        }
+    }
+    tx.commit().await?;
+    metrics::METRICS.count_deletions(results.iter().filter(|v| v.ok).count() as u64);
+
+    // The rows are gone for good, so the filedata can't be served anymore either way.
+    // Should removing it fail, it's merely wasting space and is logged for the operator.
+    for efd_sha256sum in filedata {
+        if let Err(e) = aps.storage.delete(&efd_sha256sum).await {
+            tracing::error!(efd_sha256sum, "failed to delete filedata: {e}");
+        }
+    }
+
+    for result in results.iter().filter(|v| v.ok) {
+        tracing::info!(
+            efd_sha256sum = result.hash,
+            admin = admin.username,
+            "manually deleted file"
+        );
+    }
+    Ok(results)
+}
+
+async fn change_expiry(
+    aps: &AppState,
+    hashes: &[String],
+    expiry: Expiry,
+    admin: &AdminSession,
+) -> Result<Vec<BulkResult>, AppError> {
+    let mut results = Vec::with_capacity(hashes.len());
+    let mut changed = Vec::new();
+
+    let mut tx = aps.db.begin().await?;
+    for hash in hashes {
+        // Every file is bounded by its own upload, so some may be extended while others can't.
+        let expiry_ts = match expiry::apply(aps, &mut tx, hash, expiry).await {
+            Ok(v) => v,
+            Err(e) if e.status_code.is_client_error() => {
+                results.push(BulkResult::new(hash, false, &e.message));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        audit::record(
+            &mut *tx,
+            AuditEvent::ExpiryChanged,
+            admin.into(),
+            Some(hash),
+            Some(&expiry_ts),
+        )
+        .await?;
+        results.push(BulkResult::new(hash, true, "expiry changed"));
+        changed.push((hash, expiry_ts));
+    }
+    tx.commit().await?;
+
+    for (hash, expiry_ts) in changed {
+        tracing::info!(
+            efd_sha256sum = hash,
+            expiry_ts,
+            admin = admin.username,
+            "changed expiry of file"
+        );
+    }
+    Ok(results)
+}
@@ -0,0 +1,231 @@
+//! Endpoint and utilities for bundles, which share several files under a single link
+//!
+//! A bundle is a row in 'uploaded_files' without any filedata of its own.
+//! Its members are regular files that reference the bundle through their parent_id.
+//! They are downloaded individually, but expire and get deleted together with their bundle.
+
+use axum::{extract::State, http::StatusCode, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::{prelude::*, rng};
+use serde::Deserialize;
+
+use crate::audit::{Actor, AuditEvent};
+use crate::upload::{self, Expiry, PassphraseKdf, UploadFileResponse};
+use crate::*;
+
+/// Maximum number of files a single bundle may contain
+///
+/// Clients derive each member's IVs from its index, reserving two bytes of the IV for it.
+pub const MAX_BUNDLE_MEMBERS: usize = 256;
+
+/// A bundle's database row, as far as its members are concerned
+#[derive(Debug, FromRow)]
+pub struct Bundle {
+    pub id: i64,
+    pub efd_sha256sum: String,
+    pub filesize: i64,
+    pub expiry_ts: String,
+    pub max_downloads: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BundleRequest {
+    duration: Option<String>,
+    expiry_ts: Option<String>,
+    max_downloads: Option<i64>,
+    kdf_salt: Option<String>,
+    kdf_iterations: Option<i64>,
+}
+
+/// Endpoint where clients create an empty bundle to then upload its members into
+///
+/// Members are uploaded through the tus-endpoints, presenting the bundle's admin key.
+/// Clients that upload all files in a single request use the upload_endpoint instead.
+pub async fn bundle_endpoint(
+    State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    Json(req): Json<BundleRequest>,
+) -> Result<(StatusCode, Json<UploadFileResponse>), AppError> {
+    let expiry = Expiry::from_fields(
+        req.duration
+            .map(|v| upload::parse_duration(v.as_bytes()))
+            .transpose()?,
+        req.expiry_ts
+            .map(|v| upload::parse_expiry_ts(v.as_bytes()))
+            .transpose()?,
+    )?;
+    expiry.check(&aps.conf())?;
+    let max_downloads = req
+        .max_downloads
+        .map(upload::check_max_downloads)
+        .transpose()?;
+    let passphrase = PassphraseKdf::from_fields(
+        req.kdf_salt
+            .map(|v| {
+                let salt = URL_SAFE_NO_PAD.decode(v).map_err(|_| {
+                    AppError::new(StatusCode::BAD_REQUEST, "kdf_salt is not base64url-encoded")
+                })?;
+                upload::parse_kdf_salt(&salt)
+            })
+            .transpose()?,
+        req.kdf_iterations
+            .map(upload::check_kdf_iterations)
+            .transpose()?,
+    )?;
+
+    // A bundle counts as a single upload, regardless of how many files it ends up containing.
+    upload::check_upload_limits(&aps, &eip).await?;
+
+    let (bundle, admin_key) = create_bundle(&aps, &eip, expiry, max_downloads, passphrase).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadFileResponse {
+            efd_sha256sum: bundle.efd_sha256sum,
+            admin_key,
+        }),
+    ))
+}
+
+/// Create a new, empty bundle and return it alongside its admin key.
+///
+/// The download limit, if any, applies to each of the bundle's members individually,
+/// whereas the passphrase, if any, protects the bundle as a whole.
+pub async fn create_bundle(
+    aps: &AppState,
+    eip: &IpPrefix,
+    expiry: Expiry,
+    max_downloads: Option<i64>,
+    passphrase: Option<PassphraseKdf>,
+) -> Result<(Bundle, String), AppError> {
+    // Bundles have no filedata that could be hashed, so they're identified by random bytes.
+    // Their identifiers are indistinguishable from the hashes of regular files.
+    let efd_sha256sum = URL_SAFE_NO_PAD.encode(rng().random::<[u8; 32]>());
+    let (admin_key, admin_key_sha256sum) = upload::generate_admin_key();
+    let (upload_ts, expiry_ts) = upload::upload_and_expiry_ts(aps, expiry)?;
+
+    let id: i64 = sqlx::query_scalar("INSERT INTO uploaded_files (efd_sha256sum, admin_key_sha256sum, e_filename, iv_fd, iv_fn, filesize, upload_ip, upload_ts, expiry_ts, is_bundle, max_downloads, kdf_salt, kdf_iterations) VALUES (?, ?, X'', X'', X'', 0, ?, ?, ?, 1, ?, ?, ?) RETURNING id;")
+        .bind(&efd_sha256sum)
+        .bind(&admin_key_sha256sum)
+        .bind(eip.to_string())
+        .bind(&upload_ts)
+        .bind(&expiry_ts)
+        .bind(max_downloads)
+        .bind(passphrase.map(|v| v.salt.to_vec()))
+        .bind(passphrase.map(|v| v.iterations))
+        .fetch_one(&aps.db)
+        .await
+        .map_err(|e| AppError::new500(format!("failed to insert row into database: {e}")))?;
+
+    tracing::info!(
+        efd_sha256sum,
+        expiry_ts,
+        max_downloads,
+        passphrase = passphrase.is_some(),
+        "succesfully created new bundle"
+    );
+    audit::record_or_log(
+        &aps.db,
+        AuditEvent::FileUploaded,
+        Actor::Uploader,
+        Some(&efd_sha256sum),
+        Some(&format!("bundle, expires {expiry_ts}")),
+    )
+    .await;
+
+    Ok((
+        Bundle {
+            id,
+            efd_sha256sum,
+            filesize: 0,
+            expiry_ts,
+            max_downloads,
+        },
+        admin_key,
+    ))
+}
+
+/// Look up a bundle by its row id, treating expired bundles as nonexistent.
+pub async fn find_bundle(aps: &AppState, id: i64) -> Result<Bundle, AppError> {
+    let row: Option<Bundle> = sqlx::query_as("SELECT id, efd_sha256sum, filesize, expiry_ts, max_downloads FROM uploaded_files WHERE id = ? AND is_bundle = 1 LIMIT 1;")
+        .bind(id)
+        .fetch_optional(&aps.db)
+        .await?;
+
+    match row {
+        Some(row) if !has_expired(&row.expiry_ts)? => Ok(row),
+        _ => AppError::err(StatusCode::NOT_FOUND, "bundle not found or expired"),
+    }
+}
+
+/// Look up a bundle by its identifier and ensure the given admin key belongs to it.
+///
+/// Only the owner of a bundle is allowed to add files to it.
+pub async fn authorize_bundle(
+    aps: &AppState,
+    efd_sha256sum: &str,
+    admin_key: &str,
+) -> Result<Bundle, AppError> {
+    #[derive(Debug, FromRow)]
+    struct BundleRow {
+        id: i64,
+        admin_key_sha256sum: String,
+    }
+
+    let row: Option<BundleRow> = sqlx::query_as("SELECT id, admin_key_sha256sum FROM uploaded_files WHERE efd_sha256sum = ? AND is_bundle = 1 LIMIT 1;")
+        .bind(efd_sha256sum)
+        .fetch_optional(&aps.db)
+        .await?;
+
+    let row =
+        row.ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "bundle not found or expired"))?;
+
+    if !admin_key_matches(admin_key, &row.admin_key_sha256sum) {
+        return AppError::err(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    find_bundle(aps, row.id).await
+}
+
+/// Ensure another file of the given (encrypted) size still fits into the bundle.
+///
+/// The maximum filesize applies to a bundle as a whole, so that it can't be used
+/// to circumvent the limit. Members that are still being uploaded count as well.
+pub async fn check_bundle_capacity(
+    aps: &AppState,
+    bundle: &Bundle,
+    filesize: u64,
+) -> Result<(), AppError> {
+    #[derive(Debug, FromRow)]
+    struct PendingRow {
+        count: i64,
+        upload_length: i64,
+    }
+
+    let members: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM uploaded_files WHERE parent_id = ?;")
+            .bind(bundle.id)
+            .fetch_one(&aps.db)
+            .await?;
+
+    let pending: PendingRow = sqlx::query_as("SELECT COUNT(*) AS count, COALESCE(SUM(upload_length), 0) AS upload_length FROM upload_sessions WHERE parent_id = ? AND efd_sha256sum IS NULL;")
+        .bind(bundle.id)
+        .fetch_one(&aps.db)
+        .await?;
+
+    if (members + pending.count) as usize >= MAX_BUNDLE_MEMBERS {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            format!("bundles may contain at most {MAX_BUNDLE_MEMBERS} files"),
+        );
+    }
+
+    if (bundle.filesize + pending.upload_length) as u64 + filesize > aps.conf().maximum_filesize {
+        return AppError::err(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "bundle would exceed the maximum filesize",
+        );
+    }
+
+    Ok(())
+}
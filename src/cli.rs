@@ -0,0 +1,449 @@
+//! Companion command-line client for sharing files without a browser
+//!
+//! Files are encrypted and decrypted exactly like 'upload.js' and 'download.js' do it,
+//! so links created on the command line work in the browser and vice versa.
+//! The client talks to the server exclusively through the REST API.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use clap::Subcommand;
+use futures_util::{stream, StreamExt};
+use inquire::Password;
+use rand::{prelude::*, rng};
+use reqwest::{
+    multipart::{Form, Part},
+    Body, Client, Response, Url,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::api::FileMetadata;
+use crate::upload::UploadFileResponse;
+
+/// Size of the plaintext segments files are split into before encryption (format version 2)
+///
+/// Has to match SEGMENT_SIZE in 'common.js'.
+const SEGMENT_SIZE: u64 = 4194304;
+
+/// Every encrypted segment is larger than its plaintext by the AES-GCM authentication tag.
+const TAG_SIZE: u64 = 16;
+
+/// PBKDF2 iterations for deriving the key that wraps the file key from a passphrase
+///
+/// Has to match KDF_ITERATIONS in 'upload.js'.
+const KDF_ITERATIONS: u32 = 600000;
+
+/// IV the file key is wrapped with, keeping it apart from the IVs of filenames and filedata
+const WRAP_IV: [u8; 12] = [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Operations of the command-line client
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Encrypt and upload one or several files, then print the download and admin links
+    ///
+    /// Several files are shared as a bundle under a single link.
+    Upload {
+        /// Base URL of the FerriShare instance, e.g. 'https://share.example.com'
+        #[arg(long, env = "FERRISHARE_SERVER")]
+        server: Url,
+
+        /// How long the files are kept, one of the server's expiry options
+        #[arg(long, default_value = "1d", conflicts_with = "expiry_ts")]
+        expiry: String,
+
+        /// Exact RFC3339 timestamp at which the files expire, if the server permits it
+        #[arg(long, value_name = "TIMESTAMP")]
+        expiry_ts: Option<String>,
+
+        /// Remove each file after it has been downloaded this many times
+        #[arg(long, value_name = "N")]
+        max_downloads: Option<u64>,
+
+        /// Additionally protect the link with a passphrase
+        #[arg(long, env = "FERRISHARE_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+
+        /// The files to share
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Download and decrypt all files behind a download link
+    Download {
+        /// The download link, including the '#key=...' part
+        link: Url,
+
+        /// Directory to save the files in
+        #[arg(short, long, default_value = ".", value_name = "DIR")]
+        output: PathBuf,
+
+        /// The link's passphrase, prompted for interactively if required but not provided
+        #[arg(long, env = "FERRISHARE_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+    },
+}
+
+/// Run the command-line client and report any error on stderr.
+pub async fn run(command: Command) -> ExitCode {
+    let result = match command {
+        Command::Upload {
+            server,
+            expiry,
+            expiry_ts,
+            max_downloads,
+            passphrase,
+            files,
+        } => upload(server, expiry, expiry_ts, max_downloads, passphrase, files).await,
+        Command::Download {
+            link,
+            output,
+            passphrase,
+        } => download(link, output, passphrase).await,
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Number of segments a plaintext of the given size is split into.
+///
+/// Empty files still consist of a single (empty) segment.
+fn segment_count(plaintext_size: u64) -> u64 {
+    plaintext_size.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+/// Derive the IV of a single filedata segment from the file's base IV.
+///
+/// Bytes 0-2 are copied from the base IV, bytes 3-10 hold the segment index (big-endian)
+/// and byte 11 marks the final segment, just like segmentIv in 'common.js'.
+fn segment_iv(base_iv: &[u8; 12], index: u64, last: bool) -> [u8; 12] {
+    let mut iv = [0u8; 12];
+    iv[..3].copy_from_slice(&base_iv[..3]);
+    iv[3..11].copy_from_slice(&index.to_be_bytes());
+    iv[11] = last as u8;
+    iv
+}
+
+/// The IVs of the filename and the filedata of the n-th file of an upload.
+///
+/// All files of a bundle share the same key, so bytes 1-2 hold the file's index.
+fn member_ivs(index: usize) -> ([u8; 12], [u8; 12]) {
+    let [hi, lo] = (index as u16).to_be_bytes();
+    (
+        [0, hi, lo, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        [1, hi, lo, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    )
+}
+
+/// Derive the key that wraps a file key from a passphrase using PBKDF2-HMAC-SHA256.
+fn passphrase_key(passphrase: &str, salt: &[u8], iterations: u32) -> Aes256Gcm {
+    let key = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(passphrase.as_bytes(), salt, iterations);
+    Aes256Gcm::new(&key.into())
+}
+
+/// Turn an unsuccessful response into an error carrying the server's message.
+async fn check_response(response: Response) -> Result<Response, anyhow::Error> {
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        message: String,
+    }
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // Errors from the REST API are JSON, but proxies in between might respond differently.
+    let text = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<ErrorBody>(&text).map_or(text, |v| v.message);
+    Err(anyhow!("server responded with {status}: {message}"))
+}
+
+/// Encrypt and upload the given files, then print the download and admin links.
+async fn upload(
+    server: Url,
+    expiry: String,
+    expiry_ts: Option<String>,
+    max_downloads: Option<u64>,
+    passphrase: Option<String>,
+    files: Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    // Generate a random key to use for encryption.
+    let raw_key = rng().random::<[u8; 32]>();
+    let key = Aes256Gcm::new(&raw_key.into());
+
+    let mut form = Form::new();
+
+    for (index, path) in files.iter().enumerate() {
+        let filename = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .ok_or_else(|| anyhow!("{path:?} does not have a valid filename"))?;
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("failed to open {path:?}"))?;
+        let size = file.metadata().await?.len();
+        let (iv_fn, iv_fd) = member_ivs(index);
+
+        let e_filename = key
+            .encrypt(Nonce::from_slice(&iv_fn), filename.as_bytes())
+            .map_err(|_| anyhow!("failed to encrypt filename"))?;
+
+        // The filedata is encrypted in segments while it is being sent (format version 2),
+        // so that only a single segment has to be held in memory at any given time.
+        let segments = segment_count(size);
+        let upload_length = size + TAG_SIZE * segments;
+        let segment_key = key.clone();
+        let e_filedata = stream::try_unfold((file, 0), move |(mut file, index)| {
+            let key = segment_key.clone();
+            async move {
+                if index == segments {
+                    return Ok(None);
+                }
+                let mut segment = Vec::with_capacity(SEGMENT_SIZE as usize);
+                (&mut file)
+                    .take(SEGMENT_SIZE)
+                    .read_to_end(&mut segment)
+                    .await?;
+                let iv = segment_iv(&iv_fd, index, index == segments - 1);
+                let e_segment = key
+                    .encrypt(Nonce::from_slice(&iv), segment.as_slice())
+                    .map_err(|_| std::io::Error::other("failed to encrypt segment"))?;
+                Ok::<_, std::io::Error>(Some((e_segment, (file, index + 1))))
+            }
+        });
+
+        // The n-th occurrence of each of these fields belongs to the n-th file.
+        form = form
+            .part(
+                "e_filedata",
+                Part::stream_with_length(Body::wrap_stream(e_filedata), upload_length),
+            )
+            .part("e_filename", Part::bytes(e_filename))
+            .part("iv_fd", Part::bytes(iv_fd.to_vec()))
+            .part("iv_fn", Part::bytes(iv_fn.to_vec()));
+    }
+
+    // The remaining fields apply to all files at once.
+    form = match expiry_ts {
+        Some(expiry_ts) => form.text("expiry_ts", expiry_ts),
+        None => form.text("duration", expiry),
+    };
+    form = form.text("format_version", "2");
+    if let Some(max_downloads) = max_downloads {
+        form = form.text("max_downloads", max_downloads.to_string());
+    }
+
+    // With a passphrase, the link only holds the key wrapped with one derived from it.
+    let link_key = match passphrase {
+        Some(passphrase) => {
+            let salt = rng().random::<[u8; 16]>();
+            form = form
+                .part("kdf_salt", Part::bytes(salt.to_vec()))
+                .text("kdf_iterations", KDF_ITERATIONS.to_string());
+            passphrase_key(&passphrase, &salt, KDF_ITERATIONS)
+                .encrypt(Nonce::from_slice(&WRAP_IV), raw_key.as_slice())
+                .map_err(|_| anyhow!("failed to wrap key"))?
+        }
+        None => raw_key.to_vec(),
+    };
+    let link_key = URL_SAFE_NO_PAD.encode(link_key);
+
+    eprintln!("Uploading {} file(s) to {server} ...", files.len());
+    let response = Client::new()
+        .post(server.join("/api/v1/files")?)
+        .multipart(form)
+        .send()
+        .await
+        .context("failed to reach server")?;
+    let result: UploadFileResponse = check_response(response).await?.json().await?;
+
+    let base = server.as_str().trim_end_matches('/');
+    println!(
+        "Download link: {base}/file?hash={}#key={link_key}",
+        result.efd_sha256sum
+    );
+    println!(
+        "Admin link:    {base}/file?hash={}&admin={}#key={link_key}",
+        result.efd_sha256sum, result.admin_key
+    );
+
+    Ok(())
+}
+
+/// Download and decrypt all files behind the given download link.
+async fn download(
+    link: Url,
+    output: PathBuf,
+    passphrase: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let hash = link
+        .query_pairs()
+        .find(|(k, _)| k == "hash")
+        .map(|(_, v)| v.into_owned())
+        .ok_or_else(|| anyhow!("the link does not contain a hash"))?;
+    let link_key = link
+        .fragment()
+        .and_then(|v| v.strip_prefix("key="))
+        .ok_or_else(|| anyhow!("the link does not contain a key"))?;
+    let link_key = URL_SAFE_NO_PAD
+        .decode(link_key)
+        .map_err(|_| anyhow!("the link's key is not base64url-encoded"))?;
+
+    let client = Client::new();
+    let response = client
+        .get(link.join(&format!("/api/v1/files/{hash}"))?)
+        .send()
+        .await
+        .context("failed to reach server")?;
+    let metadata: FileMetadata = check_response(response).await?.json().await?;
+
+    // Passphrase-protected links hold a wrapped key instead of the actual one.
+    let raw_key = match metadata.passphrase {
+        Some(kdf) => {
+            let passphrase = match passphrase {
+                Some(v) => v,
+                None => Password::new("Passphrase:")
+                    .with_display_mode(inquire::PasswordDisplayMode::Masked)
+                    .without_confirmation()
+                    .prompt()?,
+            };
+            let salt = URL_SAFE_NO_PAD.decode(kdf.salt)?;
+            // AES-GCM's authentication tag makes sure a wrong passphrase is detected here.
+            passphrase_key(&passphrase, &salt, kdf.iterations.try_into()?)
+                .decrypt(Nonce::from_slice(&WRAP_IV), link_key.as_slice())
+                .map_err(|_| anyhow!("wrong passphrase"))?
+        }
+        None => link_key,
+    };
+    let key =
+        Aes256Gcm::new_from_slice(&raw_key).map_err(|_| anyhow!("the link's key is invalid"))?;
+
+    for member in metadata.members {
+        let iv_fn = URL_SAFE_NO_PAD.decode(&member.iv_fn)?;
+        let iv_fd: [u8; 12] = URL_SAFE_NO_PAD
+            .decode(&member.iv_fd)?
+            .try_into()
+            .map_err(|_| anyhow!("the file's IV is invalid"))?;
+        let filename = key
+            .decrypt(
+                Nonce::from_slice(&iv_fn),
+                URL_SAFE_NO_PAD.decode(&member.e_filename)?.as_slice(),
+            )
+            .map_err(|_| {
+                anyhow!("could not decrypt filename, the link's key is probably corrupt")
+            })?;
+        let filename = String::from_utf8(filename)?;
+
+        // Never let the sender decide where the file ends up, only what it is called.
+        let filename = Path::new(&filename)
+            .file_name()
+            .ok_or_else(|| anyhow!("the file has an invalid filename: {filename:?}"))?;
+        let path = output.join(filename);
+
+        if member.remaining_downloads == Some(0) {
+            eprintln!("Skipping {path:?}, it has reached its download limit");
+            continue;
+        }
+
+        eprintln!("Downloading {path:?} ...");
+        let response = client
+            .get(link.join(&format!("/api/v1/files/{}/data", member.efd_sha256sum))?)
+            .send()
+            .await
+            .context("failed to reach server")?;
+        let response = check_response(response).await?;
+
+        let file = tokio::fs::File::create_new(&path)
+            .await
+            .with_context(|| format!("failed to create {path:?}"))?;
+        let result = match member.format_version {
+            1 => save_single(response, file, &key, &iv_fd).await,
+            _ => save_segmented(response, file, &key, &iv_fd, member.filesize as u64).await,
+        };
+        // Don't leave a partial file behind that might be mistaken for the real one.
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e.context(format!("failed to download {path:?}")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Decrypt a file that was encrypted in a single operation (format version 1).
+async fn save_single(
+    response: Response,
+    mut file: tokio::fs::File,
+    key: &Aes256Gcm,
+    iv_fd: &[u8; 12],
+) -> Result<(), anyhow::Error> {
+    let e_filedata = response.bytes().await?;
+    let filedata = key
+        .decrypt(Nonce::from_slice(iv_fd), e_filedata.as_ref())
+        .map_err(|_| anyhow!("could not decrypt file"))?;
+    file.write_all(&filedata).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Decrypt a file that was encrypted in fixed-size segments (format version 2).
+///
+/// Every segment is decrypted and written to disk as soon as it has fully arrived.
+async fn save_segmented(
+    response: Response,
+    mut file: tokio::fs::File,
+    key: &Aes256Gcm,
+    iv_fd: &[u8; 12],
+    filesize: u64,
+) -> Result<(), anyhow::Error> {
+    let e_segment_size = (SEGMENT_SIZE + TAG_SIZE) as usize;
+    let segments = filesize.div_ceil(SEGMENT_SIZE + TAG_SIZE).max(1);
+
+    let mut stream = response.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::with_capacity(e_segment_size);
+    let mut index = 0;
+
+    loop {
+        let chunk = stream.next().await.transpose()?;
+        let done = chunk.is_none();
+        if let Some(chunk) = chunk {
+            buffer.extend_from_slice(&chunk);
+        }
+
+        // Once the stream is done the remaining bytes form the final, possibly shorter, segment.
+        while buffer.len() >= e_segment_size || (done && !buffer.is_empty()) {
+            let end = buffer.len().min(e_segment_size);
+            let iv = segment_iv(iv_fd, index, index == segments - 1);
+            let segment = key
+                .decrypt(Nonce::from_slice(&iv), &buffer[..end])
+                .map_err(|_| anyhow!("could not decrypt file"))?;
+            file.write_all(&segment).await?;
+            buffer.drain(..end);
+            index += 1;
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    // A missing final segment means the download was cut short.
+    if index != segments {
+        return Err(anyhow!(
+            "expected {segments} segments, but received {index}"
+        ));
+    }
+
+    file.flush().await?;
+    Ok(())
+}
@@ -7,11 +7,15 @@ use std::{
 };
 
 use anyhow::anyhow;
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::TimeDelta;
 use inquire::{validator::Validation, Confirm, CustomUserError, Password, Select, Text};
+use rand::{prelude::*, rng};
 use serde::{Deserialize, Serialize};
 use tracing::Level;
 
+use crate::accounts::hash_password;
+use crate::storage::StorageConfiguration;
 use crate::*;
 
 /// Global configuration for the entire application read from 'config.toml'.
@@ -23,7 +27,12 @@ pub struct AppConfiguration {
     pub app_name: String,
     pub interface: String,
     pub proxy_depth: u64,
-    pub admin_password_hash: String,
+    // Without a public URL, the origin of browser requests is derived from their headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_url: Option<String>,
+    // Only used to create the initial 'admin' account, see accounts::seed_initial_admin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_password_hash: Option<String>,
     pub maximum_filesize: u64,
     pub maximum_quota: u64,
     pub maximum_uploads_per_ip: u64,
@@ -32,6 +41,39 @@ pub struct AppConfiguration {
     pub enable_privacy_policy: bool,
     pub enable_legal_notice: bool,
     pub demo_mode: bool,
+    // Configurations that predate the expiry settings keep the previously hardcoded choices.
+    #[serde(default = "default_expiry_options")]
+    pub expiry_options: Vec<String>,
+    #[serde(default = "default_default_expiry")]
+    pub default_expiry: String,
+    #[serde(default = "default_maximum_expiry")]
+    pub maximum_expiry: String,
+    #[serde(default)]
+    pub allow_custom_expiry: bool,
+    // Configurations that predate the storage backends keep their files on the local disk.
+    #[serde(default)]
+    pub storage: StorageConfiguration,
+    #[serde(default = "default_daily_report_limit_per_ip")]
+    pub daily_report_limit_per_ip: u64,
+    // The '/metrics' endpoint is only served if a token has been configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_token: Option<String>,
+}
+
+fn default_expiry_options() -> Vec<String> {
+    vec!["1h".into(), "1d".into(), "1w".into()]
+}
+
+fn default_default_expiry() -> String {
+    "1d".into()
+}
+
+fn default_maximum_expiry() -> String {
+    "1w".into()
+}
+
+fn default_daily_report_limit_per_ip() -> u64 {
+    10
 }
 
 impl AppConfiguration {
@@ -44,6 +86,102 @@ impl AppConfiguration {
             _ => Level::INFO,
         }
     }
+
+    /// Ensure the settings are consistent beyond what deserialization alone can check.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let maximum_expiry = transform_duration_input(&self.maximum_expiry).ok_or_else(|| {
+            anyhow!(
+                "maximum_expiry '{}' is not a valid duration",
+                self.maximum_expiry
+            )
+        })?;
+        if self.expiry_options.is_empty() {
+            return Err(anyhow!("expiry_options must contain at least one duration"));
+        }
+        for option in &self.expiry_options {
+            let duration = transform_duration_input(option)
+                .ok_or_else(|| anyhow!("expiry option '{option}' is not a valid duration"))?;
+            if duration > maximum_expiry {
+                return Err(anyhow!(
+                    "expiry option '{option}' exceeds maximum_expiry '{}'",
+                    self.maximum_expiry
+                ));
+            }
+        }
+        if let Some(ref public_url) = self.public_url {
+            validate_public_url(public_url)?;
+        }
+        if self.metrics_token.as_ref().is_some_and(|v| v.is_empty()) {
+            return Err(anyhow!("metrics_token must not be empty"));
+        }
+        if !self.expiry_options.contains(&self.default_expiry) {
+            return Err(anyhow!(
+                "default_expiry '{}' is not one of the expiry_options",
+                self.default_expiry
+            ));
+        }
+        Ok(())
+    }
+
+    /// The configured expiry options as durations, in the order they were configured.
+    ///
+    /// Invalid options are skipped, but can't occur once the configuration has been validated.
+    pub fn expiry_durations(&self) -> Vec<TimeDelta> {
+        self.expiry_options
+            .iter()
+            .filter_map(|v| transform_duration_input(v))
+            .collect()
+    }
+
+    /// The configured expiry options along with their labels, ready to be templated.
+    pub fn labeled_expiry_options(&self) -> Vec<ExpiryOption> {
+        self.expiry_options
+            .iter()
+            .map(|v| ExpiryOption {
+                value: v.clone(),
+                label: format_duration_input(v),
+            })
+            .collect()
+    }
+
+    /// The longest duration any upload may be stored for.
+    pub fn maximum_expiry_duration(&self) -> TimeDelta {
+        transform_duration_input(&self.maximum_expiry).unwrap_or_default()
+    }
+}
+
+/// Check that the public URL consists of nothing but a scheme, host and optional port.
+fn validate_public_url(input: &str) -> Result<(), anyhow::Error> {
+    let url = url::Url::parse(input)
+        .map_err(|e| anyhow!("public_url '{input}' is not a valid URL: {e}"))?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        return Err(anyhow!(
+            "public_url '{input}' must start with 'https://' or 'http://', followed by a host"
+        ));
+    }
+    if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+        return Err(anyhow!(
+            "public_url '{input}' must not contain a path, query or fragment"
+        ));
+    }
+    Ok(())
+}
+
+/// Validator for 'inquire' to check that the public URL is either empty or valid.
+fn validate_public_url_input(input: &str) -> Result<Validation, CustomUserError> {
+    match input.is_empty() || validate_public_url(input).is_ok() {
+        true => Ok(Validation::Valid),
+        false => Ok(Validation::Invalid(
+            "Failed to parse URL. Use values like 'https://files.example.com'.".into(),
+        )),
+    }
+}
+
+/// An expiry option as offered by the frontend, e.g. '1d' labeled as '1 day'
+#[derive(Debug, Serialize)]
+pub struct ExpiryOption {
+    pub value: String,
+    pub label: String,
 }
 
 /// Translate a filesize string to the actual number of bytes it represents.
@@ -54,7 +192,7 @@ impl AppConfiguration {
 ///     '5G' ->   5 GiB -> 5_368_709_120 Bytes
 fn transform_filesize_input(input: &str) -> Option<u64> {
     // Split the string into number and suffix.
-    let (number_str, suffix) = input.split_at(input.len() - 1);
+    let (number_str, suffix) = input.split_at_checked(input.len().checked_sub(1)?)?;
     // Try to parse the number.
     let number = number_str.parse::<u64>().ok();
     // Next, try to parse the suffix and return the actual byte value.
@@ -66,30 +204,76 @@ fn transform_filesize_input(input: &str) -> Option<u64> {
     }
 }
 
-/// Validator for 'inquire' to check that the filesize input is valid.
-fn validate_filesize_input(input: &str) -> Result<Validation, CustomUserError> {
-    match transform_filesize_input(input) {
+/// Translate an expiry duration string to the actual duration it represents.
+///
+/// The prompt uses suffixes 'm', 'h', 'd' and 'w' for minutes, hours, days and weeks:
+///   '10m' -> 10 minutes
+///    '3d' ->  3 days
+///   '30d' -> 30 days
+pub fn transform_duration_input(input: &str) -> Option<TimeDelta> {
+    // Split the string into number and suffix.
+    let input = input.trim();
+    let (number_str, suffix) = input.split_at_checked(input.len().checked_sub(1)?)?;
+    // Try to parse the number. Durations of zero make no sense for an upload.
+    let number = number_str.parse::<i64>().ok().filter(|n| *n > 0)?;
+    // Next, try to parse the suffix and return the actual duration.
+    match suffix {
+        "m" => TimeDelta::try_minutes(number),
+        "h" => TimeDelta::try_hours(number),
+        "d" => TimeDelta::try_days(number),
+        "w" => TimeDelta::try_weeks(number),
+        _ => None,
+    }
+}
+
+/// Formats an expiry duration such as '3d' as '3 days' for display on the upload page.
+pub fn format_duration_input(input: &str) -> String {
+    let input = input.trim();
+    let Some((number_str, suffix)) = input.split_at_checked(input.len().saturating_sub(1)) else {
+        return input.to_string();
+    };
+    let unit = match suffix {
+        "m" => "minute",
+        "h" => "hour",
+        "d" => "day",
+        "w" => "week",
+        _ => return input.to_string(),
+    };
+    if number_str == "1" {
+        format!("1 {unit}")
+    } else {
+        format!("{number_str} {unit}s")
+    }
+}
+
+/// Validator for 'inquire' to check that the duration input is valid.
+fn validate_duration_input(input: &str) -> Result<Validation, CustomUserError> {
+    match transform_duration_input(input) {
         Some(_) => Ok(Validation::Valid),
         None => Ok(Validation::Invalid(
-            "Failed to parse filesize. Use values like '100K', '25M' or '5G'.".into(),
+            "Failed to parse duration. Use values like '10m', '12h', '3d' or '2w'.".into(),
         )),
     }
 }
 
-/// Validator for 'inquire' to check that the maximum filesize input is valid.
-///
-/// This is similar to [validate_filesize_input] but also checks that the given filesize
-/// stays at or below 2^39 - 256 bits = 68719476704 Bytes =~= 64GiB.
-/// This is required by AES-GCM. If the message length exceeds that length the cypher
-/// breaks down and loses its secure properties.
-fn validate_max_filesize_input(input: &str) -> Result<Validation, CustomUserError> {
+/// Validator for 'inquire' to check that a comma-separated list of durations is valid.
+fn validate_duration_list_input(input: &str) -> Result<Validation, CustomUserError> {
+    let options = input.split(',').map(str::trim).collect::<Vec<_>>();
+    match options
+        .iter()
+        .all(|v| transform_duration_input(v).is_some())
+    {
+        true => Ok(Validation::Valid),
+        false => Ok(Validation::Invalid(
+            "Failed to parse durations. Use comma-separated values like '1h, 1d, 1w'.".into(),
+        )),
+    }
+}
+
+/// Validator for 'inquire' to check that the filesize input is valid.
+fn validate_filesize_input(input: &str) -> Result<Validation, CustomUserError> {
     match transform_filesize_input(input) {
-        Some(v) => match v {
-            0..=WEBCRYPTO_MAX_FILESIZE => Ok(Validation::Valid),
-            _ => Ok(Validation::Invalid(
-                "Filesize too large. Choose a value smaller or equal to 2GiB.".into(),
-            )),
-        },
+        Some(_) => Ok(Validation::Valid),
         None => Ok(Validation::Invalid(
             "Failed to parse filesize. Use values like '100K', '25M' or '5G'.".into(),
         )),
@@ -105,6 +289,189 @@ fn format_filesize_input(input: &str) -> String {
     )
 }
 
+/// Prefix of the environment variables overriding settings of the 'config.toml'
+const ENV_PREFIX: &str = "FERRISHARE_";
+
+/// Environment variables with the [ENV_PREFIX] that aren't settings, but are read elsewhere
+const NON_SETTING_ENV_VARS: &[&str] = &["SERVER", "PASSPHRASE", "ADMIN_PASSWORD_FILE"];
+
+/// Settings whose values never leave the server and are redacted by '--print-config'
+pub const SECRET_SETTINGS: &[&str] = &[
+    "admin_password_hash",
+    "metrics_token",
+    "storage.secret_access_key",
+];
+
+/// How the textual value of an override is turned into a TOML value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueKind {
+    /// Taken as-is, the empty string removes the setting
+    String,
+    Integer,
+    /// Either a number of bytes or a filesize with suffix, like '25M'
+    Filesize,
+    Boolean,
+    /// Comma-separated, like '1h, 1d, 1w'
+    List,
+}
+
+/// Every setting that can be overridden, settings of the 'storage' table are prefixed with 'storage.'
+const OVERRIDABLE_SETTINGS: &[(&str, ValueKind)] = &[
+    ("app_name", ValueKind::String),
+    ("interface", ValueKind::String),
+    ("proxy_depth", ValueKind::Integer),
+    ("public_url", ValueKind::String),
+    ("admin_password_hash", ValueKind::String),
+    ("maximum_filesize", ValueKind::Filesize),
+    ("maximum_quota", ValueKind::Filesize),
+    ("maximum_uploads_per_ip", ValueKind::Integer),
+    ("daily_request_limit_per_ip", ValueKind::Integer),
+    ("log_level", ValueKind::String),
+    ("enable_privacy_policy", ValueKind::Boolean),
+    ("enable_legal_notice", ValueKind::Boolean),
+    ("demo_mode", ValueKind::Boolean),
+    ("expiry_options", ValueKind::List),
+    ("default_expiry", ValueKind::String),
+    ("maximum_expiry", ValueKind::String),
+    ("allow_custom_expiry", ValueKind::Boolean),
+    ("daily_report_limit_per_ip", ValueKind::Integer),
+    ("metrics_token", ValueKind::String),
+    ("storage.backend", ValueKind::String),
+    ("storage.bucket", ValueKind::String),
+    ("storage.region", ValueKind::String),
+    ("storage.endpoint", ValueKind::String),
+    ("storage.access_key_id", ValueKind::String),
+    ("storage.secret_access_key", ValueKind::String),
+    ("storage.prefix", ValueKind::String),
+];
+
+/// Name of the environment variable overriding a setting, e.g. 'FERRISHARE_STORAGE__BUCKET'.
+fn env_var_name(key: &str) -> String {
+    format!("{ENV_PREFIX}{}", key.to_uppercase().replace('.', "__"))
+}
+
+/// Parse a configuration and apply all overrides to it.
+///
+/// Settings are taken from the 'config.toml', then the FERRISHARE_* environment variables and
+/// finally the KEY=VALUE pairs passed with '--set', each taking precedence over the previous.
+pub fn parse_config(
+    config_string: &str,
+    overrides: &[String],
+) -> Result<AppConfiguration, anyhow::Error> {
+    let mut table: toml::Table = toml::from_str(config_string)?;
+    apply_overrides(&mut table, overrides)?;
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+/// Apply the overrides from the environment and the command line to a parsed 'config.toml'.
+fn apply_overrides(table: &mut toml::Table, overrides: &[String]) -> Result<(), anyhow::Error> {
+    for (name, value) in std::env::vars_os() {
+        let (Some(name), Some(value)) = (name.to_str(), value.to_str()) else {
+            continue;
+        };
+        let Some(suffix) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        match OVERRIDABLE_SETTINGS
+            .iter()
+            .find(|(key, _)| env_var_name(key) == name)
+        {
+            Some((key, kind)) => {
+                set_value(table, key, *kind, value).map_err(|e| anyhow!("{name}: {e}"))?
+            }
+            None if NON_SETTING_ENV_VARS.contains(&suffix) => {}
+            None => eprintln!("Ignoring unknown setting in environment variable {name}."),
+        }
+    }
+
+    for o in overrides {
+        let (key, value) = o
+            .split_once('=')
+            .ok_or_else(|| anyhow!("'--set {o}' is not of the form KEY=VALUE"))?;
+        let (key, kind) = OVERRIDABLE_SETTINGS
+            .iter()
+            .find(|(k, _)| *k == key.trim())
+            .ok_or_else(|| anyhow!("'--set {o}' refers to an unknown setting"))?;
+        set_value(table, key, *kind, value).map_err(|e| anyhow!("--set {key}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+/// Overwrite a single setting with the given textual value.
+fn set_value(
+    table: &mut toml::Table,
+    key: &str,
+    kind: ValueKind,
+    raw: &str,
+) -> Result<(), anyhow::Error> {
+    // Nested settings create their table if the 'config.toml' doesn't have it yet.
+    let (table, key) = match key.split_once('.') {
+        Some((outer, inner)) => (
+            table
+                .entry(outer)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("'{outer}' is not a table"))?,
+            inner,
+        ),
+        None => (table, key),
+    };
+
+    let raw = raw.trim();
+    let value = match kind {
+        ValueKind::String if raw.is_empty() => {
+            table.remove(key);
+            return Ok(());
+        }
+        ValueKind::String => toml::Value::String(raw.into()),
+        ValueKind::Integer => toml::Value::Integer(
+            raw.parse()
+                .map_err(|_| anyhow!("'{raw}' is not a valid number"))?,
+        ),
+        ValueKind::Filesize => {
+            let bytes = raw
+                .parse::<u64>()
+                .ok()
+                .or_else(|| transform_filesize_input(raw))
+                .and_then(|v| i64::try_from(v).ok())
+                .ok_or_else(|| anyhow!("'{raw}' is not a valid filesize"))?;
+            toml::Value::Integer(bytes)
+        }
+        ValueKind::Boolean => toml::Value::Boolean(
+            raw.parse()
+                .map_err(|_| anyhow!("'{raw}' is neither 'true' nor 'false'"))?,
+        ),
+        ValueKind::List => toml::Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(|v| toml::Value::String(v.into()))
+                .collect(),
+        ),
+    };
+    table.insert(key.into(), value);
+    Ok(())
+}
+
+/// Render the configuration as TOML, with all secrets replaced by a placeholder.
+pub fn redacted_config(app_config: &AppConfiguration) -> Result<String, anyhow::Error> {
+    let mut table = toml::Table::try_from(app_config)?;
+    for key in SECRET_SETTINGS {
+        let value = match key.split_once('.') {
+            Some((outer, inner)) => table
+                .get_mut(outer)
+                .and_then(|v| v.as_table_mut())
+                .and_then(|v| v.get_mut(inner)),
+            None => table.get_mut(*key),
+        };
+        if let Some(value) = value {
+            *value = toml::Value::String("<redacted>".into());
+        }
+    }
+    Ok(toml::to_string(&table)?)
+}
+
 /// The application's interactive configuration wizard started with the '--init' flag.
 pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
     eprintln!("Setting up new configuration at {config_path:?}");
@@ -162,34 +529,50 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
         .parse::<u64>()
         .unwrap();
 
+    let public_url = Text::new("Public URL:")
+        .with_validator(validate_public_url_input)
+        .with_help_message(
+            "
+  The URL users reach the application at, e.g. 'https://files.example.com'.
+
+  Requests made by the browser are only accepted if they originate from this URL,
+  which protects against cross-site request forgery. Leave empty to derive it from
+  the 'Host', 'X-Forwarded-Host' and 'X-Forwarded-Proto'-headers instead.
+
+  Does your reverse-proxy rewrite the 'Host'-header without adding 'X-Forwarded-Host',
+  like nginx does by default? Then you have to set this.
+",
+        )
+        .prompt()?;
+
     let admin_password = Password::new("Admin password:")
         .with_display_mode(inquire::PasswordDisplayMode::Masked)
         .with_help_message(
             "
-  Site-wide administration password used at the '/admin'-URL.
+  Password of the initial site-wide administrator 'admin' at the '/admin'-URL.
 
   The admin panel allows you to view statistics on all uploaded files.
   It also allows you to delete files before they have expired.
   However, since the files are end-to-end-encrypted, you cannot download them.
 
   An argon2id-hash of your password will be stored in the generated config.
+  It is only used to create the 'admin' account on first launch.
+  Afterwards, administrators are managed with the 'admin' subcommand.
 ",
         )
         .prompt()?;
 
     let maximum_filesize = Text::new("Maximum filesize:")
         .with_initial_value("25M")
-        .with_validator(validate_max_filesize_input)
+        .with_validator(validate_filesize_input)
         .with_formatter(&format_filesize_input)
         .with_help_message(
             "
   Maximum filesize that users can upload and store on the server.
 
-  Due to limitations of the WebCrypto-API used on the frontend this
-  is currently limited to 2GiB. Additionally, please bear in mind
-  that uploaded files are first streamed to memory, hashed,
-  and then stored on disk. This can cause problems if you accept
-  files that are larger than your RAM.
+  Files are encrypted in fixed-size segments in the browser and
+  streamed straight to disk, so the filesize is limited by neither
+  the WebCrypto-API nor the server's RAM.
 
   The prompt uses suffixes 'K', 'M' and 'G' which are read as binary suffixes:
     '250K' -> 250 KiB ->       256_000 Bytes
@@ -269,6 +652,176 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
         .parse::<u64>()
         .unwrap();
 
+    let daily_report_limit_per_ip = Text::new("Daily abuse report limit per IP:")
+        .with_initial_value("10")
+        .with_validator(|v: &str| {
+            v.parse::<u64>()
+                .map_or(Ok(Validation::Invalid("not a valid number".into())), |_| {
+                    Ok(Validation::Valid)
+                })
+        })
+        .with_help_message(
+            "
+  How many abuse reports can a single IP address file per day?
+
+  Recipients of a download link can report illegal content to the administrators,
+  who then find the reports in the admin panel. This limit keeps a single client
+  from flooding the panel with bogus reports.
+
+  'IP address' here refers to either an IPv4 address or an IPv6 /64-subnet.
+",
+        )
+        .prompt()?
+        // Due to the validator this parse should never fail.
+        .parse::<u64>()
+        .unwrap();
+
+    let expiry_options = Text::new("Expiry options:")
+        .with_initial_value("1h, 1d, 1w")
+        .with_validator(validate_duration_list_input)
+        .with_help_message(
+            "
+  Comma-separated list of durations uploaders can choose from.
+  Files are deleted automatically once their duration has passed.
+
+  The prompt uses suffixes 'm', 'h', 'd' and 'w' for minutes, hours, days and weeks:
+    '10m' -> 10 minutes
+     '3d' ->  3 days
+    '30d' -> 30 days
+",
+        )
+        .prompt()?
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .collect::<Vec<_>>();
+
+    let default_expiry = Select::new("Default expiry:", expiry_options.clone())
+        .with_help_message(
+            "
+  The expiry option that is preselected on the upload page. (↑↓ to move, enter to select)
+",
+        )
+        .prompt()?;
+
+    // The maximum has to accommodate all of the options chosen above.
+    let longest_option = expiry_options
+        .iter()
+        .filter_map(|v| transform_duration_input(v))
+        .max()
+        .unwrap_or_default();
+    let maximum_expiry = Text::new("Maximum expiry:")
+        .with_initial_value(
+            expiry_options
+                .iter()
+                .max_by_key(|v| transform_duration_input(v))
+                .map_or("1w", |v| v.as_str()),
+        )
+        .with_validator(validate_duration_input)
+        .with_validator(move |v: &str| {
+            match transform_duration_input(v).is_some_and(|v| v >= longest_option) {
+                true => Ok(Validation::Valid),
+                false => Ok(Validation::Invalid(
+                    "The maximum must not be shorter than any of the expiry options.".into(),
+                )),
+            }
+        })
+        .with_help_message(
+            "
+  The longest duration any file may be stored for.
+
+  Must not be shorter than any of the expiry options.
+  Also limits the exact expiry timestamps uploaders can pick, if enabled below.
+",
+        )
+        .prompt()?;
+
+    let allow_custom_expiry = Confirm::new("Allow exact expiry timestamps?")
+        .with_default(false)
+        .with_help_message(
+            "
+  In addition to the expiry options, uploaders can pick an exact date and time
+  at which their files expire, as long as it lies within the maximum expiry.
+",
+        )
+        .prompt()?;
+
+    let storage_backends = vec!["Local filesystem", "S3-compatible object storage"];
+    let storage_backend = Select::new("Storage backend:", storage_backends)
+        .with_help_message(
+            "
+  Where the encrypted files are stored. (↑↓ to move, enter to select)
+
+  The local filesystem keeps them in `./data/uploaded_files/`.
+  An S3-compatible object storage (AWS S3, MinIO, Garage, ...) keeps them
+  in a bucket instead, which suits deployments without persistent disks.
+  The database always remains at `./data/sqlite.db`.
+",
+        )
+        .prompt()?;
+
+    let storage = if storage_backend == "Local filesystem" {
+        StorageConfiguration::Filesystem
+    } else {
+        let bucket = Text::new("S3 bucket:")
+            .with_validator(inquire::validator::MinLengthValidator::new(1))
+            .with_help_message(
+                "
+  Name of the bucket the files are stored in. It has to exist already.
+",
+            )
+            .prompt()?;
+
+        let region = Text::new("S3 region:")
+            .with_initial_value("us-east-1")
+            .with_validator(inquire::validator::MinLengthValidator::new(1))
+            .prompt()?;
+
+        let endpoint = Text::new("S3 endpoint:")
+            .with_help_message(
+                "
+  Custom endpoint for S3-compatible services, e.g. 'http://minio:9000'.
+  Leave empty when using AWS S3.
+",
+            )
+            .prompt()?;
+
+        let access_key_id = Text::new("S3 access key ID:")
+            .with_help_message(
+                "
+  Leave empty to read the credentials from the AWS_ACCESS_KEY_ID and
+  AWS_SECRET_ACCESS_KEY environment variables instead of storing them here.
+",
+            )
+            .prompt()?;
+
+        let secret_access_key = if access_key_id.is_empty() {
+            String::new()
+        } else {
+            Password::new("S3 secret access key:")
+                .with_display_mode(inquire::PasswordDisplayMode::Masked)
+                .without_confirmation()
+                .prompt()?
+        };
+
+        let prefix = Text::new("S3 object prefix:")
+            .with_help_message(
+                "
+  Optional prefix for the names of all stored objects, e.g. 'ferrishare/'.
+  Allows the bucket to be shared with other applications.
+",
+            )
+            .prompt()?;
+
+        StorageConfiguration::S3 {
+            bucket,
+            region,
+            endpoint: Some(endpoint).filter(|v| !v.is_empty()),
+            access_key_id: Some(access_key_id).filter(|v| !v.is_empty()),
+            secret_access_key: Some(secret_access_key).filter(|v| !v.is_empty()),
+            prefix,
+        }
+    };
+
     let log_levels = vec![Level::INFO, Level::WARN, Level::ERROR];
     let log_level = Select::new("Log level:", log_levels)
         .with_help_message(
@@ -284,6 +837,20 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
         )
         .prompt()?;
 
+    let enable_metrics = Confirm::new("Enable Prometheus metrics?")
+        .with_default(false)
+        .with_help_message(
+            "
+  Serve upload, download and error counters as well as storage and
+  rate-limiter gauges on `/metrics` for scraping with Prometheus.
+
+  The endpoint is protected by a randomly generated bearer token that is
+  printed at the end of this wizard. Configure your scraper to send it in
+  the 'Authorization: Bearer <token>' header.
+",
+        )
+        .prompt()?;
+
     let enable_privacy_policy = Confirm::new("Enable Privacy Policy?")
         .with_default(true)
         .with_help_message(
@@ -317,28 +884,7 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
 
     eprintln!("\nFinalizing configuration...");
 
-    // Copy over the Privacy Policy if it doesn't already exist.
-    let privacy_policy_path = format!("{DATA_PATH}/user_templates/privacy_policy.html");
-    if PathBuf::from(privacy_policy_path.clone()).exists() {
-        eprintln!("Found Privacy Policy at '{privacy_policy_path}', leaving untouched.");
-    } else {
-        std::fs::copy(
-            "./templates/privacy_policy_default.html",
-            &privacy_policy_path,
-        )
-        .map_err(|e| anyhow!("failed to copy privacy policy template: {e}"))?;
-        eprintln!("Copied Privacy Policy template to '{privacy_policy_path}'.",);
-    }
-
-    // Copy over the Legal Notice if it doesn't already exist.
-    let legal_notice_path = format!("{DATA_PATH}/user_templates/legal_notice.html");
-    if PathBuf::from(legal_notice_path.clone()).exists() {
-        eprintln!("Found Legal Notice at '{legal_notice_path}', leaving untouched.");
-    } else {
-        std::fs::copy("./templates/legal_notice_stub.html", &legal_notice_path)
-            .map_err(|e| anyhow!("failed to copy legal notice template: {e}"))?;
-        eprintln!("Copied Legal Notice template to '{legal_notice_path}'.");
-    }
+    copy_user_templates()?;
 
     // Perform postprocessing on the given answers.
     eprint!("Hashing password ...");
@@ -348,26 +894,21 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
     let maximum_quota = transform_filesize_input(&maximum_quota).unwrap();
 
     // Hash the admin password.
-    // Use 32MB of memory and 4 iterations. That's a little stronger than the default parameters.
-    let admin_password_hash = Argon2::new(
-        argon2::Algorithm::default(),
-        argon2::Version::default(),
-        argon2::Params::new(32768, 4, 1, None).map_err(|e| anyhow!(e.to_string()))?,
-    )
-    .hash_password(
-        admin_password.as_bytes(),
-        &SaltString::generate(&mut argon2::password_hash::rand_core::OsRng),
-    )
-    .map_err(|e| anyhow!(e.to_string()))?
-    .to_string();
+    let admin_password_hash = Some(hash_password(&admin_password)?);
 
     eprintln!(" done!");
 
+    let metrics_token = enable_metrics.then(|| URL_SAFE_NO_PAD.encode(rng().random::<[u8; 32]>()));
+    if let Some(ref token) = metrics_token {
+        eprintln!("Prometheus can scrape '/metrics' with the bearer token '{token}'.");
+    }
+
     // Bring it all together.
     let app_config = AppConfiguration {
         app_name,
         interface,
         proxy_depth,
+        public_url: Some(public_url).filter(|v| !v.is_empty()),
         admin_password_hash,
         maximum_filesize,
         maximum_quota,
@@ -377,10 +918,117 @@ pub fn setup_config(config_path: &Path) -> Result<(), anyhow::Error> {
         enable_privacy_policy,
         enable_legal_notice,
         demo_mode: false,
+        expiry_options,
+        default_expiry,
+        maximum_expiry,
+        allow_custom_expiry,
+        storage,
+        daily_report_limit_per_ip,
+        metrics_token,
     };
 
-    // Serialize to TOML and write to disk as 'config.toml'.
-    File::create(config_path)?.write_all(toml::to_string(&app_config)?.as_bytes())?;
+    write_config(config_path, &app_config)
+}
+
+/// Settings used by the non-interactive setup unless overridden, the wizard's suggested answers
+const DEFAULT_CONFIG: &str = r#"
+app_name = "FerriShare"
+interface = "0.0.0.0:3000"
+proxy_depth = 1
+maximum_filesize = 26214400
+maximum_quota = 5368709120
+maximum_uploads_per_ip = 10
+daily_request_limit_per_ip = 1000
+log_level = "INFO"
+enable_privacy_policy = true
+enable_legal_notice = false
+demo_mode = false
+expiry_options = ["1h", "1d", "1w"]
+default_expiry = "1d"
+maximum_expiry = "1w"
+allow_custom_expiry = false
+daily_report_limit_per_ip = 10
+
+[storage]
+backend = "filesystem"
+"#;
+
+/// The non-interactive counterpart to [setup_config] started with '--init --non-interactive'.
+///
+/// Settings are taken from the environment and '--set' on top of [DEFAULT_CONFIG].
+/// The admin password is read from the given file, or stdin for '-', and hashed.
+pub fn setup_config_non_interactive(
+    config_path: &Path,
+    overrides: &[String],
+    admin_password_file: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    eprintln!("Setting up new configuration at {config_path:?} non-interactively");
+
+    let mut table: toml::Table = toml::from_str(DEFAULT_CONFIG)?;
+    apply_overrides(&mut table, overrides)?;
+
+    if let Some(path) = admin_password_file {
+        let password = match path.to_str() {
+            Some("-") => std::io::read_to_string(std::io::stdin())?,
+            _ => std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("failed to read admin password from {path:?}: {e}"))?,
+        };
+        // Files and pipes usually end with a newline that isn't part of the password.
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            return Err(anyhow!("the admin password must not be empty"));
+        }
+        eprint!("Hashing password ...");
+        table.insert(
+            "admin_password_hash".into(),
+            toml::Value::String(hash_password(password)?),
+        );
+        eprintln!(" done!");
+    } else if !table.contains_key("admin_password_hash") {
+        return Err(anyhow!(
+            "provide the admin password with '--admin-password-file' or its hash with {}",
+            env_var_name("admin_password_hash")
+        ));
+    }
+
+    let app_config: AppConfiguration = toml::Value::Table(table).try_into()?;
+    app_config.validate()?;
+
+    copy_user_templates()?;
+    write_config(config_path, &app_config)
+}
+
+/// Copy the default Privacy Policy and the Legal Notice stub to the user templates.
+fn copy_user_templates() -> Result<(), anyhow::Error> {
+    // Copy over the Privacy Policy if it doesn't already exist.
+    let privacy_policy_path = format!("{DATA_PATH}/user_templates/privacy_policy.html");
+    if PathBuf::from(privacy_policy_path.clone()).exists() {
+        eprintln!("Found Privacy Policy at '{privacy_policy_path}', leaving untouched.");
+    } else {
+        std::fs::copy(
+            "./templates/privacy_policy_default.html",
+            &privacy_policy_path,
+        )
+        .map_err(|e| anyhow!("failed to copy privacy policy template: {e}"))?;
+        eprintln!("Copied Privacy Policy template to '{privacy_policy_path}'.",);
+    }
+
+    // Copy over the Legal Notice if it doesn't already exist.
+    let legal_notice_path = format!("{DATA_PATH}/user_templates/legal_notice.html");
+    if PathBuf::from(legal_notice_path.clone()).exists() {
+        eprintln!("Found Legal Notice at '{legal_notice_path}', leaving untouched.");
+    } else {
+        std::fs::copy("./templates/legal_notice_stub.html", &legal_notice_path)
+            .map_err(|e| anyhow!("failed to copy legal notice template: {e}"))?;
+        eprintln!("Copied Legal Notice template to '{legal_notice_path}'.");
+    }
+
+    Ok(())
+}
+
+/// Serialize the configuration to TOML and write it to disk as 'config.toml'.
+fn write_config(config_path: &Path, app_config: &AppConfiguration) -> Result<(), anyhow::Error> {
+    File::create(config_path)?.write_all(toml::to_string(app_config)?.as_bytes())?;
 
     eprintln!("Successfully wrote config to {config_path:?}.");
     eprintln!("You can now launch the app.");
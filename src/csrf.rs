@@ -0,0 +1,217 @@
+//! Protection against cross-site request forgery for all routes used by the browser
+//!
+//! Uses the double-submit pattern: Every client receives a random token in the 'csrf' cookie,
+//! which is also templated into every page. State-changing requests have to send it back,
+//! either in the 'X-CSRF-Token' header or the 'csrf_token' form field. Other sites can neither
+//! read the cookie nor the page, so they can't forge such a request. The Origin and Referer
+//! are checked as well, in case the browser's SameSite-support for the session cookie is shaky.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use cookie::Cookie;
+use rand::{prelude::*, rng};
+use url::{Origin, Url};
+
+use crate::*;
+
+/// Name of the cookie holding the token
+const COOKIE_NAME: &str = "csrf";
+
+/// Name of the header JavaScript sends the token in
+const HEADER_NAME: &str = "x-csrf-token";
+
+/// Name of the form field HTML forms send the token in
+const FORM_FIELD: &str = "csrf_token";
+
+/// Maximum size of form bodies that are read to find the token
+///
+/// The admin forms are tiny, anything larger is not a legitimate request.
+const MAX_FORM_SIZE: usize = 16384;
+
+tokio::task_local! {
+    /// The token of the request currently being handled, for use in templates
+    static CSRF_TOKEN: String;
+}
+
+/// The token of the request currently being handled, if it passed through the middleware.
+pub fn current_token() -> Option<String> {
+    CSRF_TOKEN.try_with(|v| v.clone()).ok()
+}
+
+/// Middleware handing out tokens and checking them on every state-changing request.
+pub async fn csrf_protection(
+    State(aps): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Reuse the client's token if it looks like one of ours, otherwise hand out a new one.
+    let existing_token = CookieJar::from_headers(request.headers())
+        .get(COOKIE_NAME)
+        .map(|v| v.value().to_string())
+        .filter(|v| URL_SAFE_NO_PAD.decode(v).is_ok_and(|v| v.len() == 32));
+
+    let request = if is_safe(request.method()) {
+        request
+    } else {
+        match verify(&aps, request, existing_token.as_deref()).await {
+            Ok(v) => v,
+            Err(e) => return e.into_response(),
+        }
+    };
+
+    let token = existing_token
+        .clone()
+        .unwrap_or_else(|| URL_SAFE_NO_PAD.encode(rng().random::<[u8; 32]>()));
+
+    let mut response = CSRF_TOKEN.scope(token.clone(), next.run(request)).await;
+
+    if existing_token.is_none() {
+        let cookie = Cookie::build((COOKIE_NAME, token))
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(cookie::SameSite::Strict);
+        if let Ok(v) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(header::SET_COOKIE, v);
+        }
+    }
+
+    response
+}
+
+/// Requests that must not change any state and therefore don't need to be checked.
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Check the origin and token of a state-changing request.
+///
+/// Returns the request, whose body might have been read and replaced in the process.
+async fn verify(
+    aps: &AppState,
+    request: Request,
+    expected_token: Option<&str>,
+) -> Result<Request, AppError> {
+    if !same_origin(aps, request.headers()) {
+        return AppError::err(StatusCode::FORBIDDEN, "CSRF check failed: foreign origin");
+    }
+
+    let header_token = request
+        .headers()
+        .get(HEADER_NAME)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    // HTML forms can't set headers, so their body has to be searched for the token instead.
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+
+    let (request, token) = match header_token {
+        Some(v) => (request, Some(v)),
+        None if is_form => {
+            let (parts, body) = request.into_parts();
+            let bytes = axum::body::to_bytes(body, MAX_FORM_SIZE)
+                .await
+                .map_err(|_| AppError::new(StatusCode::PAYLOAD_TOO_LARGE, "form too large"))?;
+            let token = form_urlencoded::parse(&bytes)
+                .find(|(k, _)| k == FORM_FIELD)
+                .map(|(_, v)| v.into_owned());
+            (Request::from_parts(parts, Body::from(bytes)), token)
+        }
+        None => (request, None),
+    };
+
+    match (token, expected_token) {
+        (Some(token), Some(expected)) if tokens_match(&token, expected) => Ok(request),
+        _ => AppError::err(StatusCode::FORBIDDEN, "CSRF check failed: invalid token"),
+    }
+}
+
+/// Check that the request originates from one of our own pages, as far as the browser tells.
+///
+/// Scheme, host and port all have to match the configured public_url. Without one, the origin
+/// is derived from the Host header, or X-Forwarded-Host and X-Forwarded-Proto behind a proxy.
+/// Browsers that send neither Origin nor Referer are let through, the token still has to match.
+fn same_origin(aps: &AppState, headers: &HeaderMap) -> bool {
+    let source = match headers.get(header::ORIGIN) {
+        Some(v) => v,
+        None => match headers.get(header::REFERER) {
+            Some(v) => v,
+            None => return true,
+        },
+    };
+
+    // Opaque origins such as 'null' are never equal to any other origin, so they're rejected.
+    let Some(source_origin) = source
+        .to_str()
+        .ok()
+        .and_then(|v| Url::parse(v).ok())
+        .map(|v| v.origin())
+    else {
+        return false;
+    };
+
+    let conf = aps.conf();
+    let expected: Vec<Origin> = match conf.public_url {
+        Some(ref public_url) => Url::parse(public_url)
+            .map(|v| v.origin())
+            .into_iter()
+            .collect(),
+        None => {
+            let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+            // The proxy terminates TLS, which the README requires, unless it says otherwise.
+            let (scheme, hosts) = match conf.proxy_depth {
+                0 => (Some("http"), vec![header_str(header::HOST.as_str())]),
+                _ => (
+                    header_str("x-forwarded-proto").or(Some("https")),
+                    vec![
+                        header_str("x-forwarded-host"),
+                        header_str(header::HOST.as_str()),
+                    ],
+                ),
+            };
+            hosts
+                .into_iter()
+                .flatten()
+                .filter_map(|host| Url::parse(&format!("{}://{host}", scheme?)).ok())
+                .map(|v| v.origin())
+                .collect()
+        }
+    };
+
+    if expected.contains(&source_origin) {
+        return true;
+    }
+
+    // Misconfigured proxies make every request look foreign, so point operators to the cause.
+    tracing::warn!(
+        origin = source_origin.ascii_serialization(),
+        expected = expected
+            .iter()
+            .map(|v| v.ascii_serialization())
+            .collect::<Vec<_>>()
+            .join(", "),
+        "rejected request from a foreign origin; if this is your own site, set public_url \
+        or have your reverse-proxy pass on the Host and X-Forwarded-Proto headers"
+    );
+    false
+}
+
+/// Compare two tokens in constant time.
+pub fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -2,10 +2,11 @@
 
 use axum::{extract::State, http::StatusCode, Json};
 use axum_extra::extract::CookieJar;
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use serde::Deserialize;
-use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
 
+use crate::accounts::Role;
+use crate::audit::{Actor, AuditEvent};
 use crate::*;
 
 #[derive(Debug, Deserialize)]
@@ -20,20 +21,31 @@ pub async fn delete_endpoint(
     jar: CookieJar,
     Json(req): Json<DeleteRequest>,
 ) -> Result<StatusCode, AppError> {
-    // Extract the two parameters.
-    let efd_sha256sum = req.hash;
-    let admin_key = req.admin;
+    delete_file(&aps, &req.hash, req.admin.as_deref(), &jar).await?;
+    Ok(StatusCode::OK)
+}
 
+/// Delete a file if the request is authorized to do so.
+///
+/// That's either the uploader presenting the file's admin key
+/// or a site-wide administrator with a session cookie.
+pub async fn delete_file(
+    aps: &AppState,
+    efd_sha256sum: &str,
+    admin_key: Option<&str>,
+    jar: &CookieJar,
+) -> Result<(), AppError> {
     // Do not entertain hashes with invalid length.
     if efd_sha256sum.len() != 43 {
         return AppError::err(StatusCode::BAD_REQUEST, "invalid hash length");
     }
 
     // Query the databse for the entry.
+    // Members of a bundle can only be deleted along with the whole bundle.
     let row: Option<String> = sqlx::query_scalar(
-        "SELECT admin_key_sha256sum FROM uploaded_files WHERE efd_sha256sum = ? LIMIT 1;",
+        "SELECT admin_key_sha256sum FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;",
     )
-    .bind(&efd_sha256sum)
+    .bind(efd_sha256sum)
     .fetch_optional(&aps.db)
     .await?;
 
@@ -47,67 +59,161 @@ pub async fn delete_endpoint(
 
     let mut authorized = false;
 
-    // Compute the sha256-digest of the admin_key if it was provided.
+    // If the admin key was provided and matches, the request can go through.
     if let Some(admin_key) = admin_key {
-        let admin_key_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
-            URL_SAFE_NO_PAD
-                .decode(admin_key)
-                .ok()
-                .filter(|v| v.len() == 32)
-                .unwrap_or_default(),
-        ));
-
-        // If the admin key matches, the request can go through.
-        if admin_key_sha256sum == db_admin_key_sha256sum {
+        if admin_key_matches(admin_key, &db_admin_key_sha256sum) {
             authorized = true;
         }
     }
 
     // No matching admin_key? Check for session_id, then.
     // This is for the case where the deletion request is not made by the user who uploaded the
-    // file, but by the site-wide administrator who is currently logged into the admin panel.
+    // file, but by a site-wide administrator who is currently logged into the admin panel.
+    let mut admin_session = None;
     if !authorized {
-        // Calculate the base64url-encoded sha256sum of the session cookie, if any.
-        let user_session_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
-            URL_SAFE_NO_PAD
-                .decode(jar.get("id").map_or("", |e| e.value()))
-                .unwrap_or_default(),
-        ));
-
-        let session_row: Option<i64> = sqlx::query_scalar(
-            "SELECT 1 FROM admin_sessions WHERE session_id_sha256sum = ? LIMIT 1;",
-        )
-        .bind(&user_session_sha256sum)
-        .fetch_optional(&aps.db)
-        .await?;
-
-        if session_row.is_some() {
+        if let Some(admin) = admin::current_admin(aps, jar).await? {
+            if admin.role < Role::Deleter {
+                return AppError::err(
+                    StatusCode::FORBIDDEN,
+                    "your role does not permit deleting files",
+                );
+            }
             authorized = true;
+            admin_session = Some(admin);
         }
     }
 
     // Now delete the file if we're authroized.
     if authorized {
         // Use the cleanup method and bubble up any internal server errors.
-        cleanup_file(&efd_sha256sum, &aps.db).await?;
+        cleanup_file(efd_sha256sum, aps).await?;
         // Log the successful deletion.
-        tracing::info!(efd_sha256sum, "manually deleted file");
-        Ok(StatusCode::OK)
+        tracing::info!(
+            efd_sha256sum,
+            admin = admin_session.as_ref().map(|v| &v.username),
+            "manually deleted file"
+        );
+        metrics::METRICS.count_deletions(1);
+        let actor = admin_session.as_ref().map_or(Actor::Uploader, Actor::from);
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::FileDeleted,
+            actor,
+            Some(efd_sha256sum),
+            None,
+        )
+        .await;
+        Ok(())
     } else {
         AppError::err(StatusCode::UNAUTHORIZED, "unauthorized")
     }
 }
 
-/// Remove a single file identified by its efd_sha256sum from the database and disk.
-pub async fn cleanup_file(efd_sha256sum: &str, db: &SqlitePool) -> Result<(), anyhow::Error> {
+/// Remove the rows of a file or bundle from the database as part of a larger transaction.
+///
+/// Returns the files whose filedata has to be removed from the storage backend
+/// once the transaction has been committed, or None if there is no such file.
+/// Members of a bundle can only be removed along with the whole bundle.
+pub async fn delete_rows(
+    tx: &mut SqliteConnection,
+    efd_sha256sum: &str,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let row: Option<(i64, bool)> = sqlx::query_as(
+        "SELECT id, is_bundle FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;",
+    )
+    .bind(efd_sha256sum)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((id, is_bundle)) = row else {
+        return Ok(None);
+    };
+
+    let filedata = if is_bundle {
+        sqlx::query_scalar("SELECT efd_sha256sum FROM uploaded_files WHERE parent_id = ?;")
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?
+    } else {
+        vec![efd_sha256sum.to_string()]
+    };
+
+    // Members reference the bundle, so they have to be removed first.
+    sqlx::query("DELETE FROM uploaded_files WHERE parent_id = ?;")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM uploaded_files WHERE id = ?;")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(Some(filedata))
+}
+
+/// Remove a single file identified by its efd_sha256sum from the database and storage backend.
+///
+/// Removing a bundle removes all of its members along with it.
+pub async fn cleanup_file(efd_sha256sum: &str, aps: &AppState) -> Result<(), anyhow::Error> {
+    let db = &aps.db;
+
+    #[derive(Debug, FromRow)]
+    struct FileRow {
+        filesize: i64,
+        is_bundle: bool,
+        parent_id: Option<i64>,
+    }
+
+    let row: Option<FileRow> = sqlx::query_as(
+        "SELECT filesize, is_bundle, parent_id FROM uploaded_files WHERE efd_sha256sum = ? LIMIT 1;",
+    )
+    .bind(efd_sha256sum)
+    .fetch_optional(db)
+    .await?;
+
+    // Bundles don't have any filedata of their own, but their members do.
+    let is_bundle = row.as_ref().is_some_and(|v| v.is_bundle);
+
+    if is_bundle {
+        let members: Vec<String> = sqlx::query_scalar("SELECT efd_sha256sum FROM uploaded_files WHERE parent_id = (SELECT id FROM uploaded_files WHERE efd_sha256sum = ?);")
+            .bind(efd_sha256sum)
+            .fetch_all(db)
+            .await?;
+
+        // Members reference the bundle, so they have to be removed first.
+        for member in members {
+            sqlx::query("DELETE FROM uploaded_files WHERE efd_sha256sum = ?;")
+                .bind(&member)
+                .execute(db)
+                .await?;
+            aps.storage.delete(&member).await?;
+        }
+    }
+
     // First, remove the corresponding row form the DB.
     sqlx::query("DELETE FROM uploaded_files WHERE efd_sha256sum = ?;")
         .bind(efd_sha256sum)
         .execute(db)
         .await?;
 
-    // Next, remove the actual file from disk.
-    tokio::fs::remove_file(format!("{DATA_PATH}/uploaded_files/{}", efd_sha256sum)).await?;
+    // Members can be removed on their own when they reach their download limit.
+    // Their bundle's filesize is the sum of its members', so it has to shrink along with them.
+    if let Some(FileRow {
+        filesize,
+        parent_id: Some(parent_id),
+        ..
+    }) = row
+    {
+        sqlx::query("UPDATE uploaded_files SET filesize = filesize - ? WHERE id = ?;")
+            .bind(filesize)
+            .bind(parent_id)
+            .execute(db)
+            .await?;
+    }
+
+    // Next, remove the actual filedata.
+    if !is_bundle {
+        aps.storage.delete(efd_sha256sum).await?;
+    }
 
     // If neither yielded an Error, return Ok.
     Ok(())
@@ -3,27 +3,94 @@
 use axum::{
     body::Body,
     extract::{Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{Html, IntoResponse, Response},
 };
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use chrono::{prelude::Utc, DateTime, TimeZone};
 use itertools::*;
 use minify_html::minify;
 use serde::Serialize;
-use sha2::{Digest, Sha256};
 use sqlx::FromRow;
 use std::collections::HashMap;
 use tera::Context;
-use tokio_util::io::ReaderStream;
 
+use crate::audit::{Actor, AuditEvent};
+use crate::metrics::Removal;
 use crate::*;
 
+/// Which part of a file a request asks for, as determined by its Range header
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// The entire file
+    Full,
+    /// Only the bytes from start to end, both inclusive
+    Partial { start: u64, end: u64 },
+    /// A range that lies entirely outside of the file
+    Unsatisfiable,
+}
+
+/// Evaluate the Range header of a request for a file of the given length.
+///
+/// Only a single range is supported. Requests for several ranges at once, malformed
+/// headers and ranges that are conditional on an outdated ETag are served the entire file,
+/// which is what the spec asks for when a Range header is ignored.
+fn requested_range(headers: &HeaderMap, filesize: u64, etag: &str) -> ByteRange {
+    let Some(range) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return ByteRange::Full;
+    };
+
+    // A range that is only wanted if the file hasn't changed. Files never change, but
+    // a client might still present the ETag of an entirely different file.
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if if_range.as_bytes() != etag.as_bytes() {
+            return ByteRange::Full;
+        }
+    }
+
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // 'bytes=100-199', where the end may lie beyond the end of the file.
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(filesize.saturating_sub(1))),
+        // 'bytes=100-', i.e. everything from the given offset onwards.
+        (Ok(start), Err(_)) if end.is_empty() => (start, filesize.saturating_sub(1)),
+        // 'bytes=-100', i.e. the final 100 bytes.
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (filesize.saturating_sub(suffix), filesize.saturating_sub(1))
+        }
+        _ => return ByteRange::Full,
+    };
+
+    if start >= filesize {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
 /// Endpoint for downloading files from the service
+///
+/// Supports HEAD and single-range requests so that interrupted downloads can be resumed.
+/// Only requests for the start of a file count as a download. Resuming a download later on
+/// or merely asking for its size doesn't, so that every logical download is counted once.
+/// Files with a download limit can't be resumed, as that would let clients fetch them piecemeal
+/// without ever using up a download.
 pub async fn download_endpoint(
+    method: Method,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
     State(aps): State<AppState>,
-) -> Result<(StatusCode, impl IntoResponse), AppError> {
+) -> Result<Response, AppError> {
     // Only the file parameter is permitted here.
     let hash = params.get("hash");
 
@@ -38,6 +105,18 @@ pub async fn download_endpoint(
     // Guaranteed to work.
     let hash = hash.ok_or_else(|| AppError::new500("illegal unwrap"))?;
 
+    serve_file(&aps, &method, &headers, hash).await
+}
+
+/// Serve the encrypted filedata of the file with the given hash.
+///
+/// Shared between the download_endpoint and the REST API.
+pub async fn serve_file(
+    aps: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+    hash: &str,
+) -> Result<Response, AppError> {
     // Do not entertain hashes with invalid length.
     if hash.len() != 43 {
         return AppError::err(StatusCode::BAD_REQUEST, "invalid hash length");
@@ -46,16 +125,18 @@ pub async fn download_endpoint(
     #[derive(Debug, FromRow)]
     struct FileRow {
         expiry_ts: String,
-        downloads: i64,
+        is_bundle: bool,
+        max_downloads: Option<i64>,
     }
 
     // Next, query for the given file.
     // We only need to know
     // (1) whether the row exists
     // (2) the expiry timestamp of the row
-    // (2) the 'downloads' value of that row, as it's going to be incremented
+    // (3) whether the row is a bundle, which has no filedata of its own
+    // (4) whether the file has a download limit
     let row: Option<FileRow> = sqlx::query_as(
-        "SELECT expiry_ts, downloads FROM uploaded_files WHERE efd_sha256sum = ? LIMIT 1;",
+        "SELECT expiry_ts, is_bundle, max_downloads FROM uploaded_files WHERE efd_sha256sum = ? LIMIT 1;",
     )
     .bind(hash)
     .fetch_optional(&aps.db)
@@ -72,28 +153,199 @@ pub async fn download_endpoint(
     // Guaranteed to work.
     let row = row.ok_or_else(|| AppError::new500("illegal unwrap"))?;
 
-    // Open the AsyncRead-stream for the file.
-    let file = match tokio::fs::File::open(format!("{DATA_PATH}/uploaded_files/{}", hash)).await {
-        Ok(file) => file,
-        Err(_) => {
-            // A file being in the DB but not on disk should not be possible.
-            return AppError::err(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "file in database but not on disk",
+    if row.is_bundle {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            "the files of a bundle have to be downloaded one by one",
+        );
+    }
+
+    // A concurrent request may have just used up the last download and removed the file.
+    let not_found = || match row.max_downloads {
+        Some(_) => AppError::new(
+            StatusCode::NOT_FOUND,
+            "file not found, expired or download limit reached",
+        ),
+        // A file being in the DB but not in storage should not be possible.
+        None => AppError::new500("file in database but not in storage"),
+    };
+
+    let filesize = aps
+        .storage
+        .size(hash)
+        .await
+        .map_err(|e| AppError::new500(format!("failed to look up file in storage: {e}")))?
+        .ok_or_else(not_found)?;
+
+    // Files are identified by the hash of their contents, which makes for a perfect strong ETag.
+    let etag = format!("\"{hash}\"");
+
+    // Clients that already have the file don't need it again.
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        if if_none_match.as_bytes() == etag.as_bytes() || if_none_match == "*" {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            response
+                .headers_mut()
+                .insert(header::ETAG, HeaderValue::from_str(&etag)?);
+            return Ok(response);
+        }
+    }
+
+    // Ranges are ignored for files with a download limit, every request is a counted download.
+    let range = match row.max_downloads {
+        Some(_) => ByteRange::Full,
+        None => requested_range(headers, filesize, &etag),
+    };
+    if range == ByteRange::Unsatisfiable {
+        let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes */{filesize}"))?,
+        );
+        return Ok(response);
+    }
+
+    // Open the stream for the file.
+    // This happens before the download is counted, so that the file can still be streamed
+    // even if this very download hits the limit and the file is removed further below.
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else {
+        let byte_range = match range {
+            ByteRange::Partial { start, end } => Some(start..end + 1),
+            _ => None,
+        };
+        let stream = aps
+            .storage
+            .get(hash, byte_range)
+            .await
+            .map_err(|_| not_found())?;
+        Body::from_stream(stream)
+    };
+
+    // Only a request that starts at the very beginning of the file begins a new download.
+    let starts_download = method != Method::HEAD
+        && match range {
+            ByteRange::Partial { start, .. } => start == 0,
+            _ => true,
+        };
+    if starts_download {
+        count_download(aps, hash).await?;
+    }
+
+    let mut response = match range {
+        ByteRange::Partial { start, end } => {
+            let mut response = (StatusCode::PARTIAL_CONTENT, body).into_response();
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {start}-{end}/{filesize}"))?,
             );
+            response
+        }
+        _ => {
+            let mut response = (StatusCode::OK, body).into_response();
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(filesize));
+            response
         }
     };
 
-    let body = Body::from_stream(ReaderStream::new(file));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        header::ACCEPT_RANGES,
+        HeaderValue::from_static(match row.max_downloads {
+            Some(_) => "none",
+            None => "bytes",
+        }),
+    );
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+
+    Ok(response)
+}
+
+/// Count a new download of the given file, enforcing its download limit.
+///
+/// The file is removed right away once the last download has been handed out.
+/// Streams that have already been opened stay readable until they have completed.
+async fn count_download(aps: &AppState, hash: &str) -> Result<(), AppError> {
+    #[derive(Debug, FromRow)]
+    struct CountRow {
+        downloads: i64,
+        max_downloads: Option<i64>,
+        parent_id: Option<i64>,
+    }
 
-    // Add to the download count.
-    sqlx::query("UPDATE uploaded_files SET downloads = ? WHERE efd_sha256sum = ?;")
-        .bind(row.downloads + 1)
+    // Add to the download count, but only if the download limit has not been reached yet.
+    // Checking and incrementing in a single statement ensures that two concurrent
+    // requests can never both get the last download.
+    let count: Option<CountRow> = sqlx::query_as("UPDATE uploaded_files SET downloads = downloads + 1 WHERE efd_sha256sum = ? AND (max_downloads IS NULL OR downloads < max_downloads) RETURNING downloads, max_downloads, parent_id;")
         .bind(hash)
-        .execute(&aps.db)
+        .fetch_optional(&aps.db)
         .await?;
 
-    Ok((StatusCode::OK, body))
+    let count = count.ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            "file not found, expired or download limit reached",
+        )
+    })?;
+    metrics::METRICS.count_download();
+
+    if count.max_downloads == Some(count.downloads) {
+        delete::cleanup_file(hash, aps).await?;
+        tracing::info!(
+            efd_sha256sum = hash,
+            "file reached its download limit and was removed"
+        );
+        metrics::METRICS.count_expiration(Removal::DownloadLimit);
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::DownloadLimitReached,
+            Actor::System,
+            Some(hash),
+            None,
+        )
+        .await;
+
+        // Bundles whose members have all been used up are of no use anymore either.
+        if let Some(parent_id) = count.parent_id {
+            let remaining: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM uploaded_files WHERE parent_id = ?;")
+                    .bind(parent_id)
+                    .fetch_one(&aps.db)
+                    .await?;
+            if remaining == 0 {
+                let bundle_sha256sum: String =
+                    sqlx::query_scalar("SELECT efd_sha256sum FROM uploaded_files WHERE id = ?;")
+                        .bind(parent_id)
+                        .fetch_one(&aps.db)
+                        .await?;
+                delete::cleanup_file(&bundle_sha256sum, aps).await?;
+                tracing::info!(
+                    efd_sha256sum = bundle_sha256sum,
+                    "bundle reached its download limit and was removed"
+                );
+                metrics::METRICS.count_expiration(Removal::DownloadLimit);
+                audit::record_or_log(
+                    &aps.db,
+                    AuditEvent::DownloadLimitReached,
+                    Actor::System,
+                    Some(&bundle_sha256sum),
+                    None,
+                )
+                .await;
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Use a struct for the download page template parameters.
@@ -103,9 +355,9 @@ struct DownloadPageContext<'a> {
     response_type: &'a str,
     error_head: &'a str,
     error_text: &'a str,
-    e_filename: &'a str,
-    iv_fd: &'a str,
-    iv_fn: &'a str,
+    members: &'a str,
+    is_bundle: bool,
+    passphrase_kdf: &'a str,
     filesize: &'a str,
     filesize_pretty: &'a str,
     upload_ts: &'a str,
@@ -113,6 +365,7 @@ struct DownloadPageContext<'a> {
     expiry_ts: &'a str,
     expiry_ts_pretty: &'a str,
     downloads: &'a str,
+    remaining_downloads: &'a str,
 }
 
 impl Default for DownloadPageContext<'_> {
@@ -121,9 +374,9 @@ impl Default for DownloadPageContext<'_> {
             response_type: "",
             error_head: "",
             error_text: "",
-            e_filename: "",
-            iv_fd: "",
-            iv_fn: "",
+            members: "[]",
+            is_bundle: false,
+            passphrase_kdf: "null",
             filesize: "0",
             filesize_pretty: "0 Bytes",
             upload_ts: "",
@@ -131,6 +384,7 @@ impl Default for DownloadPageContext<'_> {
             expiry_ts: "",
             expiry_ts_pretty: "",
             downloads: "0",
+            remaining_downloads: "",
         }
     }
 }
@@ -164,6 +418,37 @@ impl DownloadPageContext<'_> {
                 "hidden"
             },
         );
+        // Bundles list all of their files instead of displaying a single filename.
+        let file_shown = self.response_type == "file" || self.response_type == "admin";
+        context.insert(
+            "single_vis",
+            if file_shown && !self.is_bundle {
+                "flex"
+            } else {
+                "hidden"
+            },
+        );
+        context.insert(
+            "bundle_vis",
+            if file_shown && self.is_bundle {
+                "flex"
+            } else {
+                "hidden"
+            },
+        );
+        // Files with a download limit let everyone know how many downloads remain.
+        // For bundles, this is displayed for each of their files instead.
+        context.insert(
+            "limit_vis",
+            if file_shown && !self.is_bundle && !self.remaining_downloads.is_empty() {
+                "flex"
+            } else {
+                "hidden"
+            },
+        );
+        // Uploaders may change the expiry to any of the options offered during the upload.
+        context.insert("expiry_options", &aps.conf().labeled_expiry_options());
+        context.insert("allow_custom_expiry", &aps.conf().allow_custom_expiry);
         // For admin mode in particular there's a few more elements that need to be visible.
         context.insert(
             "admin_vis",
@@ -227,7 +512,7 @@ pub async fn download_page(
                 ..Default::default()
             };
 
-            let h = aps.tera.render("download.html", &dpc.to_context(&aps)?)?;
+            let h = aps.tera().render("download.html", &dpc.to_context(&aps)?)?;
 
             return Ok((
                 StatusCode::BAD_REQUEST,
@@ -248,7 +533,7 @@ pub async fn download_page(
             ..Default::default()
         };
 
-        let h = aps.tera.render("download.html", &dpc.to_context(&aps)?)?;
+        let h = aps.tera().render("download.html", &dpc.to_context(&aps)?)?;
 
         return Ok((
             StatusCode::BAD_REQUEST,
@@ -258,18 +543,20 @@ pub async fn download_page(
 
     #[derive(FromRow)]
     struct FileRow {
+        id: i64,
         admin_key_sha256sum: String,
-        e_filename: Vec<u8>,
-        iv_fd: Vec<u8>,
-        iv_fn: Vec<u8>,
         filesize: i64,
         upload_ts: String,
         expiry_ts: String,
         downloads: i64,
+        is_bundle: bool,
+        kdf_salt: Option<Vec<u8>>,
+        kdf_iterations: Option<i64>,
     }
 
     // Grab the row from the DB.
-    let row: Option<FileRow> = sqlx::query_as("SELECT admin_key_sha256sum, e_filename, iv_fd, iv_fn, filesize, upload_ts, expiry_ts, downloads FROM uploaded_files WHERE efd_sha256sum = ? LIMIT 1;")
+    // Members of a bundle are only ever shown as part of their bundle.
+    let row: Option<FileRow> = sqlx::query_as("SELECT id, admin_key_sha256sum, filesize, upload_ts, expiry_ts, downloads, is_bundle, kdf_salt, kdf_iterations FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;")
         .bind(hash)
         .fetch_optional(&aps.db)
         .await?;
@@ -286,7 +573,7 @@ pub async fn download_page(
             ..Default::default()
         };
 
-        let h = aps.tera.render("download.html", &dpc.to_context(&aps)?)?;
+        let h = aps.tera().render("download.html", &dpc.to_context(&aps)?)?;
 
         return Ok((
             StatusCode::NOT_FOUND,
@@ -297,17 +584,76 @@ pub async fn download_page(
     // Guaranteed to work.
     let row = row.ok_or_else(|| AppError::new500("illegal unwrap"))?;
 
+    #[derive(FromRow)]
+    struct MemberRow {
+        efd_sha256sum: String,
+        e_filename: Vec<u8>,
+        iv_fd: Vec<u8>,
+        iv_fn: Vec<u8>,
+        filesize: i64,
+        downloads: i64,
+        format_version: i64,
+        max_downloads: Option<i64>,
+    }
+
+    // Grab all the files that can be downloaded from this page.
+    // That's either all of the bundle's members or just the file itself.
+    let members: Vec<MemberRow> = sqlx::query_as("SELECT efd_sha256sum, e_filename, iv_fd, iv_fn, filesize, downloads, format_version, max_downloads FROM uploaded_files WHERE parent_id = ? OR (id = ? AND is_bundle = 0) ORDER BY id;")
+        .bind(row.id)
+        .bind(row.id)
+        .fetch_all(&aps.db)
+        .await?;
+
     // Extract several variables that we'll need in all cases.
     let filesize = row.filesize.to_string();
     let filesize_pretty = pretty_print_bytes(row.filesize as u64);
-    let efn = format!("[{}]", row.e_filename.iter().join(", "));
-    let iv_fd = format!("[{}]", row.iv_fd.iter().join(", "));
-    let iv_fn = format!("[{}]", row.iv_fn.iter().join(", "));
+
+    // The members are handed to the page's script as an array of objects.
+    let members_js = format!(
+        "[{}]",
+        members
+            .iter()
+            .map(|m| format!(
+                "{{hash: \"{}\", e_filename: [{}], iv_fd: [{}], iv_fn: [{}], filesize: {}, format_version: {}, remaining_downloads: {}}}",
+                m.efd_sha256sum,
+                m.e_filename.iter().join(", "),
+                m.iv_fd.iter().join(", "),
+                m.iv_fn.iter().join(", "),
+                m.filesize,
+                m.format_version,
+                m.max_downloads
+                    .map_or("null".to_string(), |v| (v - m.downloads).to_string())
+            ))
+            .join(", ")
+    );
+
+    // Passphrase-protected links hold a wrapped key that first has to be unwrapped.
+    let passphrase_kdf = match (&row.kdf_salt, row.kdf_iterations) {
+        (Some(salt), Some(iterations)) => format!(
+            "{{salt: [{}], iterations: {}}}",
+            salt.iter().join(", "),
+            iterations
+        ),
+        _ => "null".to_string(),
+    };
+
+    // Only single files display their remaining downloads outside of the member list.
+    let remaining_downloads = match (row.is_bundle, members.first()) {
+        (false, Some(m)) => m
+            .max_downloads
+            .map_or(String::new(), |v| (v - m.downloads).to_string()),
+        _ => String::new(),
+    };
 
     // Also extract and convert several more variables.
     // We only need them if the admin key is given,
     // but due to lifetime issues we're already converting them here.
-    let downloads = row.downloads.to_string();
+    // The files of a bundle are downloaded individually, so their counts are summed up.
+    let downloads = if row.is_bundle {
+        members.iter().map(|m| m.downloads).sum::<i64>().to_string()
+    } else {
+        row.downloads.to_string()
+    };
 
     // Timestamps
     let uts = DateTime::parse_from_rfc3339(&row.upload_ts)?;
@@ -324,29 +670,14 @@ pub async fn download_page(
 
     // Now, branch depending on whether there's an admin key.
     if let Some(admin) = admin {
-        // Perform the following steps:
-        // 1) Turn the base64url-encoded admin_key to binary.
-        // 2) Check that the binary key is exactly 32 bytes long.
-        //    Yes, sha256 is preimage-resistant for any length of data,
-        //    but we know this invariant must hold for legitimate keys,
-        //    so we might as well check it here regardless.
-        // 2) Calculate the sha256sum of that key in binary format.
-        // 3) Reencode the digest to base64url.
-        let admin_key_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
-            URL_SAFE_NO_PAD
-                .decode(admin)
-                .ok()
-                .filter(|v| v.len() == 32)
-                .unwrap_or_default(),
-        ));
-
-        // Now, check if the hashes match.
-        if admin_key_sha256sum == row.admin_key_sha256sum {
+        // Check if the admin key hashes to the stored sha256sum.
+        if admin_key_matches(admin, &row.admin_key_sha256sum) {
             dpc = DownloadPageContext {
                 response_type: "admin",
-                e_filename: &efn,
-                iv_fd: &iv_fd,
-                iv_fn: &iv_fn,
+                members: &members_js,
+                is_bundle: row.is_bundle,
+                passphrase_kdf: &passphrase_kdf,
+                remaining_downloads: &remaining_downloads,
                 filesize: &filesize,
                 filesize_pretty: &filesize_pretty,
                 upload_ts: &upload_ts,
@@ -361,9 +692,10 @@ pub async fn download_page(
                 response_type: "file",
                 error_head: "Invalid \"admin\" parameter",
                 error_text: "The \"admin\" parameter does not match the database record. Displaying normal file download instead.",
-                e_filename: &efn,
-                iv_fd: &iv_fd,
-                iv_fn: &iv_fn,
+                members: &members_js,
+                is_bundle: row.is_bundle,
+                passphrase_kdf: &passphrase_kdf,
+                remaining_downloads: &remaining_downloads,
                 filesize: &filesize,
                 filesize_pretty: &filesize_pretty,
                 ..Default::default()
@@ -372,9 +704,10 @@ pub async fn download_page(
     } else {
         dpc = DownloadPageContext {
             response_type: "file",
-            e_filename: &efn,
-            iv_fd: &iv_fd,
-            iv_fn: &iv_fn,
+            members: &members_js,
+            is_bundle: row.is_bundle,
+            passphrase_kdf: &passphrase_kdf,
+            remaining_downloads: &remaining_downloads,
             filesize: &filesize,
             filesize_pretty: &filesize_pretty,
             ..Default::default()
@@ -382,7 +715,7 @@ pub async fn download_page(
     }
 
     // Use the DownloadPageContext to actually render the template.
-    let h = aps.tera.render("download.html", &dpc.to_context(&aps)?)?;
+    let h = aps.tera().render("download.html", &dpc.to_context(&aps)?)?;
 
     // Minify and return.
     Ok((
@@ -3,7 +3,12 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::metrics;
 
 /// Use a custom error type that can be returned by handlers
 ///
@@ -37,11 +42,32 @@ impl AppError {
     pub fn new500(message: impl Into<String>) -> Self {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
     }
-}
 
-impl IntoResponse for AppError {
-    /// Allow axum to automatically convert our custom [AppError] into a [Response]
-    fn into_response(self) -> Response {
+    /// Stable, machine-readable error code for the REST API, derived from the StatusCode.
+    ///
+    /// Unlike the messages, these codes are part of the API and must never change.
+    pub fn code(&self) -> &'static str {
+        match self.status_code {
+            StatusCode::BAD_REQUEST => "bad_request",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::REQUEST_TIMEOUT => "request_timeout",
+            StatusCode::CONFLICT => "conflict",
+            StatusCode::PRECONDITION_FAILED => "precondition_failed",
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+            StatusCode::RANGE_NOT_SATISFIABLE => "range_not_satisfiable",
+            StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+            StatusCode::INSUFFICIENT_STORAGE => "insufficient_storage",
+            s if s.is_client_error() => "client_error",
+            _ => "internal_error",
+        }
+    }
+
+    /// Report the error in the logs, depending on its severity.
+    fn log(&self) {
+        metrics::METRICS.count_error(self.status_code);
         // Report client-side errors that are not 404s as warnings.
         // This might help identify implementation problems in the frontent.
         if self.status_code.is_client_error() && self.status_code != StatusCode::NOT_FOUND {
@@ -50,6 +76,13 @@ impl IntoResponse for AppError {
         } else if self.status_code.is_server_error() {
             tracing::error!(status_code = self.status_code.to_string(), self.message);
         }
+    }
+}
+
+impl IntoResponse for AppError {
+    /// Allow axum to automatically convert our custom [AppError] into a [Response]
+    fn into_response(self) -> Response {
+        self.log();
         (
             self.status_code,
             format!("{}: {}", self.status_code, self.message),
@@ -71,3 +104,45 @@ where
         }
     }
 }
+
+/// Error type of the REST API, which reports errors as JSON instead of plain text
+///
+/// Every [AppError] converts into an ApiError, so the ?-operator works just like it does
+/// in the other request handlers.
+pub struct ApiError(pub AppError);
+
+/// JSON body of every error response returned by the REST API
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    /// Stable, machine-readable error code, e.g. 'not_found'
+    #[schema(example = "not_found")]
+    pub code: &'static str,
+    /// Human-readable description of the error, which may change at any time
+    #[schema(example = "file not found or expired")]
+    pub message: String,
+}
+
+impl IntoResponse for ApiError {
+    /// Allow axum to automatically convert our [ApiError] into a JSON [Response]
+    fn into_response(self) -> Response {
+        self.0.log();
+        (
+            self.0.status_code,
+            Json(ApiErrorBody {
+                code: self.0.code(),
+                message: self.0.message,
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<AppError>,
+{
+    /// Build an ApiError out of anything that an [AppError] can be built from.
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
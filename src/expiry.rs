@@ -0,0 +1,164 @@
+//! Endpoint and utilities for changing when a file expires after it has been uploaded
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+
+use crate::audit::{Actor, AuditEvent};
+use crate::download::pretty_print_delta;
+use crate::upload::{self, Expiry};
+use crate::*;
+
+#[derive(Debug, Deserialize)]
+pub struct ExpiryChangeRequest {
+    hash: String,
+    admin: String,
+    duration: Option<String>,
+    expiry_ts: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExpiryChangeResponse {
+    expiry_ts: String,
+    expiry_ts_pretty: String,
+    expiry_ts_formatted: String,
+}
+
+/// Endpoint where the uploader POSTs a new expiry for their file, authorized by its admin key.
+///
+/// Responds with the new expiry, formatted just like on the download page.
+pub async fn expiry_endpoint(
+    State(aps): State<AppState>,
+    Json(req): Json<ExpiryChangeRequest>,
+) -> Result<Json<ExpiryChangeResponse>, AppError> {
+    // Do not entertain hashes with invalid length.
+    if req.hash.len() != 43 {
+        return AppError::err(StatusCode::BAD_REQUEST, "invalid hash length");
+    }
+
+    // Members of a bundle share its expiry, which can only be changed as a whole.
+    let row: Option<(String, String)> = sqlx::query_as(
+        "SELECT admin_key_sha256sum, expiry_ts FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;",
+    )
+    .bind(&req.hash)
+    .fetch_optional(&aps.db)
+    .await?;
+    let Some((admin_key_sha256sum, _)) = row.filter(|v| !has_expired(&v.1).unwrap_or(true)) else {
+        return AppError::err(StatusCode::NOT_FOUND, "file not found or expired");
+    };
+
+    if !admin_key_matches(&req.admin, &admin_key_sha256sum) {
+        return AppError::err(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let expiry = parse(
+        req.duration.as_deref(),
+        req.expiry_ts.as_deref(),
+        &aps.conf(),
+    )?;
+    let expiry_ts = apply(&aps, &mut *aps.db.acquire().await?, &req.hash, expiry).await?;
+
+    tracing::info!(
+        efd_sha256sum = req.hash,
+        expiry_ts,
+        "changed expiry of file"
+    );
+    audit::record_or_log(
+        &aps.db,
+        AuditEvent::ExpiryChanged,
+        Actor::Uploader,
+        Some(&req.hash),
+        Some(&expiry_ts),
+    )
+    .await;
+
+    let ets = DateTime::parse_from_rfc3339(&expiry_ts)?;
+    Ok(Json(ExpiryChangeResponse {
+        expiry_ts_pretty: pretty_print_delta(Utc::now(), ets),
+        expiry_ts_formatted: ets.format("(%c)").to_string(),
+        expiry_ts,
+    }))
+}
+
+/// Parse a new expiry from its request parameters and ensure the configuration permits it.
+///
+/// Exactly one of the duration and the RFC3339 expiry_ts has to be present.
+pub fn parse(
+    duration: Option<&str>,
+    expiry_ts: Option<&str>,
+    conf: &AppConfiguration,
+) -> Result<Expiry, AppError> {
+    let expiry = Expiry::from_fields(
+        duration
+            .map(|v| upload::parse_duration(v.as_bytes()))
+            .transpose()?,
+        expiry_ts
+            .map(|v| upload::parse_expiry_ts(v.as_bytes()))
+            .transpose()?,
+    )?;
+    expiry.check(conf)?;
+    Ok(expiry)
+}
+
+/// Compute the new RFC3339 expiry timestamp of a file that was uploaded at `upload_ts`.
+///
+/// However often its expiry is changed, a file may not be kept for longer than the maximum
+/// expiry counted from its upload. Durations are cut short at that point, as long as it still
+/// lies ahead, while exact timestamps past it are rejected.
+fn changed_expiry_ts(
+    aps: &AppState,
+    upload_ts: Option<&str>,
+    expiry: Expiry,
+) -> Result<String, AppError> {
+    let upload_ts = upload_ts.ok_or_else(|| AppError::new500("file has no upload timestamp"))?;
+    let conf = aps.conf();
+    let latest_expiry = DateTime::parse_from_rfc3339(upload_ts)? + conf.maximum_expiry_duration();
+
+    let (_, expiry_ts) = upload::upload_and_expiry_ts(aps, expiry)?;
+    if DateTime::parse_from_rfc3339(&expiry_ts)? <= latest_expiry {
+        return Ok(expiry_ts);
+    }
+    match expiry {
+        Expiry::After(_) if latest_expiry > Utc::now() => Ok(latest_expiry.to_rfc3339()),
+        _ => AppError::err(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "files can be kept for at most {} after their upload, i.e. until {}",
+                config::format_duration_input(&conf.maximum_expiry),
+                latest_expiry.to_rfc3339()
+            ),
+        ),
+    }
+}
+
+/// Apply a new expiry to a file as part of a larger transaction, returning the resulting
+/// RFC3339 expiry timestamp.
+///
+/// Files that have expired, but haven't been cleaned up yet, count as nonexistent and can't
+/// be brought back. Members of a bundle share its expiry, so they're updated along with it.
+pub async fn apply(
+    aps: &AppState,
+    tx: &mut SqliteConnection,
+    efd_sha256sum: &str,
+    expiry: Expiry,
+) -> Result<String, AppError> {
+    let row: Option<(i64, Option<String>)> = sqlx::query_as(
+        "SELECT id, upload_ts FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL AND julianday(expiry_ts) > julianday('now') LIMIT 1;",
+    )
+    .bind(efd_sha256sum)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((id, upload_ts)) = row else {
+        return AppError::err(StatusCode::NOT_FOUND, "file not found or expired");
+    };
+
+    let expiry_ts = changed_expiry_ts(aps, upload_ts.as_deref(), expiry)?;
+    sqlx::query("UPDATE uploaded_files SET expiry_ts = ? WHERE id = ? OR parent_id = ?;")
+        .bind(&expiry_ts)
+        .bind(id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(expiry_ts)
+}
@@ -0,0 +1,103 @@
+//! Liveness and readiness probes for container orchestrators
+
+use std::collections::BTreeMap;
+
+use axum::{extract::State, http::StatusCode, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use rand::{prelude::*, rng};
+use serde::Serialize;
+
+use crate::*;
+
+/// How long the cleanup cronjob may go without completing a run before the app counts as unready
+///
+/// A single run that fails early is tolerated, two in a row are not.
+const CLEANUP_STALE_AFTER: chrono::TimeDelta =
+    chrono::TimeDelta::seconds(3 * auto_cleanup::CLEANUP_INTERVAL.as_secs() as i64);
+
+/// Endpoint telling whether the process is alive and serving requests at all.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, &'static str>,
+}
+
+/// Endpoint telling whether the app is able to do its job, responding with 503 if it isn't.
+///
+/// The response only lists which checks failed, the reasons end up in the logs.
+pub async fn readyz(State(aps): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let results = [
+        ("database", check_database(&aps).await),
+        ("migrations", check_migrations(&aps).await),
+        ("uploaded_files", check_uploaded_files().await),
+        ("cleanup", check_cleanup(&aps).await),
+    ];
+
+    let mut checks = BTreeMap::new();
+    for (name, result) in results {
+        let outcome = match result {
+            Ok(_) => "ok",
+            Err(e) => {
+                tracing::warn!(check = name, "readiness check failed: {e}");
+                "failing"
+            }
+        };
+        checks.insert(name, outcome);
+    }
+
+    let ready = checks.values().all(|v| *v == "ok");
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+async fn check_database(aps: &AppState) -> Result<(), anyhow::Error> {
+    sqlx::query("SELECT 1;").execute(&aps.db).await?;
+    Ok(())
+}
+
+/// Ensure every migration compiled into the binary has been applied successfully.
+async fn check_migrations(aps: &AppState) -> Result<(), anyhow::Error> {
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1;")
+            .fetch_all(&aps.db)
+            .await?;
+    match MIGRATOR.iter().find(|v| !applied.contains(&v.version)) {
+        Some(v) => Err(anyhow::anyhow!(
+            "migration {} has not been applied",
+            v.version
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Ensure uploads can be staged, regardless of the storage backend.
+async fn check_uploaded_files() -> Result<(), anyhow::Error> {
+    // The staging prefix makes sure the probe is removed on the next launch, should we crash here.
+    let path = format!(
+        "{DATA_PATH}/uploaded_files/{}readyz-{}",
+        upload::STAGED_FILE_PREFIX,
+        URL_SAFE_NO_PAD.encode(rng().random::<[u8; 8]>())
+    );
+    tokio::fs::write(&path, b"").await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
+
+async fn check_cleanup(aps: &AppState) -> Result<(), anyhow::Error> {
+    let last_cleanup = *aps.last_cleanup.read().await;
+    if Utc::now() - last_cleanup > CLEANUP_STALE_AFTER {
+        return Err(anyhow::anyhow!(
+            "the cleanup cronjob last completed a run at {}",
+            last_cleanup.to_rfc3339()
+        ));
+    }
+    Ok(())
+}
@@ -2,10 +2,10 @@
 
 use crate::*;
 use axum::{
-    extract::{ConnectInfo, FromRef, FromRequestParts, Request, State},
+    extract::{ConnectInfo, FromRef, FromRequestParts, MatchedPath, Request, State},
     http::{request::Parts, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 use std::{
     fmt::Display,
@@ -90,6 +90,20 @@ impl IpPrefix {
             }
         }
     }
+
+    /// Parse an IP address typed in by an admin, either pretty-printed or canonical.
+    ///
+    /// IPv6 addresses may carry a '/64' suffix, any bits beyond the prefix are ignored.
+    pub fn parse_user_input(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if let Ok(v) = IpPrefix::from_str(s) {
+            return Some(v);
+        }
+        s.trim_end_matches("/64")
+            .parse::<IpAddr>()
+            .ok()
+            .map(IpPrefix::from)
+    }
 }
 
 /// This extractor conveniently allows us to extract a client's IP as an IpPrefix.
@@ -116,7 +130,7 @@ where
         // If proxy_depth is 0 there is no reverse-proxy, and we work directly with
         // the SocketAddr. Otherwise, we extract the IP address from the X-Forwarded-For header,
         // taking care to select the right one in the chain.
-        let ip: IpAddr = match AppState::from_ref(state).conf.proxy_depth {
+        let ip: IpAddr = match AppState::from_ref(state).conf().proxy_depth {
             0 => {
                 parts
                     .extensions
@@ -154,20 +168,35 @@ pub async fn ip_prefix_ratelimiter(
     ExtractIpPrefix(eip): ExtractIpPrefix,
     request: Request,
     next: Next,
-) -> Result<Response, AppError> {
+) -> Response {
     // Acquire a writing reference to the rate-limiter.
     let mut rl = aps.rate_limiter.write().await;
     // Insert the key if it wasn't already there and update its counter.
     let counter = *rl.entry(eip).and_modify(|v| *v += 1).or_insert(1);
     // Drop our borrow, or we can only process one request at a time, lol.
     drop(rl);
+
+    // Rejections are counted per route pattern, as opposed to the arbitrary paths clients send.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |v| v.as_str())
+        .to_string();
+
     // Rate limit, if need be.
-    if counter <= aps.conf.daily_request_limit_per_ip {
-        Ok(next.run(request).await)
+    let response = if counter <= aps.conf().daily_request_limit_per_ip {
+        next.run(request).await
     } else {
-        AppError::err(
+        AppError::new(
             StatusCode::TOO_MANY_REQUESTS,
             "too many requests, come back later",
         )
+        .into_response()
+    };
+
+    // This includes the more specific limits enforced by the handlers, like the upload limit.
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        metrics::METRICS.count_rate_limited(&route);
     }
+    response
 }
@@ -0,0 +1,187 @@
+//! Brute-force protection for the admin login through exponentially growing lockouts
+//!
+//! Failed logins are counted both per IpPrefix and across all clients.
+//! After a couple of free attempts every further failure locks the client out for twice as long.
+//! Too many failures across all clients slow down the login for everyone instead of locking it,
+//! so that an attacker can't keep legitimate admins out.
+//! Everything is stored in the database, so restarting the server doesn't lift any lockouts.
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::*;
+
+/// Failures an IpPrefix may accumulate before it gets locked out
+const CLIENT_FREE_ATTEMPTS: i64 = 5;
+
+/// Failures all clients together may accumulate before the login is slowed down for everyone
+///
+/// This hinders attackers that spread their attempts across many addresses.
+const GLOBAL_FREE_ATTEMPTS: i64 = 100;
+
+/// Length of the first lockout, doubling with every further failure
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+
+/// Upper bound of the lockout of a single IpPrefix
+const MAX_CLIENT_LOCKOUT_SECONDS: i64 = 24 * 60 * 60;
+
+/// Upper bound of the global slowdown, kept short since it affects legitimate admins as well
+const MAX_GLOBAL_LOCKOUT_SECONDS: i64 = 15 * 60;
+
+/// Delay of every login attempt while the global slowdown is in effect
+const GLOBAL_SLOWDOWN: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Time after the last failure after which the counter starts over
+const FAILURE_WINDOW_SECONDS: i64 = 24 * 60 * 60;
+
+/// Key of the counter shared by all clients
+const GLOBAL_CLIENT: &str = "global";
+
+/// Look up until when the login is locked for the given client, if it is locked at all.
+pub async fn locked_until(aps: &AppState, eip: &IpPrefix) -> Result<Option<String>, AppError> {
+    lock_of(aps, &eip.to_string()).await
+}
+
+/// Delay a login attempt while too many logins have failed across all clients.
+///
+/// Unlike a lockout of the client, this never turns away an admin with the right password.
+pub async fn slow_down(aps: &AppState) -> Result<(), AppError> {
+    if let Some(locked_until_ts) = lock_of(aps, GLOBAL_CLIENT).await? {
+        tracing::debug!(
+            locked_until_ts,
+            "delaying admin login attempt due to too many failed logins overall"
+        );
+        tokio::time::sleep(GLOBAL_SLOWDOWN).await;
+    }
+    Ok(())
+}
+
+/// Look up the ongoing lockout of the counter with the given key, if any.
+async fn lock_of(aps: &AppState, client: &str) -> Result<Option<String>, AppError> {
+    let lock: Option<Option<String>> =
+        sqlx::query_scalar("SELECT locked_until_ts FROM login_failures WHERE client = ?;")
+            .bind(client)
+            .fetch_optional(&aps.db)
+            .await?;
+
+    match lock.flatten() {
+        Some(lock) if !has_expired(&lock)? => Ok(Some(lock)),
+        _ => Ok(None),
+    }
+}
+
+/// Count a failed login and lock the client out if they're out of free attempts.
+pub async fn record_failure(
+    aps: &AppState,
+    eip: &IpPrefix,
+    username: &str,
+) -> Result<(), AppError> {
+    let client_lockout = increment(
+        aps,
+        &eip.to_string(),
+        CLIENT_FREE_ATTEMPTS,
+        MAX_CLIENT_LOCKOUT_SECONDS,
+    )
+    .await?;
+    let global_lockout = increment(
+        aps,
+        GLOBAL_CLIENT,
+        GLOBAL_FREE_ATTEMPTS,
+        MAX_GLOBAL_LOCKOUT_SECONDS,
+    )
+    .await?;
+
+    tracing::warn!(
+        ip = eip.pretty_print(),
+        username,
+        failures = client_lockout.0,
+        locked_until_ts = client_lockout.1,
+        "security event: failed admin login"
+    );
+    if let Some(ts) = global_lockout.1 {
+        tracing::warn!(
+            failures = global_lockout.0,
+            locked_until_ts = ts,
+            "security event: too many failed admin logins overall, slowing down the login for everyone"
+        );
+    }
+    Ok(())
+}
+
+/// Forget the failures of a client after they've logged in successfully.
+///
+/// The global counter is left untouched, a single success doesn't end a distributed attack.
+pub async fn record_success(aps: &AppState, eip: &IpPrefix) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM login_failures WHERE client = ?;")
+        .bind(eip.to_string())
+        .execute(&aps.db)
+        .await?;
+    Ok(())
+}
+
+/// Remove counters whose failure window and lockout have both passed.
+pub async fn cleanup(aps: &AppState) -> Result<(), AppError> {
+    let rows: Vec<(String, String, Option<String>)> =
+        sqlx::query_as("SELECT client, last_failure_ts, locked_until_ts FROM login_failures;")
+            .fetch_all(&aps.db)
+            .await?;
+
+    let now = Utc::now();
+    for (client, last_failure_ts, locked_until_ts) in rows {
+        let window_passed = DateTime::parse_from_rfc3339(&last_failure_ts)?
+            < now - TimeDelta::seconds(FAILURE_WINDOW_SECONDS);
+        let lock_passed = locked_until_ts.map_or(Ok(true), |v| has_expired(&v))?;
+        if window_passed && lock_passed {
+            sqlx::query("DELETE FROM login_failures WHERE client = ?;")
+                .bind(&client)
+                .execute(&aps.db)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Increment the failure counter of a client, returning the new count and lockout, if any.
+///
+/// The counter is incremented in a single statement, so concurrent failures are never lost.
+async fn increment(
+    aps: &AppState,
+    client: &str,
+    free_attempts: i64,
+    max_lockout_seconds: i64,
+) -> Result<(i64, Option<String>), AppError> {
+    let now = Utc::now();
+    let window_start = now - TimeDelta::seconds(FAILURE_WINDOW_SECONDS);
+
+    // Start over if the last failure happened long enough ago and no lockout is ongoing.
+    let failures: i64 = sqlx::query_scalar(
+        "INSERT INTO login_failures (client, failures, last_failure_ts) VALUES (?, 1, ?) ON CONFLICT (client) DO UPDATE SET failures = CASE WHEN julianday(last_failure_ts) >= julianday(?) OR julianday(locked_until_ts) > julianday(excluded.last_failure_ts) THEN failures + 1 ELSE 1 END, last_failure_ts = excluded.last_failure_ts RETURNING failures;",
+    )
+    .bind(client)
+    .bind(now.to_rfc3339())
+    .bind(window_start.to_rfc3339())
+    .fetch_one(&aps.db)
+    .await?;
+
+    // Every failure beyond the free attempts doubles the lockout.
+    let locked_until_ts = (failures > free_attempts).then(|| {
+        let exponent = (failures - free_attempts - 1).min(32) as u32;
+        let seconds = BASE_LOCKOUT_SECONDS
+            .saturating_mul(2i64.saturating_pow(exponent))
+            .min(max_lockout_seconds);
+        (now + TimeDelta::seconds(seconds)).to_rfc3339()
+    });
+
+    // Concurrent failures may finish in any order, so a lockout is only ever extended.
+    if let Some(ref locked_until_ts) = locked_until_ts {
+        sqlx::query(
+            "UPDATE login_failures SET locked_until_ts = ? WHERE client = ? AND (locked_until_ts IS NULL OR julianday(locked_until_ts) < julianday(?));",
+        )
+        .bind(locked_until_ts)
+        .bind(client)
+        .bind(locked_until_ts)
+        .execute(&aps.db)
+        .await?;
+    }
+
+    Ok((failures, locked_until_ts))
+}
@@ -5,12 +5,24 @@
 //! <https://github.com/TobiasMarschner/ferrishare>
 
 use axum::{
-    Router, extract::{ConnectInfo, DefaultBodyLimit, State}, http::StatusCode, middleware::{self, Next}, response::{Html, IntoResponse, Response}, routing::{get, post}
+    extract::{ConnectInfo, DefaultBodyLimit, State},
+    handler::Handler,
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, head, post, put},
+    Router,
 };
-use clap::Parser;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
 use minify_html::minify;
-use sqlx::{migrate::MigrateDatabase, FromRow, Sqlite, SqlitePool};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    migrate::{MigrateDatabase, Migrator},
+    FromRow, Sqlite, SqlitePool,
+};
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
@@ -30,26 +42,48 @@ pub use config::AppConfiguration;
 pub use error_handling::AppError;
 pub use ip_prefix::{ExtractIpPrefix, IpPrefix};
 
+mod accounts;
 mod admin;
+mod api;
+mod audit;
 mod auto_cleanup;
+mod bulk;
+mod bundle;
+mod cli;
 mod config;
+mod csrf;
 mod delete;
 mod download;
 mod error_handling;
+mod expiry;
+mod health;
 mod ip_prefix;
+mod lockout;
+mod metrics;
+mod reload;
+mod report;
+mod storage;
+mod totp;
+mod tus;
 mod upload;
 
 /// The application's global state that is passed to every request handler
 #[derive(Debug, Clone)]
 pub struct AppState {
     /// The global TERA instance responsible for HTML and JS templating
-    tera: Arc<Tera>,
+    ///
+    /// Replaced as a whole when the templates are reloaded, see [AppState::tera].
+    tera: Arc<std::sync::RwLock<Arc<Tera>>>,
     /// The global SqlitePool responsible for making queries to the SQLite-database
     ///
     /// [SqlitePool] is internally wrapped in an [Arc], so no need to wrap it here
     db: SqlitePool,
-    /// Immutable global configuration for FerriShare, read during startup from 'config.toml'
-    conf: Arc<AppConfiguration>,
+    /// Global configuration for FerriShare, read during startup from 'config.toml'
+    ///
+    /// Replaced as a whole when the configuration is reloaded, see [AppState::conf].
+    conf: Arc<std::sync::RwLock<Arc<AppConfiguration>>>,
+    /// Where the configuration was read from, so that it can be read again on reload
+    config_source: Arc<reload::ConfigSource>,
     /// Table keeping track of the number of requests made by each IpPrefix for rate limiting
     rate_limiter: Arc<RwLock<HashMap<IpPrefix, u64>>>,
     /// Set of IpPrefixes that are uploading a file at this moment
@@ -58,18 +92,38 @@ pub struct AppState {
     /// Otherwise, a malicious client could start hundreds of uploads
     /// simultaneously and bypass quota restrictions.
     uploading: Arc<RwLock<HashSet<IpPrefix>>>,
+    /// The storage backend holding the encrypted filedata of all uploaded files
+    storage: Arc<dyn storage::Storage>,
+    /// Point in time the cleanup cronjob last completed a run, initially the time of launch
+    last_cleanup: Arc<RwLock<DateTime<Utc>>>,
 }
 
 impl AppState {
+    /// The configuration currently in effect
+    ///
+    /// Handlers that read several settings should hold on to the returned snapshot,
+    /// so that a concurrent reload can't hand them a mix of old and new settings.
+    pub fn conf(&self) -> Arc<AppConfiguration> {
+        // Nothing can panic while the lock is held, but there's no reason to propagate it either.
+        self.conf.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The templates currently in effect
+    pub fn tera(&self) -> Arc<Tera> {
+        self.tera.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
     /// Create the default TERA templating context containing variables needed on every page
     pub fn default_context(&self) -> tera::Context {
+        let conf = self.conf();
         let mut context = tera::Context::new();
-        context.insert("global_app_name", &self.conf.app_name);
-        context.insert("enable_privacy_policy", &self.conf.enable_privacy_policy);
-        context.insert("enable_legal_notice", &self.conf.enable_legal_notice);
-        context.insert("demo_mode", &self.conf.demo_mode);
+        context.insert("global_app_name", &conf.app_name);
+        context.insert("enable_privacy_policy", &conf.enable_privacy_policy);
+        context.insert("enable_legal_notice", &conf.enable_legal_notice);
+        context.insert("demo_mode", &conf.demo_mode);
         context.insert("global_crate_version", env!("CARGO_PKG_VERSION"));
         context.insert("global_git_hash", option_env!("VCS_REF").unwrap_or("dev"));
+        context.insert("csrf_token", &csrf::current_token().unwrap_or_default());
         context
     }
 }
@@ -100,7 +154,8 @@ pub const MINIFY_CFG: minify_html::Cfg = minify_html::Cfg {
 /// This includes:
 /// - The configuration at 'config.toml'
 /// - The database at 'sqlite.db'
-/// - All uploaded files in 'uploaded_files/'
+/// - All uploaded files in 'uploaded_files/', unless they're kept in an object storage
+/// - Resumable uploads that are still in progress in 'partial_uploads/'
 /// - User templates in 'user_templates/'
 const DATA_PATH: &str = "./data";
 
@@ -109,7 +164,12 @@ const DEFAULT_CONFIG_PATH: &str = "./data/config.toml";
 /// Path to the application's SQLite-database
 const DB_URL: &str = "sqlite://data/sqlite.db";
 
-/// Currently supported maximum filesize due to WebCrypto limitations.
+/// Migrations creating all required tables, included in the binary at compile time
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Maximum filesize of files encrypted in a single WebCrypto-operation (format version 1).
+///
+/// Files using the segmented format (version 2) are not subject to this limitation.
 const WEBCRYPTO_MAX_FILESIZE: u64 = 2147483648;
 
 /// Custom middleware for tracing HTTP requests.
@@ -129,7 +189,12 @@ async fn custom_tracing(
     next: Next,
 ) -> Response {
     // Extract all relevant info from the request.
-    let path = request.uri().path();
+    // The upload id in tus-paths grants access to the upload session, so it is redacted as well.
+    let path = if request.uri().path().starts_with("/tus/") {
+        "/tus/<REDACTED IN LOGS>"
+    } else {
+        request.uri().path()
+    };
     let query = request.uri().query().map(|v| {
         // Remove "admin=XXX" query parameter since the plaintext admin_key of a single file
         // is not supposed to be stored anywhere on the server, not even in the logs.
@@ -181,31 +246,89 @@ struct Args {
     #[arg(long)]
     init: bool,
 
+    /// Create the 'config.toml' without prompting, e.g. when provisioning a container.
+    ///
+    /// Starts from the setup's suggested defaults, overridden by environment variables and '--set'.
+    /// The admin password is read from '--admin-password-file' and hashed.
+    #[arg(long, requires = "init")]
+    non_interactive: bool,
+
+    /// File containing the admin password for the non-interactive setup, or '-' to read it from stdin.
+    #[arg(long, env = "FERRISHARE_ADMIN_PASSWORD_FILE", value_name = "FILE")]
+    admin_password_file: Option<PathBuf>,
+
     /// Override the default config file path, for both normal operation and the interactive setup mode.
     #[arg(long, default_value = DEFAULT_CONFIG_PATH,value_name="FILE")]
     config_file: PathBuf,
+
+    /// Override a setting of the 'config.toml', e.g. '--set maximum_filesize=1G'. (repeatable)
+    ///
+    /// Every setting can also be overridden with an environment variable named after it, e.g.
+    /// 'FERRISHARE_MAXIMUM_FILESIZE', or 'FERRISHARE_STORAGE__BUCKET' for the storage table.
+    /// '--set' takes precedence over the environment, which takes precedence over the file.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    /// Print the effective configuration with all overrides applied and secrets redacted, then exit.
+    #[arg(long, conflicts_with = "init")]
+    print_config: bool,
+
+    /// Use the command-line client or manage administrators instead of running the server.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Subcommands that are run instead of the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(flatten)]
+    Client(cli::Command),
+
+    /// Manage the accounts of site-wide administrators
+    Admin {
+        #[command(subcommand)]
+        command: accounts::AdminCommand,
+    },
 }
 
 /// The application's main starting point
 #[tokio::main]
 async fn main() -> ExitCode {
+    // Parse cmd-line arguments. The subcommands don't run the server.
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Client(command)) => return cli::run(command).await,
+        Some(Command::Admin { command }) => return accounts::run(command).await,
+        None => {}
+    }
+
     // First things first, create the DATA_PATH and its subdirectories.
     std::fs::create_dir_all(format!("{DATA_PATH}/uploaded_files"))
+        .and_then(|_| std::fs::create_dir_all(format!("{DATA_PATH}/partial_uploads")))
         .and_then(|_| std::fs::create_dir_all(format!("{DATA_PATH}/user_templates")))
         .unwrap_or_else(|e| {
             panic!("failed to create configuration and application data directories at {DATA_PATH}: {e}")
         });
 
-    // Parse cmd-line arguments and check whether we're (re-)creating the config.toml.
-    let args = Args::parse();
+    // Check whether we're (re-)creating the config.toml.
     if args.init {
         // Set up config and exit immediately.
-        match config::setup_config(&args.config_file) {
+        let result = match args.non_interactive {
+            true => config::setup_config_non_interactive(
+                &args.config_file,
+                &args.set,
+                args.admin_password_file.as_deref(),
+            ),
+            false => config::setup_config(&args.config_file),
+        };
+        match result {
             Ok(_) => {
                 return ExitCode::SUCCESS;
             }
             Err(e) => {
-                panic!("failed to create config: {e}");
+                eprintln!("Failed to create configuration: {e}");
+                eprintln!("\nExiting!");
+                return ExitCode::FAILURE;
             }
         }
     }
@@ -230,11 +353,11 @@ async fn main() -> ExitCode {
         }
     };
 
-    let mut app_config: AppConfiguration = match toml::from_str(&config_string) {
+    let app_config = match config::parse_config(&config_string, &args.set) {
         Ok(v) => v,
         Err(e) => {
             eprintln!(
-                "Failed to parse configuration file at {:?}: {e}",
+                "Failed to load configuration from {:?}: {e}",
                 args.config_file
             );
 
@@ -247,6 +370,25 @@ async fn main() -> ExitCode {
         }
     };
 
+    if args.print_config {
+        match config::redacted_config(&app_config) {
+            Ok(v) => print!("{v}"),
+            Err(e) => {
+                eprintln!("Failed to print configuration: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Err(e) = app_config.validate() {
+        eprintln!("Invalid configuration file at {:?}: {e}", args.config_file);
+        eprintln!("\nExiting!");
+        return ExitCode::FAILURE;
+    }
+    if args.print_config {
+        return ExitCode::SUCCESS;
+    }
+
     // Set up `tracing` (logging).
     // Use the default formatting subscriber provided by `tracing_subscriber`.
     // The log level is provided by the configuration.
@@ -256,10 +398,29 @@ async fn main() -> ExitCode {
 
     tracing::info!("read config from {:?}", args.config_file);
 
-    // Limit the maximum filesize if need be and emit a warning in that case.
-    if app_config.maximum_filesize > WEBCRYPTO_MAX_FILESIZE {
-        app_config.maximum_filesize = WEBCRYPTO_MAX_FILESIZE;
-        tracing::warn!("Your maximum filesize is too large and has been lowered to 2GiB. The WebCrypto-API used on the frontend does not allow larger messages.");
+    // Remove staged files left behind by uploads that were interrupted by a crash or restart.
+    match std::fs::read_dir(format!("{DATA_PATH}/uploaded_files")) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                if entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(upload::STAGED_FILE_PREFIX)
+                {
+                    match std::fs::remove_file(entry.path()) {
+                        Ok(_) => tracing::info!("removed leftover staged file {:?}", entry.path()),
+                        Err(e) => tracing::warn!(
+                            "failed to remove leftover staged file {:?}: {e}",
+                            entry.path()
+                        ),
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("failed to read {DATA_PATH}/uploaded_files: {e}");
+            return ExitCode::FAILURE;
+        }
     }
 
     // Create the database if it doesn't already exist.
@@ -289,8 +450,7 @@ async fn main() -> ExitCode {
     };
 
     // Perform database migrations (create all required tables).
-    // Note that the migrate!-macro includes these in the binary at compile time.
-    match sqlx::migrate!("./migrations").run(&db).await {
+    match MIGRATOR.run(&db).await {
         Ok(_) => {
             tracing::info!("database migrations successful");
         }
@@ -301,21 +461,7 @@ async fn main() -> ExitCode {
     };
 
     // Initialize the templating engine.
-    let tera = match Tera::new("templates/**/*.{html,js}").and_then(|mut v| {
-        match v.add_template_files([
-            (
-                format!("{DATA_PATH}/user_templates/privacy_policy.html"),
-                Some("privacy_policy.html"),
-            ),
-            (
-                format!("{DATA_PATH}/user_templates/legal_notice.html"),
-                Some("legal_notice.html"),
-            ),
-        ]) {
-            Ok(_) => Ok(v),
-            Err(e) => Err(e),
-        }
-    }) {
+    let tera = match load_templates() {
         Ok(t) => {
             tracing::info!("successfully loaded and compiled HTML and JS templates");
             t
@@ -325,35 +471,71 @@ async fn main() -> ExitCode {
             return ExitCode::FAILURE;
         }
     };
-    // Wrap it in an Arc<_>, as required by AppState.
-    let tera = Arc::new(tera);
+
+    // Set up the storage backend and make sure it can actually be reached.
+    // Looking up a file that can't exist doubles as a check of the credentials.
+    let storage = match storage::from_config(&app_config.storage) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("failed to set up storage backend: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = storage.exists(upload::STAGED_FILE_PREFIX).await {
+        tracing::error!("failed to reach storage backend: {e}");
+        return ExitCode::FAILURE;
+    }
+    tracing::info!(
+        "using {} storage backend",
+        app_config.storage.backend_name()
+    );
 
     // Create the AppState out of database and template-engine.
     let aps = AppState {
-        tera,
+        tera: Arc::new(std::sync::RwLock::new(Arc::new(tera))),
         db,
-        conf: Arc::new(app_config),
+        conf: Arc::new(std::sync::RwLock::new(Arc::new(app_config))),
+        config_source: Arc::new(reload::ConfigSource {
+            path: args.config_file,
+            overrides: args.set,
+        }),
         rate_limiter: Arc::new(RwLock::new(HashMap::new())),
         uploading: Arc::new(RwLock::new(HashSet::new())),
+        storage,
+        last_cleanup: Arc::new(RwLock::new(Utc::now())),
     };
     // Keep a copy of the interface, we'll need it after the AppState has already been moved.
-    let interface = aps.conf.interface.clone();
+    // Like all settings the Router is built from, it is only read once and can't be reloaded.
+    let interface = aps.conf().interface.clone();
+
+    // Older installations only know the admin password from the config.toml.
+    if let Err(e) = accounts::seed_initial_admin(&aps).await {
+        tracing::error!("failed to create initial administrator: {e}");
+        return ExitCode::FAILURE;
+    }
 
     // Start the background-task that regularly cleans up expired files and sessions.
     tokio::spawn(auto_cleanup::cleanup_cronjob(aps.clone()));
 
+    // Reload the configuration and templates whenever we receive a SIGHUP.
+    tokio::spawn(reload::reload_on_sighup(aps.clone()));
+
     // Create all of the middlewares the app uses.
 
     // Small timeouts for all the "normal" routes that don't deal with files.
-    let timeout_small = TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(30));
+    let timeout_small =
+        TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(30));
 
     // Big timeout for file uploads and downloads.
     // The up- and download uses a longer timeout than the default 30s on the usual routes.
     // To accomodate very slow clients we assume each MB can take up to a full minute for up- or download.
     // However, the minimum timeout is always set to 120s.
     let file_endpoint_timeout_duration =
-        std::cmp::max(120, aps.conf.maximum_filesize as u64 / 17476);
-    let timeout_big = TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(file_endpoint_timeout_duration));
+        std::cmp::max(120, aps.conf().maximum_filesize as u64 / 17476);
+    let timeout_big = TimeoutLayer::with_status_code(
+        StatusCode::REQUEST_TIMEOUT,
+        Duration::from_secs(file_endpoint_timeout_duration),
+    );
     tracing::info!(
         "setting file endpoint timeout to {} seconds",
         file_endpoint_timeout_duration
@@ -368,6 +550,9 @@ async fn main() -> ExitCode {
     // Our custom middleware for tracing HTTP requests.
     let custom_tracing = middleware::from_fn_with_state(aps.clone(), custom_tracing);
 
+    // Our custom middleware protecting all routes the browser uses against CSRF.
+    let csrf_protection = middleware::from_fn_with_state(aps.clone(), csrf::csrf_protection);
+
     // Our custom middleware for rate-limiting with the IpPrefix.
     let rate_limiter =
         middleware::from_fn_with_state(aps.clone(), ip_prefix::ip_prefix_ratelimiter);
@@ -379,7 +564,7 @@ async fn main() -> ExitCode {
     // Set the upload size limit for the upload_endpoint to the accepted filesize
     // plus a generous 256 KiB for the other metadata.
     // The default limit is 2MB, not enough for most configurations.
-    let upload_endpoint_limit: usize = match (aps.conf.maximum_filesize + 262144).try_into() {
+    let upload_endpoint_limit: usize = match (aps.conf().maximum_filesize + 262144).try_into() {
         Ok(v) => v,
         Err(e) => {
             tracing::error!("maximum filesize exceeds computer's bit-width: {e}");
@@ -399,29 +584,79 @@ async fn main() -> ExitCode {
                 )),
         )
         .route("/download_endpoint", get(download::download_endpoint))
+        .layer(csrf_protection.clone())
+        // Resumable uploads using the tus protocol, usable by any standard tus client.
+        // Sessions are only ever authorized by their unguessable upload id, never by cookies.
+        .route("/tus", post(tus::tus_create).options(tus::tus_options))
+        .route(
+            "/tus/{upload_id}",
+            head(tus::tus_head).delete(tus::tus_delete).patch(
+                // Just like the upload_endpoint, any IpPrefix can only stream one file at a time.
+                tus::tus_patch.layer(axum::middleware::from_fn_with_state(
+                    aps.clone(),
+                    upload::upload_endpoint_wrapper,
+                )),
+            ),
+        )
+        // Versioned REST API for scripted use, accepting the same form as the upload_endpoint.
+        // It only ever authenticates through bearer tokens, so there is nothing to forge.
+        .route(
+            "/api/v1/files",
+            post(api::upload_file)
+                .layer(DefaultBodyLimit::max(upload_endpoint_limit))
+                .layer(axum::middleware::from_fn_with_state(
+                    aps.clone(),
+                    upload::upload_endpoint_wrapper,
+                )),
+        )
+        .route("/api/v1/files/{hash}/data", get(api::download_file))
         .layer(timeout_big);
 
     // The usual frontend routes
-    let mut normal_routers = Router::new()
+    let normal_routers = Router::new()
         // HTML routes
         .route("/", get(upload::upload_page))
         .route("/file", get(download::download_page))
         .route("/admin", get(admin::admin_page))
+        .route(
+            "/admin_totp",
+            get(admin::admin_totp_page).post(admin::admin_totp_enable),
+        )
         // API / non-HTML routes
         .route("/admin_login", post(admin::admin_login))
+        .route(
+            "/admin_login_second_factor",
+            post(admin::admin_login_second_factor),
+        )
+        .route("/admin_totp_disable", post(admin::admin_totp_disable))
         .route("/admin_logout", post(admin::admin_logout))
-        .route("/delete_endpoint", post(delete::delete_endpoint));
-
-    // Add Privacy Policy / Legal Notice, if configured.
-    if aps.conf.enable_privacy_policy {
-        normal_routers = normal_routers.route("/privacy-policy", get(privacy_policy));
-    }
-    if aps.conf.enable_legal_notice {
-        normal_routers = normal_routers.route("/legal-notice", get(legal_notice));
-    }
+        .route("/admin_session_revoke", post(admin::admin_session_revoke))
+        .route(
+            "/admin_session_revoke_others",
+            post(admin::admin_session_revoke_others),
+        )
+        .route("/delete_endpoint", post(delete::delete_endpoint))
+        .route("/expiry_endpoint", post(expiry::expiry_endpoint))
+        .route("/report_endpoint", post(report::report_endpoint))
+        .route("/admin_report_resolve", post(report::admin_report_resolve))
+        .route("/admin_bulk", post(bulk::bulk_endpoint))
+        .route("/admin_audit_export", get(audit::audit_export))
+        .route("/admin_reload", post(reload::admin_reload))
+        .route("/bundle_endpoint", post(bundle::bundle_endpoint))
+        // Privacy Policy / Legal Notice respond with 404 unless they're enabled.
+        .route("/privacy-policy", get(privacy_policy))
+        .route("/legal-notice", get(legal_notice));
 
     // Add middlewares for the normal routes.
+    // The REST API is added after the CSRF protection, which doesn't apply to it.
     let normal_routers = normal_routers
+        .layer(csrf_protection)
+        .route("/api/v1/openapi.json", get(api::openapi))
+        .route(
+            "/api/v1/files/{hash}",
+            get(api::file_metadata).delete(api::delete_file),
+        )
+        .route("/api/v1/files/{hash}/expiry", put(api::change_expiry))
         .layer(timeout_small)
         .layer(compression.clone());
 
@@ -447,7 +682,17 @@ async fn main() -> ExitCode {
         .merge(font_routers)
         .merge(static_routers)
         .layer(custom_tracing)
-        .layer(rate_limiter)
+        .layer(rate_limiter);
+
+    // Prometheus scrapes far more often than the request limit allows, so it's exempt.
+    // The bearer token keeps everybody else out, without one it responds with 404.
+    // The same goes for the probes of container orchestrators, which aren't logged either.
+    let app = app
+        .route("/metrics", get(metrics::metrics_endpoint))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+
+    let app = app
         .with_state(aps)
         .into_make_service_with_connect_info::<SocketAddr>();
 
@@ -499,22 +744,44 @@ async fn shutdown_handler() {
     tracing::info!("received shutdown signal");
 }
 
+/// Load and compile all HTML and JS templates, including the user templates.
+pub fn load_templates() -> Result<Tera, tera::Error> {
+    let mut tera = Tera::new("templates/**/*.{html,js}")?;
+    tera.add_template_files([
+        (
+            format!("{DATA_PATH}/user_templates/privacy_policy.html"),
+            Some("privacy_policy.html"),
+        ),
+        (
+            format!("{DATA_PATH}/user_templates/legal_notice.html"),
+            Some("legal_notice.html"),
+        ),
+    ])?;
+    Ok(tera)
+}
+
 /// Simple handler for the Privacy Policy
 ///
-/// Should only be inserted into the Router if the config enables the Privacy Policy.
+/// Responds with 404 unless the config enables the Privacy Policy.
 async fn privacy_policy(State(aps): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if !aps.conf().enable_privacy_policy {
+        return AppError::err(StatusCode::NOT_FOUND, "the privacy policy is disabled");
+    }
     let context = aps.default_context();
-    let html = aps.tera.render("privacy_policy.html", &context)?;
+    let html = aps.tera().render("privacy_policy.html", &context)?;
     let response_body = String::from_utf8(minify(html.as_bytes(), &MINIFY_CFG))?;
     Ok(Html(response_body))
 }
 
 /// Simple handler for the Legal Notice
 ///
-/// Should only be inserted into the Router if the config enables the Legal Notice.
+/// Responds with 404 unless the config enables the Legal Notice.
 async fn legal_notice(State(aps): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if !aps.conf().enable_legal_notice {
+        return AppError::err(StatusCode::NOT_FOUND, "the legal notice is disabled");
+    }
     let context = aps.default_context();
-    let html = aps.tera.render("legal_notice.html", &context)?;
+    let html = aps.tera().render("legal_notice.html", &context)?;
     let response_body = String::from_utf8(minify(html.as_bytes(), &MINIFY_CFG))?;
     Ok(Html(response_body))
 }
@@ -533,6 +800,20 @@ pub fn has_expired(expiry_ts: &str) -> Result<bool, AppError> {
         .is_negative())
 }
 
+/// Check whether an uploader's base64url-encoded admin key hashes to the stored sha256sum.
+///
+/// Legitimate keys are exactly 32 bytes long, anything else is rejected outright.
+pub fn admin_key_matches(admin_key: &str, admin_key_sha256sum: &str) -> bool {
+    let computed_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
+        URL_SAFE_NO_PAD
+            .decode(admin_key)
+            .ok()
+            .filter(|v| v.len() == 32)
+            .unwrap_or_default(),
+    ));
+    computed_sha256sum == admin_key_sha256sum
+}
+
 /// Takes a value in bytes and pretty prints it with a binary suffix.
 pub fn pretty_print_bytes(bytes: u64) -> String {
     match bytes {
@@ -0,0 +1,225 @@
+//! Prometheus metrics, served in the text-based exposition format on '/metrics'
+//!
+//! Counters are kept in a global, so that they can be bumped from anywhere without threading
+//! them through the AppState, including places like [AppError::into_response].
+//! Gauges are determined on every scrape instead.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::*;
+
+/// The counters of this process, reset on every restart
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Why a file was removed without anyone deleting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Removal {
+    /// The file reached its expiry and was cleaned up
+    Expired,
+    /// The file's last permitted download was handed out
+    DownloadLimit,
+}
+
+impl Removal {
+    fn label(&self) -> &'static str {
+        match self {
+            Removal::Expired => "expired",
+            Removal::DownloadLimit => "download_limit",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    uploads: AtomicU64,
+    downloads: AtomicU64,
+    deletions: AtomicU64,
+    expirations: Mutex<BTreeMap<Removal, u64>>,
+    rate_limited: Mutex<BTreeMap<String, u64>>,
+    errors: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    /// Count a file that has been uploaded completely, including members of bundles.
+    pub fn count_upload(&self) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a download that has started.
+    pub fn count_download(&self) {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count files deleted by their uploader or an admin.
+    pub fn count_deletions(&self, count: u64) {
+        self.deletions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count a file that was removed automatically.
+    pub fn count_expiration(&self, reason: Removal) {
+        increment(&self.expirations, reason);
+    }
+
+    /// Count a request that was rejected for exceeding a rate limit.
+    ///
+    /// The route is the path pattern it matched, so that the number of labels stays bounded.
+    pub fn count_rate_limited(&self, route: &str) {
+        increment(&self.rate_limited, route.to_string());
+    }
+
+    /// Count an error response by its status code.
+    pub fn count_error(&self, status_code: StatusCode) {
+        increment(&self.errors, status_code.as_u16());
+    }
+}
+
+fn increment<K: Ord>(map: &Mutex<BTreeMap<K, u64>>, key: K) {
+    // A panic while holding the lock can't leave a counter in an inconsistent state.
+    let mut map = map.lock().unwrap_or_else(|e| e.into_inner());
+    *map.entry(key).or_default() += 1;
+}
+
+/// Endpoint where Prometheus scrapes the metrics, authorized by the configured bearer token.
+///
+/// Only reachable if a metrics_token has been configured.
+pub async fn metrics_endpoint(
+    State(aps): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let conf = aps.conf();
+    let Some(metrics_token) = conf.metrics_token.as_deref() else {
+        return AppError::err(StatusCode::NOT_FOUND, "metrics are disabled");
+    };
+    if !api::bearer_token(&headers).is_some_and(|v| csrf::tokens_match(v, metrics_token)) {
+        return AppError::err(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    // Just like the admin panel's statistics, these cover all files that can still be downloaded.
+    let (file_count, used_quota): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(filesize), 0) FROM uploaded_files WHERE parent_id IS NULL AND julianday(expiry_ts) > julianday('now');",
+    )
+    .fetch_one(&aps.db)
+    .await?;
+    let rate_limiter_entries = aps.rate_limiter.read().await.len();
+    let uploads_in_progress = aps.uploading.read().await.len();
+
+    let m = &*METRICS;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    let single = |v: u64| vec![(String::new(), v)];
+    let labeled = |label: &str, samples: Vec<(String, u64)>| {
+        samples
+            .into_iter()
+            .map(|(k, v)| (format!("{{{label}=\"{k}\"}}"), v))
+            .collect()
+    };
+
+    metric(
+        "ferrishare_uploads_total",
+        "counter",
+        "Files uploaded completely, including members of bundles.",
+        single(m.uploads.load(Ordering::Relaxed)),
+    );
+    metric(
+        "ferrishare_downloads_total",
+        "counter",
+        "Downloads started.",
+        single(m.downloads.load(Ordering::Relaxed)),
+    );
+    metric(
+        "ferrishare_deletions_total",
+        "counter",
+        "Files deleted by their uploader or an administrator.",
+        single(m.deletions.load(Ordering::Relaxed)),
+    );
+    metric(
+        "ferrishare_expirations_total",
+        "counter",
+        "Files removed automatically, by reason.",
+        labeled("reason", snapshot(&m.expirations, |k| k.label().into())),
+    );
+    metric(
+        "ferrishare_rate_limited_requests_total",
+        "counter",
+        "Requests rejected for exceeding a rate limit, by route.",
+        labeled("route", snapshot(&m.rate_limited, |k| escape(k))),
+    );
+    metric(
+        "ferrishare_errors_total",
+        "counter",
+        "Error responses, by status code.",
+        labeled("status", snapshot(&m.errors, |k| k.to_string())),
+    );
+    metric(
+        "ferrishare_files",
+        "gauge",
+        "Files and bundles that can currently be downloaded.",
+        single(file_count as u64),
+    );
+    metric(
+        "ferrishare_used_quota_bytes",
+        "gauge",
+        "Storage used by files that can currently be downloaded.",
+        single(used_quota as u64),
+    );
+    metric(
+        "ferrishare_maximum_quota_bytes",
+        "gauge",
+        "Configured maximum storage quota.",
+        single(conf.maximum_quota),
+    );
+    metric(
+        "ferrishare_rate_limiter_entries",
+        "gauge",
+        "Clients currently tracked by the request rate-limiter.",
+        single(rate_limiter_entries as u64),
+    );
+    metric(
+        "ferrishare_uploads_in_progress",
+        "gauge",
+        "Clients that are streaming a file at this moment.",
+        single(uploads_in_progress as u64),
+    );
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        out,
+    )
+        .into_response())
+}
+
+/// Copy the counters out of a map, rendering the keys as label values.
+fn snapshot<K>(map: &Mutex<BTreeMap<K, u64>>, render: impl Fn(&K) -> String) -> Vec<(String, u64)> {
+    let map = map.lock().unwrap_or_else(|e| e.into_inner());
+    map.iter().map(|(k, v)| (render(k), *v)).collect()
+}
+
+/// Escape a label value as required by the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
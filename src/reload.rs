@@ -0,0 +1,168 @@
+//! Hot reload of the configuration and templates, triggered by SIGHUP or from the admin panel
+//!
+//! The new configuration and templates are only swapped in once both have been loaded and
+//! validated successfully, otherwise the running ones stay in effect. Requests in flight,
+//! including uploads, keep whatever they've already read and aren't interrupted.
+
+use std::path::PathBuf;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::CookieJar;
+use tokio::sync::Mutex;
+
+use crate::accounts::Role;
+use crate::audit::{Actor, AuditEvent};
+use crate::*;
+
+/// Settings that are only read during startup, changing them requires a restart
+///
+/// The listener, logger, storage backend and body limits of the upload routes are all
+/// set up once, so the running values of these settings are kept on reload.
+const RESTART_REQUIRED_SETTINGS: &[&str] =
+    &["interface", "log_level", "maximum_filesize", "storage"];
+
+/// Ensures reloads happen one after another, so that their logs describe consistent changes
+static RELOAD_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Where the configuration was read from during startup
+#[derive(Debug)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// The KEY=VALUE pairs passed with '--set'
+    pub overrides: Vec<String>,
+}
+
+/// Read the configuration and templates again and swap them in if they're valid.
+pub async fn reload(aps: &AppState, actor: Actor<'_>) -> Result<(), anyhow::Error> {
+    let _guard = RELOAD_LOCK.lock().await;
+
+    let source = &aps.config_source;
+    let config_string = tokio::fs::read_to_string(&source.path)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to open {:?}: {e}", source.path))?;
+    let mut conf = config::parse_config(&config_string, &source.overrides)?;
+    conf.validate()?;
+    let tera = load_templates()?;
+
+    let running = aps.conf();
+    let (restart_required, changed): (Vec<_>, Vec<_>) = changed_settings(&running, &conf)?
+        .into_iter()
+        .partition(|(key, _, _)| RESTART_REQUIRED_SETTINGS.contains(&key.as_str()));
+
+    conf.interface = running.interface.clone();
+    conf.log_level = running.log_level.clone();
+    conf.maximum_filesize = running.maximum_filesize;
+    conf.storage = running.storage.clone();
+
+    // Hold both locks at once, so that no request sees the new templates with the old settings.
+    {
+        let mut conf_slot = aps.conf.write().unwrap_or_else(|e| e.into_inner());
+        let mut tera_slot = aps.tera.write().unwrap_or_else(|e| e.into_inner());
+        *conf_slot = Arc::new(conf);
+        *tera_slot = Arc::new(tera);
+    }
+
+    for (key, old, new) in &changed {
+        if config::SECRET_SETTINGS.contains(&key.as_str()) {
+            tracing::info!("changed setting '{key}'");
+        } else {
+            tracing::info!("changed setting '{key}' from {old} to {new}");
+        }
+    }
+    for (key, _, _) in &restart_required {
+        tracing::warn!("setting '{key}' was changed, but only takes effect after a restart");
+    }
+    tracing::info!("reloaded configuration and templates");
+
+    let mut details = match changed.is_empty() {
+        true => "no settings changed".to_string(),
+        false => format!(
+            "changed {}",
+            changed.iter().map(|(key, _, _)| key).join(", ")
+        ),
+    };
+    if !restart_required.is_empty() {
+        details += &format!(
+            ", restart required for {}",
+            restart_required.iter().map(|(key, _, _)| key).join(", ")
+        );
+    }
+    audit::record_or_log(
+        &aps.db,
+        AuditEvent::ConfigReloaded,
+        actor,
+        None,
+        Some(&details),
+    )
+    .await;
+
+    Ok(())
+}
+
+/// List the top-level settings that differ, along with their old and new values.
+///
+/// Settings that aren't set are shown as 'unset'.
+fn changed_settings(
+    old: &AppConfiguration,
+    new: &AppConfiguration,
+) -> Result<Vec<(String, String, String)>, anyhow::Error> {
+    let old = toml::Table::try_from(old)?;
+    let new = toml::Table::try_from(new)?;
+    let render = |v: Option<&toml::Value>| v.map_or_else(|| "unset".into(), |v| v.to_string());
+
+    Ok(old
+        .keys()
+        .chain(new.keys())
+        .unique()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| (key.clone(), render(old.get(key)), render(new.get(key))))
+        .collect())
+}
+
+/// Background task reloading the configuration and templates on every SIGHUP.
+pub async fn reload_on_sighup(aps: AppState) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("failed to install SIGHUP handler: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("received SIGHUP, reloading configuration and templates");
+        if let Err(e) = reload(&aps, Actor::System).await {
+            tracing::error!("failed to reload, keeping the running configuration: {e}");
+        }
+    }
+}
+
+/// Endpoint where owners reload the configuration and templates from the admin panel.
+///
+/// What changed ends up in the audit log, which is shown right on the admin panel.
+pub async fn admin_reload(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+) -> Result<Response, AppError> {
+    let Some(admin) = admin::current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+    if admin.role < Role::Owner {
+        return AppError::err(
+            StatusCode::FORBIDDEN,
+            "your role does not permit reloading the configuration",
+        );
+    }
+
+    if let Err(e) = reload(&aps, (&admin).into()).await {
+        return Err(AppError::new500(format!(
+            "failed to reload, keeping the running configuration: {e}"
+        )));
+    }
+
+    Ok(Redirect::to("/admin").into_response())
+}
@@ -0,0 +1,289 @@
+//! Notice-and-action mechanism letting recipients of a download link report abusive content
+
+use std::str::FromStr;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::accounts::Role;
+use crate::audit::AuditEvent;
+use crate::download::pretty_print_delta;
+use crate::*;
+
+/// Longest description a report may carry, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+/// How long reports are kept after they've been handled
+const RESOLVED_REPORT_RETENTION_DAYS: i64 = 90;
+
+/// What a file is reported for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ReportCategory {
+    IllegalContent,
+    ChildAbuse,
+    Copyright,
+    Malware,
+    Phishing,
+    Other,
+}
+
+impl ReportCategory {
+    /// Human-readable description for the admin panel
+    fn label(&self) -> &'static str {
+        match self {
+            ReportCategory::IllegalContent => "Illegal content",
+            ReportCategory::ChildAbuse => "Child sexual abuse material",
+            ReportCategory::Copyright => "Copyright infringement",
+            ReportCategory::Malware => "Malware",
+            ReportCategory::Phishing => "Phishing or fraud",
+            ReportCategory::Other => "Other",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportRequest {
+    hash: String,
+    category: ReportCategory,
+    description: String,
+}
+
+/// Endpoint where recipients of a download link POST reports about its file.
+///
+/// Reports are rate-limited per IpPrefix, separately from the general request limit.
+pub async fn report_endpoint(
+    State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    Json(req): Json<ReportRequest>,
+) -> Result<StatusCode, AppError> {
+    // Do not entertain hashes with invalid length.
+    if req.hash.len() != 43 {
+        return AppError::err(StatusCode::BAD_REQUEST, "invalid hash length");
+    }
+    let description = req.description.trim();
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            format!("the description must not exceed {MAX_DESCRIPTION_LENGTH} characters"),
+        );
+    }
+
+    // Only files that can still be downloaded can be reported.
+    let expiry_ts: Option<String> = sqlx::query_scalar(
+        "SELECT expiry_ts FROM uploaded_files WHERE efd_sha256sum = ? AND parent_id IS NULL LIMIT 1;",
+    )
+    .bind(&req.hash)
+    .fetch_optional(&aps.db)
+    .await?;
+    if expiry_ts.is_none_or(|v| has_expired(&v).unwrap_or(true)) {
+        return AppError::err(StatusCode::NOT_FOUND, "file not found or expired");
+    }
+
+    let reports_by_eip: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM abuse_reports WHERE reporter_ip = ? AND julianday(report_ts) > julianday('now', '-1 day');",
+    )
+    .bind(eip.to_string())
+    .fetch_one(&aps.db)
+    .await?;
+    if reports_by_eip >= aps.conf().daily_report_limit_per_ip as i64 {
+        return AppError::err(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many reports, come back later",
+        );
+    }
+
+    sqlx::query(
+        "INSERT INTO abuse_reports (efd_sha256sum, category, description, reporter_ip, report_ts) VALUES (?, ?, ?, ?, ?);",
+    )
+    .bind(&req.hash)
+    .bind(req.category)
+    .bind(description)
+    .bind(eip.to_string())
+    .bind(Utc::now().to_rfc3339())
+    .execute(&aps.db)
+    .await?;
+
+    tracing::warn!(
+        efd_sha256sum = req.hash,
+        category = req.category.label(),
+        ip = eip.pretty_print(),
+        "received abuse report"
+    );
+
+    Ok(StatusCode::CREATED)
+}
+
+/// An open report as listed in the admin panel
+#[derive(Debug, Serialize)]
+pub struct OpenReport {
+    id: i64,
+    efd_sha256sum: String,
+    category: &'static str,
+    description: String,
+    reporter_ip_pretty: String,
+    report_ts_pretty: String,
+}
+
+/// Look up all reports that still await a decision, oldest first.
+pub async fn open_reports(aps: &AppState) -> Result<Vec<OpenReport>, AppError> {
+    #[derive(FromRow)]
+    struct ReportRow {
+        id: i64,
+        efd_sha256sum: String,
+        category: ReportCategory,
+        description: String,
+        reporter_ip: String,
+        report_ts: String,
+    }
+
+    let rows: Vec<ReportRow> = sqlx::query_as(
+        "SELECT id, efd_sha256sum, category, description, reporter_ip, report_ts FROM abuse_reports WHERE status = 'open' ORDER BY report_ts;",
+    )
+    .fetch_all(&aps.db)
+    .await?;
+
+    let now = Utc::now();
+    Ok(rows
+        .into_iter()
+        .map(|e| OpenReport {
+            id: e.id,
+            efd_sha256sum: e.efd_sha256sum,
+            category: e.category.label(),
+            description: e.description,
+            reporter_ip_pretty: IpPrefix::from_str(&e.reporter_ip)
+                .map(|v| v.pretty_print())
+                .unwrap_or_else(|_| "(invalid IP)".into()),
+            report_ts_pretty: DateTime::parse_from_rfc3339(&e.report_ts)
+                .map(|v| format!("{} ago", pretty_print_delta(now, v)))
+                .unwrap_or_else(|_| "N/A".into()),
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportDecision {
+    /// The report is unfounded, the file stays
+    Dismiss,
+    /// The report is justified, the file is deleted
+    TakeDown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportResolution {
+    report_id: i64,
+    decision: ReportDecision,
+}
+
+/// Endpoint where admins decide on a report, either dismissing it or taking the file down.
+///
+/// Taking a file down resolves all other open reports about it as well.
+pub async fn admin_report_resolve(
+    State(aps): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<ReportResolution>,
+) -> Result<Response, AppError> {
+    let Some(admin) = admin::current_admin(&aps, &jar).await? else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+    if admin.role < Role::Deleter {
+        return AppError::err(
+            StatusCode::FORBIDDEN,
+            "your role does not permit handling reports",
+        );
+    }
+
+    let efd_sha256sum: Option<String> = sqlx::query_scalar(
+        "SELECT efd_sha256sum FROM abuse_reports WHERE id = ? AND status = 'open';",
+    )
+    .bind(form.report_id)
+    .fetch_optional(&aps.db)
+    .await?;
+    let Some(efd_sha256sum) = efd_sha256sum else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+
+    let now = Utc::now().to_rfc3339();
+    match form.decision {
+        ReportDecision::Dismiss => {
+            sqlx::query(
+                "UPDATE abuse_reports SET status = 'dismissed', resolved_ts = ?, resolved_by = ? WHERE id = ?;",
+            )
+            .bind(&now)
+            .bind(&admin.username)
+            .bind(form.report_id)
+            .execute(&aps.db)
+            .await?;
+
+            tracing::info!(
+                efd_sha256sum,
+                admin = admin.username,
+                "dismissed abuse report"
+            );
+        }
+        ReportDecision::TakeDown => {
+            let mut tx = aps.db.begin().await?;
+            let filedata = delete::delete_rows(&mut tx, &efd_sha256sum).await?;
+            sqlx::query(
+                "UPDATE abuse_reports SET status = 'taken_down', resolved_ts = ?, resolved_by = ? WHERE efd_sha256sum = ? AND status = 'open';",
+            )
+            .bind(&now)
+            .bind(&admin.username)
+            .bind(&efd_sha256sum)
+            .execute(&mut *tx)
+            .await?;
+            if filedata.is_some() {
+                audit::record(
+                    &mut *tx,
+                    AuditEvent::FileDeleted,
+                    (&admin).into(),
+                    Some(&efd_sha256sum),
+                    Some("taken down after abuse report"),
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            if filedata.is_some() {
+                metrics::METRICS.count_deletions(1);
+            }
+
+            // The file may have expired in the meantime, in which case nothing is left to delete.
+            // The rows are gone for good, so a failure to remove the filedata is only logged.
+            for member in filedata.unwrap_or_default() {
+                if let Err(e) = aps.storage.delete(&member).await {
+                    tracing::error!(efd_sha256sum = member, "failed to delete filedata: {e}");
+                }
+            }
+
+            tracing::warn!(
+                efd_sha256sum,
+                admin = admin.username,
+                "took down file after abuse report"
+            );
+        }
+    }
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+/// Remove reports that were handled long enough ago.
+pub async fn cleanup(aps: &AppState) -> Result<(), AppError> {
+    let cutoff = (Utc::now() - TimeDelta::days(RESOLVED_REPORT_RETENTION_DAYS)).to_rfc3339();
+    sqlx::query(
+        "DELETE FROM abuse_reports WHERE status != 'open' AND julianday(resolved_ts) < julianday(?);",
+    )
+    .bind(cutoff)
+    .execute(&aps.db)
+    .await?;
+    Ok(())
+}
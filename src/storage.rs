@@ -0,0 +1,258 @@
+//! Storage backends for the encrypted filedata of uploaded files
+//!
+//! The database only ever refers to filedata by its efd_sha256sum, which is also the name
+//! it is stored under. Uploads are always staged on local disk first, since their name
+//! isn't known until they have been hashed, and are then handed to the configured backend.
+
+use std::{ops::Range, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    buffered::BufWriter,
+    path::Path as ObjectPath,
+    GetOptions, GetRange, ObjectStore, ObjectStoreExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::*;
+
+/// Stream of filedata as returned by [Storage::get]
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Where the encrypted filedata is kept, as configured in the 'storage' table of 'config.toml'
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfiguration {
+    /// Files live in 'uploaded_files/' inside of the DATA_PATH
+    #[default]
+    Filesystem,
+    /// Files live in a bucket of an S3-compatible object storage
+    S3 {
+        bucket: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        /// Custom endpoint for S3-compatible services other than AWS, e.g. 'http://minio:9000'
+        endpoint: Option<String>,
+        /// Credentials are taken from the usual AWS_* environment variables if left out
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+        /// Prefix prepended to all object names, allowing the bucket to be shared
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".into()
+}
+
+impl StorageConfiguration {
+    /// Short name of the backend for log messages, which must not contain any credentials.
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            Self::Filesystem => "filesystem",
+            Self::S3 { .. } => "s3",
+        }
+    }
+}
+
+/// Operations every storage backend has to provide
+///
+/// Names are always efd_sha256sums. Storing filedata under a name that already exists is
+/// never attempted, as the database's uniqueness constraint on efd_sha256sum prevents it.
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Store the data from the stream under the given name.
+    async fn put_stream(&self, name: &str, stream: ByteStream) -> Result<(), anyhow::Error>;
+
+    /// Store the staged file at `staged_path` under the given name.
+    ///
+    /// The staged file is consumed in the process. Once the data has been stored, failing to
+    /// remove it doesn't fail the upload, since the stored data would be orphaned otherwise.
+    /// Such leftovers are removed on the next startup instead.
+    async fn put_file(&self, name: &str, staged_path: &Path) -> Result<(), anyhow::Error> {
+        let file = tokio::fs::File::open(staged_path).await?;
+        self.put_stream(name, ReaderStream::new(file).boxed())
+            .await?;
+        if let Err(e) = tokio::fs::remove_file(staged_path).await {
+            tracing::error!("failed to remove staged file {staged_path:?}: {e}");
+        }
+        Ok(())
+    }
+
+    /// Stream the data stored under the given name, optionally restricted to a range of bytes.
+    ///
+    /// Once the stream has been returned, it stays readable even if the data is deleted.
+    async fn get(&self, name: &str, range: Option<Range<u64>>)
+        -> Result<ByteStream, anyhow::Error>;
+
+    /// Delete the data stored under the given name. Deleting nonexistent data is not an error.
+    async fn delete(&self, name: &str) -> Result<(), anyhow::Error>;
+
+    /// The size of the data stored under the given name, or None if it doesn't exist.
+    async fn size(&self, name: &str) -> Result<Option<u64>, anyhow::Error>;
+
+    /// Check whether any data is stored under the given name.
+    async fn exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.size(name).await?.is_some())
+    }
+}
+
+/// Create the storage backend described by the configuration.
+pub fn from_config(conf: &StorageConfiguration) -> Result<Arc<dyn Storage>, anyhow::Error> {
+    Ok(match conf {
+        StorageConfiguration::Filesystem => Arc::new(FilesystemStorage {
+            directory: format!("{DATA_PATH}/uploaded_files").into(),
+        }),
+        StorageConfiguration::S3 {
+            bucket,
+            region,
+            endpoint,
+            access_key_id,
+            secret_access_key,
+            prefix,
+        } => {
+            let mut builder = AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .with_region(region);
+            if let Some(endpoint) = endpoint {
+                // Self-hosted services are commonly reached through plain HTTP on a private network.
+                builder = builder
+                    .with_allow_http(endpoint.starts_with("http://"))
+                    .with_endpoint(endpoint);
+            }
+            if let Some(access_key_id) = access_key_id {
+                builder = builder.with_access_key_id(access_key_id);
+            }
+            if let Some(secret_access_key) = secret_access_key {
+                builder = builder.with_secret_access_key(secret_access_key);
+            }
+            Arc::new(S3Storage {
+                store: Arc::new(builder.build()?),
+                prefix: ObjectPath::from(prefix.as_str()),
+            })
+        }
+    })
+}
+
+/// Storage backend keeping every file in a single local directory
+#[derive(Debug)]
+pub struct FilesystemStorage {
+    directory: std::path::PathBuf,
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn put_stream(&self, name: &str, mut stream: ByteStream) -> Result<(), anyhow::Error> {
+        let mut file = tokio::fs::File::create_new(self.directory.join(name)).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Staged files live on the same filesystem, so they can simply be moved into place.
+    async fn put_file(&self, name: &str, staged_path: &Path) -> Result<(), anyhow::Error> {
+        tokio::fs::rename(staged_path, self.directory.join(name)).await?;
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        name: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ByteStream, anyhow::Error> {
+        let mut file = tokio::fs::File::open(self.directory.join(name)).await?;
+        match range {
+            Some(range) => {
+                file.seek(std::io::SeekFrom::Start(range.start)).await?;
+                Ok(ReaderStream::new(file.take(range.end - range.start)).boxed())
+            }
+            None => Ok(ReaderStream::new(file).boxed()),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), anyhow::Error> {
+        match tokio::fs::remove_file(self.directory.join(name)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn size(&self, name: &str) -> Result<Option<u64>, anyhow::Error> {
+        match tokio::fs::metadata(self.directory.join(name)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Storage backend keeping every file as an object in an S3-compatible bucket
+#[derive(Debug)]
+pub struct S3Storage {
+    store: Arc<AmazonS3>,
+    prefix: ObjectPath,
+}
+
+impl S3Storage {
+    fn path(&self, name: &str) -> ObjectPath {
+        self.prefix.clone().join(name)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put_stream(&self, name: &str, mut stream: ByteStream) -> Result<(), anyhow::Error> {
+        // Larger files are transparently uploaded in several parts.
+        let mut writer = BufWriter::new(self.store.clone(), self.path(name));
+        let result = async {
+            while let Some(chunk) = stream.try_next().await? {
+                writer.write_all(&chunk).await?;
+            }
+            writer.shutdown().await
+        }
+        .await;
+
+        // Don't leave the parts of an incomplete upload lying around in the bucket.
+        if let Err(e) = result {
+            writer.abort().await?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        name: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ByteStream, anyhow::Error> {
+        let options = GetOptions {
+            range: range.map(GetRange::Bounded),
+            ..Default::default()
+        };
+        let result = self.store.get_opts(&self.path(name), options).await?;
+        Ok(result.into_stream().map_err(std::io::Error::from).boxed())
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), anyhow::Error> {
+        match self.store.delete(&self.path(name)).await {
+            Err(e) if !matches!(e, object_store::Error::NotFound { .. }) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn size(&self, name: &str) -> Result<Option<u64>, anyhow::Error> {
+        match self.store.head(&self.path(name)).await {
+            Ok(meta) => Ok(Some(meta.size)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Time-based one-time passwords (RFC 6238) as an optional second factor for admin logins
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::{
+    render::{svg, unicode},
+    QrCode,
+};
+use rand::{prelude::*, rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+use url::Url;
+
+/// Length of a single time-step in seconds
+const STEP_SECONDS: i64 = 30;
+
+/// Number of digits every code consists of
+const DIGITS: u32 = 6;
+
+/// How many time-steps a code may lie in the past or future to make up for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+
+/// Number of recovery codes that are handed out when enabling TOTP
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generate a new random 160-bit secret, the size recommended by RFC 4226.
+pub fn generate_secret() -> Vec<u8> {
+    rng().random::<[u8; 20]>().to_vec()
+}
+
+/// Encode a secret the way authenticator apps expect it for manual entry.
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// Build the otpauth-URI authenticator apps import the secret from, usually as a QR code.
+pub fn otpauth_uri(issuer: &str, username: &str, secret: &[u8]) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("static URL is valid");
+    uri.set_path(&format!("{issuer}:{username}"));
+    uri.query_pairs_mut()
+        .append_pair("secret", &encode_secret(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    uri.to_string()
+}
+
+/// Render an otpauth-URI as a QR code in SVG format, ready to be inlined into HTML.
+pub fn qr_svg(uri: &str) -> Result<String, anyhow::Error> {
+    let svg = QrCode::new(uri.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    // Drop the XML declaration, which has no place inside of an HTML document.
+    Ok(svg[svg.find("<svg").ok_or_else(|| anyhow!("invalid SVG"))?..].to_string())
+}
+
+/// Render an otpauth-URI as a QR code that can be printed to a terminal.
+pub fn qr_terminal(uri: &str) -> Result<String, anyhow::Error> {
+    Ok(QrCode::new(uri.as_bytes())?
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// Calculate the code of the given time-step (RFC 4226, section 5.3).
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation: The last nibble picks which four bytes make up the code.
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Check a code against the secret, returning the time-step it belongs to if it is valid.
+///
+/// Only time-steps after last_step are considered, so that no code can be used twice.
+pub fn verify(secret: &[u8], code: &str, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let now = Utc::now().timestamp() / STEP_SECONDS;
+    (now - ALLOWED_DRIFT..=now + ALLOWED_DRIFT)
+        .filter(|step| last_step.is_none_or(|v| *step > v))
+        .find(|step| code_at(secret, *step) == code)
+}
+
+/// Generate a fresh set of recovery codes, formatted like 'abcde-fghij'.
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = BASE32_NOPAD
+                .encode(&rng().random::<[u8; 7]>())
+                .to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect()
+}
+
+/// Calculate the base64url-encoded sha256sum a recovery code is stored as.
+///
+/// Case and separators don't matter, so that the codes are forgiving to type.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    URL_SAFE_NO_PAD.encode(Sha256::digest(normalized))
+}
+
+/// Enable TOTP for an admin, replacing any previous secret and recovery codes.
+///
+/// Returns the new recovery codes, which are only ever shown this once.
+pub async fn enable(
+    db: &SqlitePool,
+    admin_id: i64,
+    secret: &[u8],
+    last_step: Option<i64>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let recovery_codes = generate_recovery_codes();
+
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "UPDATE admins SET totp_secret = ?, totp_pending_secret = NULL, totp_last_step = ? WHERE id = ?;",
+    )
+    .bind(secret)
+    .bind(last_step)
+    .bind(admin_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM admin_recovery_codes WHERE admin_id = ?;")
+        .bind(admin_id)
+        .execute(&mut *tx)
+        .await?;
+    for code in &recovery_codes {
+        sqlx::query("INSERT INTO admin_recovery_codes (admin_id, code_sha256sum) VALUES (?, ?);")
+            .bind(admin_id)
+            .bind(hash_recovery_code(code))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(recovery_codes)
+}
+
+/// Disable TOTP for an admin, removing their secret and recovery codes.
+pub async fn disable(db: &SqlitePool, admin_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "UPDATE admins SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL WHERE id = ?;",
+    )
+    .bind(admin_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM admin_recovery_codes WHERE admin_id = ?;")
+        .bind(admin_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+/// Check the second factor of an admin, either a current code or one of their recovery codes.
+///
+/// Successfully used codes are consumed and will not be accepted again.
+pub async fn check_second_factor(
+    db: &SqlitePool,
+    admin_id: i64,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    #[derive(FromRow)]
+    struct TotpRow {
+        totp_secret: Option<Vec<u8>>,
+        totp_last_step: Option<i64>,
+    }
+
+    let row: Option<TotpRow> =
+        sqlx::query_as("SELECT totp_secret, totp_last_step FROM admins WHERE id = ?;")
+            .bind(admin_id)
+            .fetch_optional(db)
+            .await?;
+    let Some(TotpRow {
+        totp_secret: Some(secret),
+        totp_last_step,
+    }) = row
+    else {
+        return Ok(false);
+    };
+
+    if let Some(step) = verify(&secret, code, totp_last_step) {
+        // Only one of several concurrent logins with the same code may succeed.
+        let result = sqlx::query(
+            "UPDATE admins SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?);",
+        )
+        .bind(step)
+        .bind(admin_id)
+        .bind(step)
+        .execute(db)
+        .await?;
+        return Ok(result.rows_affected() == 1);
+    }
+
+    // Not a valid code? Maybe it's one of the recovery codes, then.
+    let result =
+        sqlx::query("DELETE FROM admin_recovery_codes WHERE admin_id = ? AND code_sha256sum = ?;")
+            .bind(admin_id)
+            .bind(hash_recovery_code(code))
+            .execute(db)
+            .await?;
+    Ok(result.rows_affected() == 1)
+}
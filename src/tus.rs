@@ -0,0 +1,485 @@
+//! Resumable uploads implementing the tus protocol (core, creation and termination)
+//!
+//! The protocol is specified at <https://tus.io/protocols/resumable-upload>.
+//! Partially uploaded files are staged in 'partial_uploads/' and tracked in the
+//! 'upload_sessions' table until they are complete, at which point they are
+//! hashed and moved to 'uploaded_files/' just like any other upload.
+
+use std::str::FromStr;
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use chrono::{prelude::Utc, SubsecRound, TimeDelta};
+use futures_util::StreamExt;
+use rand::{prelude::*, rng};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::upload::{self, StoreAs, UploadMetadata, UploadMetadataBuilder};
+use crate::*;
+
+/// The only version of the tus protocol supported by this server
+const TUS_VERSION: &str = "1.0.0";
+
+/// How long an upload session may sit idle before it is considered abandoned
+///
+/// Every PATCH request pushes the session's expiry back by this amount.
+const SESSION_IDLE_HOURS: i64 = 24;
+
+/// Path of the file a session's partial upload is staged in.
+pub fn partial_upload_path(upload_id_sha256sum: &str) -> String {
+    format!("{DATA_PATH}/partial_uploads/{upload_id_sha256sum}")
+}
+
+/// Read a header as a string, treating non-ASCII values as absent.
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Reject requests from clients speaking a version of tus other than ours.
+fn check_tus_resumable(headers: &HeaderMap) -> Result<(), AppError> {
+    if header_str(headers, "Tus-Resumable") != Some(TUS_VERSION) {
+        return AppError::err(
+            StatusCode::PRECONDITION_FAILED,
+            format!("only version {TUS_VERSION} of the tus protocol is supported"),
+        );
+    }
+    Ok(())
+}
+
+/// Build an empty response with the given StatusCode and the mandatory Tus-Resumable header.
+fn tus_response(status_code: StatusCode) -> Response {
+    let mut response = status_code.into_response();
+    response
+        .headers_mut()
+        .insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("no-store"));
+    response
+}
+
+/// Parse the Upload-Metadata header into validated UploadMetadata.
+///
+/// The header consists of comma-separated key-value pairs,
+/// where the key is separated from its base64-encoded value by a single space.
+fn parse_upload_metadata(upload_metadata: &str) -> Result<UploadMetadata, AppError> {
+    let mut metadata = UploadMetadataBuilder::default();
+    for pair in upload_metadata.split(',').map(str::trim) {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = STANDARD.decode(value).map_err(|_| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Upload-Metadata value for {key} is not base64-encoded"),
+            )
+        })?;
+        metadata.set(key, &value)?;
+    }
+    metadata.build()
+}
+
+/// Split the bundle membership off of the Upload-Metadata header.
+///
+/// Clients add a file to a bundle by passing the bundle's identifier and admin key as
+/// "bundle" and "bundle_admin". Neither is part of the file's metadata, and the admin key
+/// must not end up in the database, so they're returned separately from the remaining header.
+fn split_bundle_metadata(
+    upload_metadata: &str,
+) -> Result<(String, Option<(String, String)>), AppError> {
+    let mut bundle = None;
+    let mut bundle_admin = None;
+    let mut remaining = Vec::new();
+    for pair in upload_metadata.split(',').map(str::trim) {
+        let target = match pair.split_once(' ').map_or(pair, |(key, _)| key) {
+            "bundle" => &mut bundle,
+            "bundle_admin" => &mut bundle_admin,
+            _ => {
+                remaining.push(pair);
+                continue;
+            }
+        };
+        let value = pair.split_once(' ').map_or("", |(_, value)| value);
+        *target = STANDARD
+            .decode(value)
+            .ok()
+            .and_then(|v| String::from_utf8(v).ok());
+    }
+
+    let membership = match (bundle, bundle_admin) {
+        (Some(bundle), Some(bundle_admin)) => Some((bundle, bundle_admin)),
+        (None, None) => None,
+        _ => {
+            return AppError::err(
+                StatusCode::BAD_REQUEST,
+                "bundle and bundle_admin must be provided together",
+            )
+        }
+    };
+
+    Ok((remaining.join(","), membership))
+}
+
+#[derive(Debug, FromRow)]
+struct SessionRow {
+    upload_id_sha256sum: String,
+    admin_key_sha256sum: Option<String>,
+    upload_metadata: String,
+    upload_length: i64,
+    upload_ip: String,
+    expiry_ts: String,
+    efd_sha256sum: Option<String>,
+    parent_id: Option<i64>,
+}
+
+/// Look up the upload session belonging to the given upload id.
+///
+/// Sessions that have expired but were not yet cleaned up are treated as nonexistent.
+async fn find_session(aps: &AppState, upload_id: &str) -> Result<SessionRow, AppError> {
+    // Like admin sessions, upload ids are only stored as their sha256-digest.
+    let upload_id_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(
+        URL_SAFE_NO_PAD
+            .decode(upload_id)
+            .ok()
+            .filter(|v| v.len() == 32)
+            .unwrap_or_default(),
+    ));
+
+    let row: Option<SessionRow> = sqlx::query_as("SELECT upload_id_sha256sum, admin_key_sha256sum, upload_metadata, upload_length, upload_ip, expiry_ts, efd_sha256sum, parent_id FROM upload_sessions WHERE upload_id_sha256sum = ? LIMIT 1;")
+        .bind(&upload_id_sha256sum)
+        .fetch_optional(&aps.db)
+        .await?;
+
+    match row {
+        Some(row) if !has_expired(&row.expiry_ts)? => Ok(row),
+        _ => AppError::err(StatusCode::NOT_FOUND, "upload not found or expired"),
+    }
+}
+
+/// Determine how many bytes of a session have been received so far.
+///
+/// The size of the staged file on disk is the single source of truth here.
+/// If a PATCH request gets interrupted, everything written up to that point is kept.
+async fn current_offset(session: &SessionRow) -> Result<u64, AppError> {
+    if session.efd_sha256sum.is_some() {
+        return Ok(session.upload_length as u64);
+    }
+    Ok(
+        tokio::fs::metadata(partial_upload_path(&session.upload_id_sha256sum))
+            .await
+            .map_err(|e| AppError::new500(format!("failed to read partial upload: {e}")))?
+            .len(),
+    )
+}
+
+/// Endpoint allowing clients to discover the server's tus capabilities
+pub async fn tus_options(State(aps): State<AppState>) -> Response {
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    let headers = response.headers_mut();
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert(
+        "Tus-Extension",
+        HeaderValue::from_static("creation,termination"),
+    );
+    headers.insert(
+        "Tus-Max-Size",
+        HeaderValue::from(aps.conf().maximum_filesize),
+    );
+    response
+}
+
+/// Endpoint where clients create a new upload session (tus creation extension)
+///
+/// Apart from the Location of the new session, the response also contains the file's admin key.
+/// Handing it out right away ensures clients don't lose it if the connection drops at the end.
+/// Files destined for a bundle are managed through the bundle's admin key instead.
+pub async fn tus_create(
+    State(aps): State<AppState>,
+    ExtractIpPrefix(eip): ExtractIpPrefix,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    check_tus_resumable(&headers)?;
+
+    // Deferring the length is not supported, the client has to know it from the start.
+    let upload_length = header_str(&headers, "Upload-Length")
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| {
+            AppError::new(StatusCode::BAD_REQUEST, "missing or invalid Upload-Length")
+        })?;
+
+    if upload_length > aps.conf().maximum_filesize {
+        return AppError::err(StatusCode::PAYLOAD_TOO_LARGE, "encrypted file is too large");
+    }
+
+    // Validate the metadata now, so that clients don't upload a file only to have it rejected.
+    let (upload_metadata, membership) =
+        split_bundle_metadata(header_str(&headers, "Upload-Metadata").unwrap_or_default())?;
+    let metadata = parse_upload_metadata(&upload_metadata)?;
+    metadata.check_filesize(upload_length)?;
+    metadata.expiry.check(&aps.conf())?;
+
+    // Sessions count towards the upload limit and the quota right away.
+    // Members of a bundle are only limited by the space remaining in their bundle.
+    let bundle = match membership {
+        Some((bundle, bundle_admin)) => {
+            let bundle = bundle::authorize_bundle(&aps, &bundle, &bundle_admin).await?;
+            bundle::check_bundle_capacity(&aps, &bundle, upload_length).await?;
+            Some(bundle)
+        }
+        None => {
+            upload::check_upload_limits(&aps, &eip).await?;
+            None
+        }
+    };
+
+    // The upload id doubles as the secret granting access to the session.
+    let upload_id_bytes = rng().random::<[u8; 32]>();
+    let upload_id = URL_SAFE_NO_PAD.encode(upload_id_bytes);
+    let upload_id_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(upload_id_bytes));
+
+    let admin_key = bundle.is_none().then(upload::generate_admin_key);
+
+    let now = Utc::now().round_subsecs(0);
+    let created_ts = now.to_rfc3339();
+    let expiry_ts = now
+        .checked_add_signed(TimeDelta::hours(SESSION_IDLE_HOURS))
+        .ok_or_else(|| AppError::new500("failed to apply duration to current timestamp"))?
+        .to_rfc3339();
+
+    // Create the empty file the upload will be staged in.
+    tokio::fs::File::create(partial_upload_path(&upload_id_sha256sum))
+        .await
+        .map_err(|e| AppError::new500(format!("failed to create file on disk: {e}")))?;
+
+    sqlx::query("INSERT INTO upload_sessions (upload_id_sha256sum, admin_key_sha256sum, upload_metadata, upload_length, upload_ip, created_ts, expiry_ts, parent_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?);")
+        .bind(&upload_id_sha256sum)
+        .bind(admin_key.as_ref().map(|(_, v)| v))
+        .bind(&upload_metadata)
+        .bind(upload_length as i64)
+        .bind(eip.to_string())
+        .bind(&created_ts)
+        .bind(&expiry_ts)
+        .bind(bundle.as_ref().map(|v| v.id))
+        .execute(&aps.db)
+        .await?;
+
+    tracing::info!(upload_length, "created new upload session");
+
+    let mut response = tus_response(StatusCode::CREATED);
+    let headers = response.headers_mut();
+    headers.insert("Location", format!("/tus/{upload_id}").parse()?);
+    if let Some((admin_key, _)) = admin_key {
+        headers.insert("FerriShare-Admin-Key", admin_key.parse()?);
+    }
+    Ok(response)
+}
+
+/// Endpoint where clients query how much of their upload the server has received
+///
+/// Completed uploads additionally report the resulting file's hash.
+pub async fn tus_head(
+    State(aps): State<AppState>,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    check_tus_resumable(&headers)?;
+    let session = find_session(&aps, &upload_id).await?;
+    let offset = current_offset(&session).await?;
+
+    let mut response = tus_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert("Upload-Offset", HeaderValue::from(offset));
+    headers.insert(
+        "Upload-Length",
+        HeaderValue::from(session.upload_length as u64),
+    );
+    if let Some(efd_sha256sum) = session.efd_sha256sum {
+        headers.insert("FerriShare-Efd-Sha256sum", efd_sha256sum.parse()?);
+    }
+    Ok(response)
+}
+
+/// Endpoint where clients append data to their upload
+///
+/// Once the upload is complete the file is hashed and stored, and its hash is returned.
+pub async fn tus_patch(
+    State(aps): State<AppState>,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    check_tus_resumable(&headers)?;
+
+    if header_str(&headers, "Content-Type") != Some("application/offset+octet-stream") {
+        return AppError::err(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream",
+        );
+    }
+
+    let session = find_session(&aps, &upload_id).await?;
+    let upload_length = session.upload_length as u64;
+    let mut offset = current_offset(&session).await?;
+
+    // The client must continue exactly where the server left off.
+    if header_str(&headers, "Upload-Offset").and_then(|v| v.parse::<u64>().ok()) != Some(offset) {
+        return AppError::err(StatusCode::CONFLICT, "Upload-Offset does not match");
+    }
+
+    if session.efd_sha256sum.is_none() {
+        // Push back the session's expiry, as it is evidently still in use.
+        let expiry_ts = Utc::now()
+            .round_subsecs(0)
+            .checked_add_signed(TimeDelta::hours(SESSION_IDLE_HOURS))
+            .ok_or_else(|| AppError::new500("failed to apply duration to current timestamp"))?
+            .to_rfc3339();
+        sqlx::query("UPDATE upload_sessions SET expiry_ts = ? WHERE upload_id_sha256sum = ?;")
+            .bind(&expiry_ts)
+            .bind(&session.upload_id_sha256sum)
+            .execute(&aps.db)
+            .await?;
+
+        // Append the request body to the staged file.
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(partial_upload_path(&session.upload_id_sha256sum))
+            .await
+            .map_err(|e| AppError::new500(format!("failed to open partial upload: {e}")))?;
+
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|_| {
+                AppError::new(StatusCode::BAD_REQUEST, "failed to read request body")
+            })?;
+            if offset + chunk.len() as u64 > upload_length {
+                return AppError::err(
+                    StatusCode::BAD_REQUEST,
+                    "request body exceeds the declared Upload-Length",
+                );
+            }
+            file.write_all(&chunk).await.map_err(|e| {
+                AppError::new500(format!("failed to write encrypted filedata to disk: {e}"))
+            })?;
+            offset += chunk.len() as u64;
+        }
+
+        file.flush()
+            .await
+            .map_err(|e| AppError::new500(format!("failed to flush encrypted filedata: {e}")))?;
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response
+        .headers_mut()
+        .insert("Upload-Offset", HeaderValue::from(offset));
+
+    // All data has been received, so the upload can be turned into a proper file.
+    if offset == upload_length {
+        let efd_sha256sum = match session.efd_sha256sum {
+            Some(ref v) => v.clone(),
+            None => finalize_session(&aps, &session).await?,
+        };
+        response
+            .headers_mut()
+            .insert("FerriShare-Efd-Sha256sum", efd_sha256sum.parse()?);
+    }
+
+    Ok(response)
+}
+
+/// Hash a completed upload and store it in the database and 'uploaded_files/'.
+async fn finalize_session(aps: &AppState, session: &SessionRow) -> Result<String, AppError> {
+    let path = partial_upload_path(&session.upload_id_sha256sum);
+
+    // Unlike the upload_endpoint we cannot hash incrementally, since the data may have
+    // arrived across several requests. Instead, the staged file is read back once.
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| AppError::new500(format!("failed to open partial upload: {e}")))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .await
+            .map_err(|e| AppError::new500(format!("failed to read partial upload: {e}")))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    let efd_sha256sum = URL_SAFE_NO_PAD.encode(hasher.finalize());
+
+    let metadata = parse_upload_metadata(&session.upload_metadata)?;
+    let eip = IpPrefix::from_str(&session.upload_ip)
+        .map_err(|_| AppError::new500("upload session has an invalid upload_ip"))?;
+
+    // Look up the bundle again, it may have been deleted or expired in the meantime.
+    let bundle = match session.parent_id {
+        Some(parent_id) => Some(bundle::find_bundle(aps, parent_id).await?),
+        None => None,
+    };
+    let store_as = match (&bundle, &session.admin_key_sha256sum) {
+        (Some(bundle), _) => StoreAs::BundleMember(bundle),
+        (None, Some(admin_key_sha256sum)) => StoreAs::File {
+            admin_key_sha256sum,
+        },
+        (None, None) => return Err(AppError::new500("upload session has no admin key")),
+    };
+
+    upload::store_upload(
+        aps,
+        &eip,
+        &metadata,
+        std::path::Path::new(&path),
+        &efd_sha256sum,
+        store_as,
+        session.upload_length as u64,
+        // Remember the hash, in case the client has to ask for it again.
+        Some(&session.upload_id_sha256sum),
+    )
+    .await?;
+
+    Ok(efd_sha256sum)
+}
+
+/// Endpoint where clients cancel an upload (tus termination extension)
+///
+/// Terminating a completed upload only forgets the session, the file itself remains.
+pub async fn tus_delete(
+    State(aps): State<AppState>,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    check_tus_resumable(&headers)?;
+    let session = find_session(&aps, &upload_id).await?;
+    cleanup_session(&session.upload_id_sha256sum, &aps.db).await?;
+    tracing::info!("upload session terminated by client");
+    Ok(tus_response(StatusCode::NO_CONTENT))
+}
+
+/// Remove a single upload session and its staged data from the database and disk.
+pub async fn cleanup_session(
+    upload_id_sha256sum: &str,
+    db: &SqlitePool,
+) -> Result<(), anyhow::Error> {
+    // First, remove the corresponding row form the DB.
+    sqlx::query("DELETE FROM upload_sessions WHERE upload_id_sha256sum = ?;")
+        .bind(upload_id_sha256sum)
+        .execute(db)
+        .await?;
+
+    // Next, remove the staged data from disk.
+    // Completed sessions no longer have any, as it was moved to 'uploaded_files/'.
+    match tokio::fs::remove_file(partial_upload_path(upload_id_sha256sum)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
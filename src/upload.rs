@@ -1,19 +1,30 @@
 //! Page and endpoint for uploading new files to the service
 
 use axum::{
+    body::Bytes,
     extract::{multipart::MultipartRejection, Multipart, Request, State},
     http::StatusCode,
     response::Html,
     Json,
 };
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
-use chrono::{prelude::Utc, SubsecRound, TimeDelta};
+use chrono::{
+    prelude::{DateTime, Utc},
+    SubsecRound, TimeDelta,
+};
 use minify_html::minify;
 use rand::{prelude::*, rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
 
+use crate::audit::{Actor, AuditEvent};
+use crate::bundle::{self, Bundle};
 use crate::*;
 
 /// Handler that serves the page where users can upload new files.
@@ -21,14 +32,24 @@ pub async fn upload_page(State(aps): State<AppState>) -> Result<Html<String>, Ap
     let mut context = aps.default_context();
     // Check if the server has hit its quota limit and serve the appropriate template.
     let html = if maximum_quota_reached(&aps).await? {
-        aps.tera.render("full_quota.html", &context)?
+        aps.tera().render("full_quota.html", &context)?
     } else {
+        let conf = aps.conf();
+        context.insert("max_filesize", &pretty_print_bytes(conf.maximum_filesize));
+        context.insert("raw_max_filesize", &conf.maximum_filesize);
+
+        context.insert("expiry_options", &conf.labeled_expiry_options());
+        context.insert("default_expiry", &conf.default_expiry);
+        context.insert("allow_custom_expiry", &conf.allow_custom_expiry);
         context.insert(
-            "max_filesize",
-            &pretty_print_bytes(aps.conf.maximum_filesize),
+            "maximum_expiry",
+            &config::format_duration_input(&conf.maximum_expiry),
         );
-        context.insert("raw_max_filesize", &aps.conf.maximum_filesize);
-        aps.tera.render("upload.html", &context)?
+        context.insert(
+            "raw_maximum_expiry",
+            &conf.maximum_expiry_duration().num_seconds(),
+        );
+        aps.tera().render("upload.html", &context)?
     };
     let response_body = String::from_utf8(minify(html.as_bytes(), &MINIFY_CFG))?;
     Ok(Html(response_body))
@@ -66,6 +87,18 @@ pub async fn upload_endpoint(
     ExtractIpPrefix(eip): ExtractIpPrefix,
     multipart: Result<Multipart, MultipartRejection>,
 ) -> Result<(StatusCode, Json<UploadFileResponse>), AppError> {
+    let response = receive_upload(&aps, &eip, multipart).await?;
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Receive the multipart form of an upload and store the file(s) it contains.
+///
+/// Shared between the upload_endpoint and the REST API, which accept the very same form.
+pub async fn receive_upload(
+    aps: &AppState,
+    eip: &IpPrefix,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<UploadFileResponse, AppError> {
     // Handle bad multipart form data in here.
     // If something went wrong parsing it, blame the client.
     let mut multipart = multipart.map_err(|_| {
@@ -75,34 +108,58 @@ pub async fn upload_endpoint(
         )
     })?;
 
-    // Find out how many files this user has already uploaded.
-    let uploads_by_eip: i64 =
-        sqlx::query_scalar("SELECT COUNT(*) FROM uploaded_files WHERE upload_ip = ?;")
-            .bind(eip.to_string())
-            .fetch_one(&aps.db)
-            .await?;
+    // Check if the user or the server have hit their limits.
+    check_upload_limits(aps, eip).await?;
 
-    // Check if the user has hit their upload limit.
-    if uploads_by_eip as u64 >= aps.conf.maximum_uploads_per_ip {
-        return AppError::err(StatusCode::TOO_MANY_REQUESTS, "your computer has reached the file upload limit; delete old files or wait for them to expire");
-    }
+    // Several files may be uploaded at once, in which case they're stored as a bundle.
+    // The n-th occurrence of a per-file field belongs to the n-th file,
+    // whereas the expiry, format_version, max_downloads and passphrase apply to all of them.
+    let mut members: Vec<UploadMetadataBuilder> = Vec::new();
+    let mut member_field_counts: HashMap<String, usize> = HashMap::new();
+    let mut shared_fields: Vec<(String, Bytes)> = Vec::new();
+    let mut e_filedata: Vec<(StagedFile, Sha256, u64)> = Vec::new();
+    let mut total_filesize: u64 = 0;
+    let maximum_filesize = aps.conf().maximum_filesize;
 
-    // Check if the server has hit its quota limits.
-    if maximum_quota_reached(&aps).await? {
-        return AppError::err(
-            StatusCode::INSUFFICIENT_STORAGE,
-            "server has reached maximum storage capacity; please try again later",
-        );
-    }
+    while let Some(mut field) = multipart.next_field().await? {
+        let field_name = field.name().map_or(String::new(), |e| e.to_string());
 
-    let mut e_filename: Option<Vec<u8>> = None;
-    let mut e_filedata: Option<Vec<u8>> = None;
-    let mut iv_fd: Option<[u8; 12]> = None;
-    let mut iv_fn: Option<[u8; 12]> = None;
-    let mut hour_duration: Option<i64> = None;
+        // The encrypted filedata is streamed chunk-by-chunk straight to disk.
+        // All other fields are tiny and can simply be buffered in memory.
+        if field_name == "e_filedata" {
+            if e_filedata.len() >= bundle::MAX_BUNDLE_MEMBERS {
+                return AppError::err(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "bundles may contain at most {} files",
+                        bundle::MAX_BUNDLE_MEMBERS
+                    ),
+                );
+            }
+            let mut staged = StagedFile::create().await?;
+            let mut hasher = Sha256::new();
+            let mut filesize: u64 = 0;
+            while let Some(chunk) = field.chunk().await.map_err(|_| {
+                AppError::new(
+                    StatusCode::BAD_REQUEST,
+                    "failed to extract form data for field e_filedata; is your file too large?",
+                )
+            })? {
+                filesize += chunk.len() as u64;
+                total_filesize += chunk.len() as u64;
+                // The maximum filesize applies to bundles as a whole.
+                if total_filesize > maximum_filesize {
+                    return AppError::err(StatusCode::BAD_REQUEST, "encrypted file is too large");
+                }
+                hasher.update(&chunk);
+                staged.file.write_all(&chunk).await.map_err(|e| {
+                    AppError::new500(format!("failed to write encrypted filedata to disk: {e}"))
+                })?;
+            }
+            e_filedata.push((staged, hasher, filesize));
+            continue;
+        }
 
-    while let Some(field) = multipart.next_field().await? {
-        let field_name = field.name().map_or(String::new(), |e| e.to_string());
         let field_data = field.bytes().await.map_err(|_| {
             AppError::new(
                 StatusCode::BAD_REQUEST,
@@ -112,7 +169,201 @@ pub async fn upload_endpoint(
             )
         })?;
 
-        match field_name.as_str() {
+        if UploadMetadataBuilder::is_shared_field(&field_name) {
+            shared_fields.push((field_name, field_data));
+            continue;
+        }
+
+        let index = member_field_counts.entry(field_name.clone()).or_default();
+        if *index >= bundle::MAX_BUNDLE_MEMBERS {
+            return AppError::err(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "bundles may contain at most {} files",
+                    bundle::MAX_BUNDLE_MEMBERS
+                ),
+            );
+        }
+        if members.len() <= *index {
+            members.push(UploadMetadataBuilder::default());
+        }
+        members[*index].set(&field_name, &field_data)?;
+        *index += 1;
+    }
+
+    if e_filedata.is_empty() {
+        return AppError::err(StatusCode::BAD_REQUEST, "no encrypted filedata provided");
+    }
+    if members.len() != e_filedata.len() {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            "number of encrypted files does not match the metadata provided",
+        );
+    }
+
+    // Validate each file's metadata and finalize the sha256sums of the encrypted data,
+    // computed incrementally while streaming. Likelihood of collision is ridiculously small,
+    // so we can ignore it here. We'll use their base64url-encoding as the URL to identify files.
+    let mut files = Vec::with_capacity(members.len());
+    for (mut member, (mut staged, hasher, filesize)) in members.into_iter().zip(e_filedata) {
+        for (field_name, field_data) in &shared_fields {
+            member.set(field_name, field_data)?;
+        }
+        let metadata = member.build()?;
+        metadata.check_filesize(filesize)?;
+        metadata.expiry.check(&aps.conf())?;
+        let efd_sha256sum = URL_SAFE_NO_PAD.encode(hasher.finalize());
+
+        // Ensure everything we've streamed so far has been handed to the OS.
+        staged
+            .file
+            .flush()
+            .await
+            .map_err(|e| AppError::new500(format!("failed to flush encrypted filedata: {e}")))?;
+
+        files.push((metadata, staged, efd_sha256sum, filesize));
+    }
+
+    // A single file is stored on its own, just like it always has been.
+    if files.len() == 1 {
+        let (metadata, mut staged, efd_sha256sum, filesize) = files
+            .pop()
+            .ok_or_else(|| AppError::new500("illegal unwrap"))?;
+
+        let (admin_key, admin_key_sha256sum) = generate_admin_key();
+
+        store_upload(
+            aps,
+            eip,
+            &metadata,
+            &staged.path,
+            &efd_sha256sum,
+            StoreAs::File {
+                admin_key_sha256sum: &admin_key_sha256sum,
+            },
+            filesize,
+            None,
+        )
+        .await?;
+        staged.persisted = true;
+
+        return Ok(UploadFileResponse {
+            efd_sha256sum,
+            admin_key,
+        });
+    }
+
+    // Otherwise, create a bundle and store every file as one of its members.
+    // All files share the bundle's expiry, download limit and passphrase, so the first one's are used.
+    let (mut bundle, admin_key) = bundle::create_bundle(
+        aps,
+        eip,
+        files[0].0.expiry,
+        files[0].0.max_downloads,
+        files[0].0.passphrase,
+    )
+    .await?;
+
+    for (metadata, mut staged, efd_sha256sum, filesize) in files {
+        if let Err(e) = store_upload(
+            aps,
+            eip,
+            &metadata,
+            &staged.path,
+            &efd_sha256sum,
+            StoreAs::BundleMember(&bundle),
+            filesize,
+            None,
+        )
+        .await
+        {
+            // Don't leave a half-finished bundle behind, but report why the upload failed.
+            if let Err(cleanup_error) = delete::cleanup_file(&bundle.efd_sha256sum, aps).await {
+                tracing::error!(
+                    efd_sha256sum = bundle.efd_sha256sum,
+                    "failed to remove half-finished bundle: {cleanup_error}"
+                );
+            }
+            return Err(e);
+        }
+        staged.persisted = true;
+        bundle.filesize += filesize as i64;
+    }
+
+    Ok(UploadFileResponse {
+        efd_sha256sum: bundle.efd_sha256sum,
+        admin_key,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UploadFileResponse {
+    /// Identifies the file (or bundle) in its download link
+    pub efd_sha256sum: String,
+    /// Secret granting control over the file, which the server only stores the hash of
+    pub admin_key: String,
+}
+
+/// Validated metadata accompanying every upload
+///
+/// Regardless of whether a file is uploaded in one go through the upload_endpoint
+/// or resumably through the tus-endpoints, this is what ends up in the database.
+#[derive(Debug)]
+pub struct UploadMetadata {
+    pub e_filename: Vec<u8>,
+    pub iv_fd: [u8; 12],
+    pub iv_fn: [u8; 12],
+    pub expiry: Expiry,
+    pub format_version: i64,
+    pub max_downloads: Option<i64>,
+    pub passphrase: Option<PassphraseKdf>,
+}
+
+impl UploadMetadata {
+    /// Check that a file of the given (encrypted) size may be stored with this metadata.
+    pub fn check_filesize(&self, filesize: u64) -> Result<(), AppError> {
+        // Files encrypted in one piece can't be decrypted by the WebCrypto-API beyond 2GiB.
+        if self.format_version == 1 && filesize > WEBCRYPTO_MAX_FILESIZE {
+            return AppError::err(
+                StatusCode::BAD_REQUEST,
+                "files larger than 2GiB must use the segmented format",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Collects the individual metadata fields of an upload and validates them.
+#[derive(Debug, Default)]
+pub struct UploadMetadataBuilder {
+    e_filename: Option<Vec<u8>>,
+    iv_fd: Option<[u8; 12]>,
+    iv_fn: Option<[u8; 12]>,
+    duration: Option<TimeDelta>,
+    expiry_ts: Option<DateTime<Utc>>,
+    format_version: Option<i64>,
+    max_downloads: Option<i64>,
+    kdf_salt: Option<[u8; 16]>,
+    kdf_iterations: Option<i64>,
+}
+
+impl UploadMetadataBuilder {
+    /// Whether a field applies to all files of an upload rather than to a single one.
+    pub fn is_shared_field(field_name: &str) -> bool {
+        matches!(
+            field_name,
+            "duration"
+                | "expiry_ts"
+                | "format_version"
+                | "max_downloads"
+                | "kdf_salt"
+                | "kdf_iterations"
+        )
+    }
+
+    /// Validate and set a single metadata field by its name.
+    pub fn set(&mut self, field_name: &str, field_data: &[u8]) -> Result<(), AppError> {
+        match field_name {
             "e_filename" => {
                 if field_data.len() > 8192 {
                     return AppError::err(
@@ -120,65 +371,245 @@ pub async fn upload_endpoint(
                         "encrypted filename is too large (larger than 8KiB)",
                     );
                 }
-                e_filename = Some(Vec::from(field_data));
-            }
-            "e_filedata" => {
-                if field_data.len() as u64 > aps.conf.maximum_filesize {
-                    return AppError::err(StatusCode::BAD_REQUEST, "encrypted file is too large");
-                }
-                e_filedata = Some(Vec::from(field_data));
+                self.e_filename = Some(Vec::from(field_data));
             }
             "iv_fd" => {
-                if field_data.len() != 12 {
-                    return AppError::err(
+                self.iv_fd = Some(field_data.try_into().map_err(|_| {
+                    AppError::new(
                         StatusCode::BAD_REQUEST,
                         "iv_fd is not exactly 12 bytes long",
-                    );
-                }
-                iv_fd = Some(Vec::from(field_data).try_into().unwrap());
+                    )
+                })?);
             }
             "iv_fn" => {
-                if field_data.len() != 12 {
-                    return AppError::err(
+                self.iv_fn = Some(field_data.try_into().map_err(|_| {
+                    AppError::new(
                         StatusCode::BAD_REQUEST,
                         "iv_fn is not exactly 12 bytes long",
-                    );
-                }
-                iv_fn = Some(Vec::from(field_data).try_into().unwrap());
+                    )
+                })?);
             }
             "duration" => {
-                let s = std::str::from_utf8(&field_data).map_err(|_| {
-                    AppError::new(StatusCode::BAD_REQUEST, "invalid duration parameter")
-                })?;
-                hour_duration = match s {
-                    "hour" => Some(1),
-                    "day" => Some(24),
-                    "week" => Some(24 * 7),
-                    _ => None,
+                self.duration = Some(parse_duration(field_data)?);
+            }
+            "expiry_ts" => {
+                self.expiry_ts = Some(parse_expiry_ts(field_data)?);
+            }
+            "format_version" => {
+                self.format_version = match field_data {
+                    b"1" => Some(1),
+                    b"2" => Some(2),
+                    _ => {
+                        return AppError::err(StatusCode::BAD_REQUEST, "unsupported format_version")
+                    }
                 };
             }
+            "max_downloads" => {
+                let max_downloads = std::str::from_utf8(field_data)
+                    .ok()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        AppError::new(StatusCode::BAD_REQUEST, "invalid max_downloads parameter")
+                    })?;
+                self.max_downloads = Some(check_max_downloads(max_downloads)?);
+            }
+            "kdf_salt" => {
+                self.kdf_salt = Some(parse_kdf_salt(field_data)?);
+            }
+            "kdf_iterations" => {
+                let kdf_iterations = std::str::from_utf8(field_data)
+                    .ok()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        AppError::new(StatusCode::BAD_REQUEST, "invalid kdf_iterations parameter")
+                    })?;
+                self.kdf_iterations = Some(check_kdf_iterations(kdf_iterations)?);
+            }
             _ => {
                 return AppError::err(StatusCode::BAD_REQUEST, "illegal form field during upload");
             }
         }
+        Ok(())
     }
 
-    let e_filename = e_filename
-        .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no encrypted filename provided"))?;
-    let e_filedata = e_filedata
-        .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no encrypted filedata provided"))?;
-    let iv_fd = iv_fd.ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no iv_fd provided"))?;
-    let iv_fn = iv_fn.ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no iv_fn provided"))?;
-    let hour_duration = hour_duration
-        .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no duration provided"))?;
-    let filesize = e_filedata.len() as i64;
-    let upload_ip = eip.to_string();
+    /// Ensure all required fields are present and assemble the final UploadMetadata.
+    pub fn build(self) -> Result<UploadMetadata, AppError> {
+        Ok(UploadMetadata {
+            e_filename: self.e_filename.ok_or_else(|| {
+                AppError::new(StatusCode::BAD_REQUEST, "no encrypted filename provided")
+            })?,
+            iv_fd: self
+                .iv_fd
+                .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no iv_fd provided"))?,
+            iv_fn: self
+                .iv_fn
+                .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "no iv_fn provided"))?,
+            expiry: Expiry::from_fields(self.duration, self.expiry_ts)?,
+            // Clients that predate the segmented format don't send a format_version.
+            format_version: self.format_version.unwrap_or(1),
+            // Without a limit, files can be downloaded any number of times.
+            max_downloads: self.max_downloads,
+            passphrase: PassphraseKdf::from_fields(self.kdf_salt, self.kdf_iterations)?,
+        })
+    }
+}
 
-    // Compute the sha256sum of the encrypted data.
-    // Likelihood of collision is ridiculously small, so we can ignore it here.
-    // We'll use its base64url-encoding as the URL to identify the file.
-    let efd_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(&e_filedata));
+/// When an upload expires, as requested by the client
+#[derive(Debug, Clone, Copy)]
+pub enum Expiry {
+    /// A duration counted from the moment the upload completes
+    After(TimeDelta),
+    /// An exact point in time
+    At(DateTime<Utc>),
+}
 
+impl Expiry {
+    /// Combine the duration and expiry_ts parameters, exactly one of which has to be present.
+    pub fn from_fields(
+        duration: Option<TimeDelta>,
+        expiry_ts: Option<DateTime<Utc>>,
+    ) -> Result<Self, AppError> {
+        match (duration, expiry_ts) {
+            (Some(duration), None) => Ok(Expiry::After(duration)),
+            (None, Some(expiry_ts)) => Ok(Expiry::At(expiry_ts)),
+            (None, None) => AppError::err(StatusCode::BAD_REQUEST, "no duration provided"),
+            (Some(_), Some(_)) => AppError::err(
+                StatusCode::BAD_REQUEST,
+                "provide either a duration or an expiry_ts, not both",
+            ),
+        }
+    }
+
+    /// Ensure the configuration permits this expiry.
+    ///
+    /// Durations have to be one of the configured expiry options, while exact timestamps
+    /// have to be enabled explicitly and may lie no further in the future than the maximum.
+    pub fn check(&self, conf: &AppConfiguration) -> Result<(), AppError> {
+        match self {
+            Expiry::After(duration) => {
+                if !conf.expiry_durations().contains(duration) {
+                    return AppError::err(
+                        StatusCode::BAD_REQUEST,
+                        "duration is not one of the available expiry options",
+                    );
+                }
+            }
+            Expiry::At(expiry_ts) => {
+                if !conf.allow_custom_expiry {
+                    return AppError::err(
+                        StatusCode::BAD_REQUEST,
+                        "exact expiry timestamps are not enabled on this server",
+                    );
+                }
+                let remaining = expiry_ts.signed_duration_since(Utc::now());
+                if remaining <= TimeDelta::zero() {
+                    return AppError::err(StatusCode::BAD_REQUEST, "expiry_ts lies in the past");
+                }
+                if remaining > conf.maximum_expiry_duration() {
+                    return AppError::err(
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "expiry_ts lies further in the future than the maximum of {}",
+                            config::format_duration_input(&conf.maximum_expiry)
+                        ),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the duration parameter of an upload, e.g. '10m', '3d' or '2w'.
+///
+/// Older clients send 'hour', 'day' or 'week', which are still understood.
+pub fn parse_duration(field_data: &[u8]) -> Result<TimeDelta, AppError> {
+    let duration = match field_data {
+        b"hour" => Some(TimeDelta::hours(1)),
+        b"day" => Some(TimeDelta::days(1)),
+        b"week" => Some(TimeDelta::weeks(1)),
+        _ => std::str::from_utf8(field_data)
+            .ok()
+            .and_then(config::transform_duration_input),
+    };
+    duration.ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "invalid duration parameter"))
+}
+
+/// Parse the expiry_ts parameter of an upload, an RFC3339 timestamp.
+pub fn parse_expiry_ts(field_data: &[u8]) -> Result<DateTime<Utc>, AppError> {
+    std::str::from_utf8(field_data)
+        .ok()
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .map(|v| v.to_utc().round_subsecs(0))
+        .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "invalid expiry_ts parameter"))
+}
+
+/// Ensure a download limit is sensible, i.e. allows for at least one download.
+pub fn check_max_downloads(max_downloads: i64) -> Result<i64, AppError> {
+    if max_downloads < 1 {
+        return AppError::err(StatusCode::BAD_REQUEST, "max_downloads must be at least 1");
+    }
+    Ok(max_downloads)
+}
+
+/// Fewest PBKDF2 iterations a passphrase may be derived with, keeping brute-force attacks costly
+pub const MIN_KDF_ITERATIONS: i64 = 100_000;
+
+/// Most PBKDF2 iterations a passphrase may be derived with, keeping downloads responsive
+pub const MAX_KDF_ITERATIONS: i64 = 10_000_000;
+
+/// Parameters for deriving the key that wraps a file key from its passphrase
+///
+/// The passphrase itself never reaches the server. Clients wrap the file key with a key derived
+/// through PBKDF2-HMAC-SHA256 and put the result into the link instead of the file key.
+#[derive(Debug, Clone, Copy)]
+pub struct PassphraseKdf {
+    pub salt: [u8; 16],
+    pub iterations: i64,
+}
+
+impl PassphraseKdf {
+    /// Combine the kdf_salt and kdf_iterations parameters, which are only valid together.
+    pub fn from_fields(
+        salt: Option<[u8; 16]>,
+        iterations: Option<i64>,
+    ) -> Result<Option<Self>, AppError> {
+        match (salt, iterations) {
+            (Some(salt), Some(iterations)) => Ok(Some(PassphraseKdf { salt, iterations })),
+            (None, None) => Ok(None),
+            _ => AppError::err(
+                StatusCode::BAD_REQUEST,
+                "kdf_salt and kdf_iterations must be provided together",
+            ),
+        }
+    }
+}
+
+/// Parse the kdf_salt parameter of an upload, exactly 16 random bytes.
+pub fn parse_kdf_salt(field_data: &[u8]) -> Result<[u8; 16], AppError> {
+    field_data.try_into().map_err(|_| {
+        AppError::new(
+            StatusCode::BAD_REQUEST,
+            "kdf_salt is not exactly 16 bytes long",
+        )
+    })
+}
+
+/// Ensure a number of PBKDF2 iterations lies in the permitted range.
+pub fn check_kdf_iterations(iterations: i64) -> Result<i64, AppError> {
+    if !(MIN_KDF_ITERATIONS..=MAX_KDF_ITERATIONS).contains(&iterations) {
+        return AppError::err(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "kdf_iterations must lie between {MIN_KDF_ITERATIONS} and {MAX_KDF_ITERATIONS}"
+            ),
+        );
+    }
+    Ok(iterations)
+}
+
+/// Generate a new admin key for a file and return it alongside its base64url-encoded sha256sum.
+pub fn generate_admin_key() -> (String, String) {
     // Generate a random admin password out of 256 bits of strong entropy.
     let admin_key_bytes = rng().random::<[u8; 32]>();
     let admin_key = URL_SAFE_NO_PAD.encode(admin_key_bytes);
@@ -202,82 +633,271 @@ pub async fn upload_endpoint(
     // as a single sha256-digest can be computed very quickly.
     let admin_key_sha256sum = URL_SAFE_NO_PAD.encode(Sha256::digest(admin_key_bytes));
 
+    (admin_key, admin_key_sha256sum)
+}
+
+/// Check whether the given IpPrefix may start another upload.
+///
+/// Fails if the IpPrefix has hit its upload limit or if the server has hit its quota.
+/// Upload sessions that have not yet completed count towards both limits.
+pub async fn check_upload_limits(aps: &AppState, eip: &IpPrefix) -> Result<(), AppError> {
+    // Find out how many files this user has already uploaded or is in the process of uploading.
+    // Bundles count as a single upload, so their members are left out.
+    let uploads_by_eip: i64 = sqlx::query_scalar(
+        "SELECT (SELECT COUNT(*) FROM uploaded_files WHERE upload_ip = ? AND parent_id IS NULL) + (SELECT COUNT(*) FROM upload_sessions WHERE upload_ip = ? AND efd_sha256sum IS NULL AND parent_id IS NULL);",
+    )
+    .bind(eip.to_string())
+    .bind(eip.to_string())
+    .fetch_one(&aps.db)
+    .await?;
+
+    // Check if the user has hit their upload limit.
+    if uploads_by_eip as u64 >= aps.conf().maximum_uploads_per_ip {
+        return AppError::err(StatusCode::TOO_MANY_REQUESTS, "your computer has reached the file upload limit; delete old files or wait for them to expire");
+    }
+
+    // Check if the server has hit its quota limits.
+    if maximum_quota_reached(aps).await? {
+        return AppError::err(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "server has reached maximum storage capacity; please try again later",
+        );
+    }
+
+    Ok(())
+}
+
+/// How a fully uploaded file is to be stored
+pub enum StoreAs<'a> {
+    /// A file of its own, managed with the admin key corresponding to the given hash
+    File { admin_key_sha256sum: &'a str },
+    /// A member of the given bundle, which it shares its expiry and admin key with
+    BundleMember(&'a Bundle),
+}
+
+/// Compute the upload and expiry timestamps of a new upload with the given expiry.
+pub fn upload_and_expiry_ts(aps: &AppState, expiry: Expiry) -> Result<(String, String), AppError> {
     // Grab the current time.
     let now = Utc::now().round_subsecs(0);
 
     // Generate the rfc3339 timestamps from this.
     let upload_ts = now.to_rfc3339();
-    let expiry_ts = now
-        .checked_add_signed(if aps.conf.demo_mode {
-            // If demo mode is enabled, all expiry timestamps are set to 15 minutes.
-            TimeDelta::minutes(15)
-        } else {
-            TimeDelta::hours(hour_duration)
-        })
-        .ok_or_else(|| AppError::new500("failed to apply duration to current timestamp"))?
-        .to_rfc3339();
+    let expiry_ts = match expiry {
+        // If demo mode is enabled, all expiry timestamps are set to 15 minutes.
+        _ if aps.conf().demo_mode => now.checked_add_signed(TimeDelta::minutes(15)),
+        Expiry::After(duration) => now.checked_add_signed(duration),
+        Expiry::At(expiry_ts) => Some(expiry_ts),
+    }
+    .ok_or_else(|| AppError::new500("failed to apply duration to current timestamp"))?
+    .to_rfc3339();
 
-    // Store the file using asynchronous IO.
-    tokio::fs::File::create(format!("{DATA_PATH}/uploaded_files/{efd_sha256sum}"))
-        .await
-        .map_err(|e| AppError::new500(format!("failed to create file on disk: {e}")))?
-        .write_all(&e_filedata)
-        .await
-        .map_err(|e| {
-            AppError::new500(format!("failed to write encrypted filedata to disk: {e}"))
-        })?;
+    Ok((upload_ts, expiry_ts))
+}
 
-    // Then, add the row to the database.
-    sqlx::query("INSERT INTO uploaded_files (efd_sha256sum, admin_key_sha256sum, e_filename, iv_fd, iv_fn, filesize, upload_ip, upload_ts, expiry_ts) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);")
-        .bind(&efd_sha256sum)
-        .bind(&admin_key_sha256sum)
-        .bind(&e_filename)
-        .bind(&iv_fd[..])
-        .bind(&iv_fn[..])
+/// Store a fully uploaded file, both in the database and in the storage backend.
+///
+/// The encrypted filedata at `staged_path` is handed to the storage backend once the database
+/// row has been committed, which consumes the file at `staged_path`.
+/// Files uploaded through a resumable upload session pass its `upload_id_sha256sum`,
+/// so that the session learns the file's hash in the same transaction that adds the row.
+#[allow(clippy::too_many_arguments)]
+pub async fn store_upload(
+    aps: &AppState,
+    eip: &IpPrefix,
+    metadata: &UploadMetadata,
+    staged_path: &Path,
+    efd_sha256sum: &str,
+    store_as: StoreAs<'_>,
+    filesize: u64,
+    upload_id_sha256sum: Option<&str>,
+) -> Result<(), AppError> {
+    let filesize = filesize as i64;
+    let upload_ip = eip.to_string();
+    let format_version = metadata.format_version;
+
+    // Members take on the expiry and download limit of their bundle, and are managed through it.
+    // Their bundle's passphrase covers them as well, so they don't store one of their own.
+    let (upload_ts, expiry_ts, admin_key_sha256sum, parent_id, max_downloads, passphrase) =
+        match store_as {
+            StoreAs::File {
+                admin_key_sha256sum,
+            } => {
+                let (upload_ts, expiry_ts) = upload_and_expiry_ts(aps, metadata.expiry)?;
+                (
+                    upload_ts,
+                    expiry_ts,
+                    Some(admin_key_sha256sum),
+                    None,
+                    metadata.max_downloads,
+                    metadata.passphrase,
+                )
+            }
+            StoreAs::BundleMember(bundle) => (
+                Utc::now().round_subsecs(0).to_rfc3339(),
+                bundle.expiry_ts.clone(),
+                None,
+                Some(bundle.id),
+                bundle.max_downloads,
+                None,
+            ),
+        };
+
+    // Add the row to the database.
+    let mut tx = aps.db.begin().await?;
+    sqlx::query("INSERT INTO uploaded_files (efd_sha256sum, admin_key_sha256sum, e_filename, iv_fd, iv_fn, filesize, upload_ip, upload_ts, expiry_ts, format_version, parent_id, max_downloads, kdf_salt, kdf_iterations) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
+        .bind(efd_sha256sum)
+        .bind(admin_key_sha256sum)
+        .bind(&metadata.e_filename)
+        .bind(&metadata.iv_fd[..])
+        .bind(&metadata.iv_fn[..])
         .bind(filesize)
         .bind(&upload_ip)
         .bind(&upload_ts)
         .bind(&expiry_ts)
-        .execute(&aps.db)
+        .bind(format_version)
+        .bind(parent_id)
+        .bind(max_downloads)
+        .bind(passphrase.map(|v| v.salt.to_vec()))
+        .bind(passphrase.map(|v| v.iterations))
+        .execute(&mut *tx)
         .await
-        .map_err(|e| AppError::new500(format!("failed to insert row into database: {e}")))?;
-
-    tracing::info!(
-        efd_sha256sum,
-        filesize,
-        hour_duration,
-        "succesfully created new file"
-    );
-
-    Ok((
-        StatusCode::CREATED,
-        Json(UploadFileResponse {
+        .map_err(|e| match e {
+            // Identical ciphertext can only come from a client reusing its key and IVs.
+            // Its filedata would have to be shared with the existing file, so refuse it.
+            sqlx::Error::Database(e) if e.is_unique_violation() => AppError::new(
+                StatusCode::CONFLICT,
+                "an identical encrypted file has already been uploaded",
+            ),
+            e => AppError::new500(format!("failed to insert row into database: {e}")),
+        })?;
+    if let Some(upload_id_sha256sum) = upload_id_sha256sum {
+        sqlx::query("UPDATE upload_sessions SET efd_sha256sum = ? WHERE upload_id_sha256sum = ?;")
+            .bind(efd_sha256sum)
+            .bind(upload_id_sha256sum)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    // With the row committed, move the staged file to its final location.
+    // Should that fail, undo both changes so that the database doesn't reference a ghost.
+    if let Err(e) = aps.storage.put_file(efd_sha256sum, staged_path).await {
+        let mut tx = aps.db.begin().await?;
+        sqlx::query("DELETE FROM uploaded_files WHERE efd_sha256sum = ?;")
+            .bind(efd_sha256sum)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(upload_id_sha256sum) = upload_id_sha256sum {
+            sqlx::query(
+                "UPDATE upload_sessions SET efd_sha256sum = NULL WHERE upload_id_sha256sum = ?;",
+            )
+            .bind(upload_id_sha256sum)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        return Err(AppError::new500(format!(
+            "failed to move encrypted filedata into place: {e}"
+        )));
+    }
+
+    metrics::METRICS.count_upload();
+
+    if let Some(parent_id) = parent_id {
+        // The bundle's filesize is the sum of its members', which keeps the quota accurate.
+        sqlx::query("UPDATE uploaded_files SET filesize = filesize + ? WHERE id = ?;")
+            .bind(filesize)
+            .bind(parent_id)
+            .execute(&aps.db)
+            .await?;
+
+        tracing::info!(
             efd_sha256sum,
-            admin_key,
-        }),
-    ))
+            filesize,
+            parent_id,
+            format_version,
+            "succesfully added new file to bundle"
+        );
+    } else {
+        tracing::info!(
+            efd_sha256sum,
+            filesize,
+            expiry_ts,
+            format_version,
+            max_downloads,
+            passphrase = passphrase.is_some(),
+            "succesfully created new file"
+        );
+        audit::record_or_log(
+            &aps.db,
+            AuditEvent::FileUploaded,
+            Actor::Uploader,
+            Some(efd_sha256sum),
+            Some(&format!("{filesize} bytes, expires {expiry_ts}")),
+        )
+        .await;
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Serialize)]
-pub struct UploadFileResponse {
-    efd_sha256sum: String,
-    admin_key: String,
+/// Prefix of the temporary files in 'uploaded_files/' that uploads are streamed into
+///
+/// Proper file names are base64url-encoded hashes and can never start with a dot,
+/// so there is no risk of a temporary file colliding with an uploaded one.
+pub const STAGED_FILE_PREFIX: &str = ".staged-";
+
+/// Temporary file that an upload is streamed into before it is moved to its final location
+///
+/// The file is removed again when the StagedFile is dropped without having been persisted,
+/// which takes care of aborted uploads and any errors encountered along the way.
+struct StagedFile {
+    path: PathBuf,
+    file: tokio::fs::File,
+    persisted: bool,
+}
+
+impl StagedFile {
+    /// Create a new, randomly named StagedFile in the 'uploaded_files/' directory.
+    async fn create() -> Result<Self, AppError> {
+        let path = PathBuf::from(format!(
+            "{DATA_PATH}/uploaded_files/{STAGED_FILE_PREFIX}{}",
+            hex::encode(rng().random::<[u8; 16]>())
+        ));
+        let file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| AppError::new500(format!("failed to create file on disk: {e}")))?;
+        Ok(Self {
+            path,
+            file,
+            persisted: false,
+        })
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                tracing::error!("failed to remove staged file {:?}: {e}", self.path);
+            }
+        }
+    }
 }
 
 /// Helper function that checks whether the application has hit its global storage limit.
+///
+/// Space reserved by upload sessions that have not yet completed is counted as used.
 async fn maximum_quota_reached(aps: &AppState) -> Result<bool, AppError> {
     // Determine the total size of files uploaded so far.
-    let total_quota: i64 = sqlx::query_scalar("SELECT SUM(filesize) FROM uploaded_files;")
+    // Bundles already account for the size of all their members.
+    let total_quota: i64 = sqlx::query_scalar("SELECT (SELECT COALESCE(SUM(filesize), 0) FROM uploaded_files WHERE parent_id IS NULL) + (SELECT COALESCE(SUM(upload_length), 0) FROM upload_sessions WHERE efd_sha256sum IS NULL);")
         .fetch_one(&aps.db)
         .await?;
 
     // Check if we've hit the global limit.
     // In order to stay *strictly* underneath the limit, this function returns true
     // if the remaining space on disk is smaller than the biggest possible file.
-    Ok(total_quota as u64
-        >= aps
-            .conf
-            .maximum_quota
-            .checked_sub(aps.conf.maximum_filesize)
-            .unwrap_or_default())
+    let conf = aps.conf();
+    Ok(total_quota as u64 >= conf.maximum_quota.saturating_sub(conf.maximum_filesize))
 }
@@ -20,6 +20,19 @@ function b64u_decBytes(base64) {
   return Uint8Array.from(binString, (m) => m.codePointAt(0));
 }
 
+// Encode Uint8Array to regular, padded base64-string.
+function b64_encBytes(bytes) {
+  const binString = Array.from(bytes, (byte) =>
+    String.fromCodePoint(byte),
+  ).join("");
+  return btoa(binString);
+}
+
+// Encode JS-string to regular, padded base64-string (via Uint8Array and TextEncoder).
+function b64_encString(str) {
+  return b64_encBytes(new TextEncoder().encode(str));
+}
+
 // Encode JS-string to base64url-string (via Uint8Array and TextEncoder) and truncate the padding.
 function b64u_encString(str) {
   return b64u_encBytes(new TextEncoder().encode(str));
@@ -30,6 +43,52 @@ function b64u_decString(base64) {
   return new TextEncoder().decode(b64u_decBytes(base64));
 }
 
+// Size of the plaintext segments files are split into before encryption (format version 2).
+// Every encrypted segment is 16 bytes larger due to the AES-GCM authentication tag.
+const SEGMENT_SIZE = 4194304;
+
+// Number of segments a plaintext of the given size is split into.
+// Empty files still consist of a single (empty) segment.
+function segmentCount(plaintext_size) {
+  if (plaintext_size === 0) {
+    return 1;
+  }
+  return Math.ceil(plaintext_size / SEGMENT_SIZE);
+}
+
+// Derive the IV of a single filedata segment from the file's base IV.
+// Bytes 0-2 are copied from the base IV, bytes 3-10 hold the segment index (big-endian)
+// and byte 11 marks the final segment. Binding index and final flag to the IV ensures that
+// segments cannot be reordered and that the ciphertext cannot be truncated unnoticed.
+function segmentIv(base_iv, index, final) {
+  let iv = new Uint8Array(12);
+  iv.set(base_iv.subarray(0, 3));
+  new DataView(iv.buffer).setBigUint64(3, BigInt(index));
+  iv[11] = final ? 1 : 0;
+  return iv;
+}
+
+// Derive the key that wraps a file key from a passphrase using PBKDF2-HMAC-SHA256.
+// The wrapped file key is an AES-GCM encryption of the raw file key under this key.
+// Its IV starts with 3, keeping it apart from the IVs of filenames (0) and filedata (1).
+async function passphraseKey(passphrase, salt, iterations) {
+  const key_material = await window.crypto.subtle.importKey(
+    "raw",
+    new TextEncoder().encode(passphrase),
+    "PBKDF2",
+    false,
+    ["deriveKey"]
+  );
+  const wrapping_key = await window.crypto.subtle.deriveKey(
+    { name: "PBKDF2", hash: "SHA-256", salt: salt, iterations: iterations },
+    key_material,
+    { name: "AES-GCM", length: 256 },
+    false,
+    ["encrypt", "decrypt"]
+  );
+  return { key: wrapping_key, iv: new Uint8Array([3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]) };
+}
+
 // Updating the infobox is the same for both download and upload pages.
 function updateInfoBox(type, message) {
   let ib = document.getElementById("infobox");
@@ -78,3 +137,6 @@ function updateInfoBox(type, message) {
   // And copy over the message.
   ibText.textContent = message;
 }
+
+// Token that has to accompany every state-changing request, see 'csrf.rs'.
+const CSRF_TOKEN = document.querySelector('meta[name="csrf-token"]').content;
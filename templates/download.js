@@ -29,106 +29,402 @@ document.addEventListener('DOMContentLoaded', async (_) => {
       return;
     }
 
-    // Construct the AES key, if possible.
-    try {
-      key = await window.crypto.subtle.importKey(
-        "raw",
-        key_bytes,
-        "AES-GCM",
-        true,
-        ["encrypt", "decrypt"]
-      )
-    } catch (e) {
-      displayError('Invalid key', 'Cannot construct decryption key because it is corrupt or missing. This makes decrypting the file and filename impossible.');
+    // Passphrase-protected links hold a wrapped key instead of the actual one.
+    // Unwrapping it has to wait until the user has entered the passphrase.
+    if (passphrase_kdf !== null) {
+      wrapped_key_bytes = key_bytes;
+      document.getElementById("passphrase-form").style.display = "flex";
+      document.getElementById("passphrase-input").focus();
       return;
     }
 
-    // Now try decrypting the filename and put it in the document.
-    try {
+    await unlockFiles(key_bytes);
+  }
+});
+
+// Import the file key and decrypt the filenames with it.
+// If that works out, the files are displayed and can be downloaded.
+async function unlockFiles(key_bytes) {
+  // Construct the AES key, if possible.
+  try {
+    key = await window.crypto.subtle.importKey(
+      "raw",
+      key_bytes,
+      "AES-GCM",
+      true,
+      ["encrypt", "decrypt"]
+    )
+  } catch (e) {
+    displayError('Invalid key', 'Cannot construct decryption key because it is corrupt or missing. This makes decrypting the file and filename impossible.');
+    return;
+  }
+
+  // Now try decrypting the filenames and put them in the document.
+  try {
+    for (let member of members) {
+      // The template hands over the binary fields as plain arrays.
+      member.e_filename = new Uint8Array(member.e_filename);
+      member.iv_fd = new Uint8Array(member.iv_fd);
+      member.iv_fn = new Uint8Array(member.iv_fn);
+
       let d_filename_bytes = await window.crypto.subtle.decrypt(
         {
           name: "AES-GCM",
-          iv: iv_fn
+          iv: member.iv_fn
         },
         key,
-        e_filename
+        member.e_filename
       );
-      d_filename = new TextDecoder().decode(d_filename_bytes);
-      document.getElementById('dl-filename').textContent = d_filename;
-    } catch (e) {
-      displayError('Could not decrypt filename', 'Your decryption key is probably corrupt.');
-      return;
+      member.d_filename = new TextDecoder().decode(d_filename_bytes);
     }
+  } catch (e) {
+    displayError('Could not decrypt filename', 'Your decryption key is probably corrupt.');
+    return;
+  }
 
-    // Looks like the key is correct. Enable the download button and show the filename.
-    document.getElementById("filename-li").style.visibility = "visible";
-    document.getElementById("download-button").disabled = false;
-
-    // If decryption worked *and* this is an admin page, display the public download link.
-    // We're hiding it if the key is incorrect since a download link with an incorrect
-    // key serves no purpose.
-    if (response_type === 'admin') {
-      document.getElementById("admin-download-box").style.visibility = "visible";
+  if (is_bundle) {
+    document.getElementById('dl-filecount').textContent = members.length.toString();
+    for (let member of members) {
+      addBundleListItem(member);
     }
+  } else {
+    document.getElementById('dl-filename').textContent = members[0].d_filename;
   }
+
+  // Looks like the key is correct. Enable the download buttons and show the filename.
+  document.getElementById("filename-li").style.visibility = "visible";
+  setDownloadButtonsDisabled(false);
+
+  // If decryption worked *and* this is an admin page, display the public download link.
+  // We're hiding it if the key is incorrect since a download link with an incorrect
+  // key serves no purpose.
+  if (response_type === 'admin') {
+    document.getElementById("admin-download-box").style.visibility = "visible";
+  }
+}
+
+document.getElementById("passphrase-form").addEventListener("submit", async (e) => {
+  e.preventDefault();
+  document.getElementById("passphrase-submit").disabled = true;
+  updateInfoBox("inprogress", "Checking passphrase");
+
+  // Derive the wrapping key from the passphrase and use it to unwrap the actual key.
+  // AES-GCM's authentication tag makes sure a wrong passphrase is detected here.
+  let key_bytes;
+  try {
+    const wrapping = await passphraseKey(
+      document.getElementById("passphrase-input").value,
+      new Uint8Array(passphrase_kdf.salt),
+      passphrase_kdf.iterations
+    );
+    key_bytes = await window.crypto.subtle.decrypt(
+      {
+        name: "AES-GCM",
+        iv: wrapping.iv
+      },
+      wrapping.key,
+      wrapped_key_bytes
+    );
+  } catch (e) {
+    updateInfoBox("error", "Wrong passphrase");
+    document.getElementById("passphrase-submit").disabled = false;
+    return;
+  }
+
+  updateInfoBox("invisible");
+  document.getElementById("passphrase-form").style.display = "none";
+  await unlockFiles(key_bytes);
 });
 
-// Set up the handler for the actual download button.
-document.getElementById("download-button").addEventListener("click", (_) => {
-  // I'd love to use fetch for modern posting,
-  // but if we want a regularly updating progress indicator we're stuck with XHR.
-  let xhr = new XMLHttpRequest();
-  xhr.open("GET", `/download_endpoint?hash=${efd_sha256sum}`);
-  // Immediately store the response into an arraybuffer.
-  xhr.responseType = 'arraybuffer';
+// Add a bundle's member to the list of files, with a button to download just that file.
+function addBundleListItem(member) {
+  let li = document.createElement("li");
+  li.className = "flex items-center gap-4 p-2 rounded-lg bg-zinc-200";
+
+  let icon = document.createElement("span");
+  icon.className = "matsym text-zinc-500";
+  icon.setAttribute("aria-hidden", "true");
+  icon.textContent = "draft";
+
+  let details = document.createElement("div");
+  details.className = "flex-1 flex flex-col";
+  let name = document.createElement("span");
+  name.className = "font-system [word-break:break-word]";
+  name.textContent = member.d_filename;
+  member.details = document.createElement("span");
+  member.details.className = "text-sm text-zinc-600";
+  details.append(name, member.details);
+  updateMemberDetails(member);
+
+  let button = document.createElement("button");
+  button.type = "button";
+  button.className = "member-download-button btn-secondary";
+  button.setAttribute("aria-label", `Download ${member.d_filename}`);
+  button.disabled = true;
+  member.button = button;
+  let buttonIcon = document.createElement("span");
+  buttonIcon.className = "matsym";
+  buttonIcon.setAttribute("aria-hidden", "true");
+  buttonIcon.textContent = "download";
+  button.append(buttonIcon);
+  button.addEventListener("click", (_) => downloadMembers([member]));
+
+  li.append(icon, details, button);
+  document.getElementById("dl-bundle-list").append(li);
+}
+
+// Display a bundle member's size and, if it has a download limit, its remaining downloads.
+function updateMemberDetails(member) {
+  let text = (member.filesize / 1048576).toFixed(2) + " MiB";
+  if (member.remaining_downloads !== null) {
+    text += ` · ${member.remaining_downloads} download(s) remaining`;
+  }
+  member.details.textContent = text;
+}
+
+// Files that have reached their download limit have been removed from the server.
+function isExhausted(member) {
+  return member.remaining_downloads === 0;
+}
+
+// Keep track of a successful download of a file that has a download limit.
+function countDownload(member) {
+  if (member.remaining_downloads === null) {
+    return;
+  }
+  member.remaining_downloads--;
+  if (is_bundle) {
+    updateMemberDetails(member);
+  } else {
+    document.getElementById("dl-remaining").textContent = member.remaining_downloads.toString();
+  }
+}
+
+// Enable or disable all download buttons at once, so that only one download runs at a time.
+function setDownloadButtonsDisabled(disabled) {
+  document.getElementById("download-button").disabled = disabled || members.every(isExhausted);
+  for (let member of members) {
+    if (member.button) {
+      member.button.disabled = disabled || isExhausted(member);
+    }
+  }
+}
+
+// Hand the decrypted file over to the browser so that it is actually saved to disk.
+function saveFile(parts, d_filename) {
+  // Assemble the file.
+  let d_file = new File(parts, d_filename);
+
+  // And download it.
+  let link = document.createElement("a");
+  let url = URL.createObjectURL(d_file);
+  link.setAttribute('href', url);
+  link.setAttribute('download', d_file.name);
+  link.click();
+}
+
+// Update the progress bar and infobox while an encrypted file is being downloaded.
+function updateDownloadProgress(loaded, total, prefix) {
+  let progress = (loaded / total) * 100;
+  document.getElementById("infobox-pbar-inner").style.width = progress.toString() + "%";
+  updateInfoBox("inprogress", `${prefix}Downloading ${(loaded / 1000000).toFixed(2)} / ${(total / 1000000).toFixed(2)} MB (${progress.toFixed(0)}%)`);
+}
+
+// Download and decrypt a file that was encrypted in a single operation (format version 1).
+// Resolves to an error message, or null if the file was downloaded successfully.
+function downloadSingle(member, prefix) {
+  return new Promise((resolve) => {
+    // I'd love to use fetch for modern posting,
+    // but if we want a regularly updating progress indicator we're stuck with XHR.
+    let xhr = new XMLHttpRequest();
+    xhr.open("GET", `/download_endpoint?hash=${member.hash}`);
+    // Immediately store the response into an arraybuffer.
+    xhr.responseType = 'arraybuffer';
+
+    xhr.onload = async () => {
+      if (xhr.status == 200) {
+        try {
+          updateInfoBox('inprogress', `${prefix}Decrypting`);
+
+          // Now actually decrypt the file.
+          let d_filedata = await window.crypto.subtle.decrypt(
+            {
+              name: "AES-GCM",
+              iv: member.iv_fd
+            },
+            key,
+            xhr.response
+          );
+
+          saveFile([d_filedata], member.d_filename);
+        } catch (e) {
+          console.log(e);
+          resolve("Could not decrypt file");
+          return;
+        }
 
-  let dlbutton = document.getElementById("download-button");
-  let dlprog_pbar_inner = document.getElementById("infobox-pbar-inner");
+        resolve(null);
+      } else {
+        resolve(new TextDecoder().decode(xhr.response));
+      }
+    }
+
+    xhr.onerror = () => {
+      resolve("Error during file download");
+    }
+
+    xhr.onprogress = (event) => {
+      updateDownloadProgress(event.loaded, member.filesize, prefix);
+    }
+
+    xhr.send();
+  });
+}
+
+// Ask the user where to save a file, if the browser can stream it straight to disk (File System Access API).
+// Resolves to a writable stream, or null if the file has to be assembled in the browser instead.
+// Rejects with an AbortError if the user cancelled the dialog.
+async function openSaveStream(d_filename) {
+  if (!window.showSaveFilePicker) {
+    return null;
+  }
+  try {
+    const handle = await window.showSaveFilePicker({ suggestedName: d_filename });
+    return await handle.createWritable();
+  } catch (e) {
+    if (e.name === "AbortError") {
+      throw e;
+    }
+    // E.g. the dialog can't be shown for the second file of a bundle, as it needs a fresh click.
+    console.log(e);
+    return null;
+  }
+}
 
-  xhr.onload = async () => {
-    if (xhr.status == 200) {
-      try {
-        updateInfoBox('inprogress', "Decrypting");
+// Download and decrypt a file that was encrypted in fixed-size segments (format version 2).
+// Resolves to an error message, or null if the file was downloaded successfully.
+//
+// The response is streamed and every segment is decrypted as soon as it has fully arrived,
+// so only a couple of segments of ciphertext have to be held in memory at any given time.
+// Decrypted segments are written straight to disk where the browser allows it.
+// Otherwise each one is wrapped in its own Blob, which the browser is free to page out.
+async function downloadSegmented(member, prefix) {
+  const e_segment_size = SEGMENT_SIZE + 16;
+  const segment_count = Math.max(1, Math.ceil(member.filesize / e_segment_size));
 
-        // Now actually decrypt the file.
-        d_filedata = await window.crypto.subtle.decrypt(
+  // Ask before starting the download, so that cancelling doesn't use up one of its downloads.
+  let writable;
+  try {
+    writable = await openSaveStream(member.d_filename);
+  } catch (e) {
+    return "Download cancelled";
+  }
+
+  let response;
+  try {
+    response = await fetch(`/download_endpoint?hash=${member.hash}`);
+  } catch (e) {
+    console.log(e);
+    await writable?.abort();
+    return "Error during file download";
+  }
+  if (!response.ok) {
+    await writable?.abort();
+    return await response.text();
+  }
+
+  let reader = response.body.getReader();
+  let buffer = new Uint8Array(0);
+  let loaded = 0;
+  let index = 0;
+  let parts = [];
+
+  try {
+    while (true) {
+      const { done, value } = await reader.read();
+
+      if (value) {
+        // Append the newly received bytes to the ones that are still waiting for decryption.
+        let merged = new Uint8Array(buffer.length + value.length);
+        merged.set(buffer);
+        merged.set(value, buffer.length);
+        buffer = merged;
+        loaded += value.length;
+        updateDownloadProgress(loaded, member.filesize, prefix);
+      }
+
+      // Decrypt every segment that has fully arrived.
+      // Once the stream is done the remaining bytes form the final, possibly shorter, segment.
+      while (buffer.length >= e_segment_size || (done && buffer.length > 0)) {
+        let e_segment = buffer.subarray(0, e_segment_size);
+        let d_segment = await window.crypto.subtle.decrypt(
           {
             name: "AES-GCM",
-            iv: iv_fd
+            iv: segmentIv(member.iv_fd, index, index === segment_count - 1)
           },
           key,
-          xhr.response
+          e_segment
         );
+        if (writable) {
+          await writable.write(d_segment);
+        } else {
+          parts.push(new Blob([d_segment]));
+        }
+        buffer = buffer.slice(e_segment.length);
+        index++;
+      }
 
-        // Assemble the file.
-        let d_file = new File([d_filedata], d_filename);
-
-        // And download it.
-        let link = document.createElement("a");
-        let url = URL.createObjectURL(d_file);
-        link.setAttribute('href', url);
-        link.setAttribute('download', d_file.name);
-        link.click();
-      } catch (e) {
-        console.log(e);
-        updateInfoBox("error", "Could not decrypt file");
+      if (done) {
+        break;
       }
+    }
+
+    // A missing final segment means the download was cut short.
+    if (index !== segment_count) {
+      throw new Error(`expected ${segment_count} segments, but received ${index}`);
+    }
 
-      updateInfoBox('success', "File downloaded");
+    if (writable) {
+      await writable.close();
     } else {
-      updateInfoBox("error", new TextDecoder().decode(xhr.response));
+      saveFile(parts, member.d_filename);
     }
+  } catch (e) {
+    console.log(e);
+    // Don't leave a truncated file behind.
+    await writable?.abort().catch(() => {});
+    return "Could not decrypt file";
   }
 
-  xhr.onprogress = (event) => {
-    let progress = (event.loaded / filesize) * 100;
-    dlprog_pbar_inner.style.width = progress.toString() + "%";
-    updateInfoBox("inprogress", `Downloading ${(event.loaded / 1000000).toFixed(2)} / ${(filesize / 1000000).toFixed(2)} MB (${progress.toFixed(0)}%)`);
+  return null;
+}
+
+// Download and decrypt the given files one after the other.
+async function downloadMembers(selected) {
+  // Disable the download buttons while the operation is ongoing.
+  setDownloadButtonsDisabled(true);
+
+  for (let [i, member] of selected.entries()) {
+    // Let the user know how far along we are when downloading several files.
+    const prefix = selected.length > 1 ? `File ${i + 1} of ${selected.length}: ` : "";
+    const error = member.format_version === 1
+      ? await downloadSingle(member, prefix)
+      : await downloadSegmented(member, prefix);
+    if (error) {
+      updateInfoBox("error", error);
+      setDownloadButtonsDisabled(false);
+      return;
+    }
+    countDownload(member);
   }
 
-  // Disable download button while the operation is ongoing.
-  dlbutton.disabled = true;
-  xhr.send();
+  updateInfoBox('success', selected.length > 1 ? "All files downloaded" : "File downloaded");
+  setDownloadButtonsDisabled(false);
+}
+
+// Set up the handler for the actual download button.
+document.getElementById("download-button").addEventListener("click", (_) => {
+  downloadMembers(members.filter((m) => !isExhausted(m)));
 });
 
 document.getElementById("admin-download-copy").addEventListener("click", (_) => {
@@ -138,11 +434,85 @@ document.getElementById("admin-download-copy").addEventListener("click", (_) =>
   navigator.clipboard.writeText(textbox.value);
 });
 
+// Only shown in admin mode: The uploader may shorten or extend how long the file is kept.
+document.getElementById("expiry-select").addEventListener("change", (event) => {
+  const custom = document.getElementById("expiry-custom");
+  if (custom) {
+    custom.style.display = event.target.value === "custom" ? "block" : "none";
+  }
+});
+
+document.getElementById("expiry-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+
+  // Either send one of the predefined durations or an exact timestamp.
+  let duration = document.getElementById("expiry-select").value;
+  let expiry_ts = null;
+  if (duration === "custom") {
+    const expiry_date = new Date(document.getElementById("expiry-custom").value);
+    if (isNaN(expiry_date.getTime())) {
+      updateInfoBox("error", "Please choose an exact expiry time.");
+      return;
+    }
+    duration = null;
+    expiry_ts = expiry_date.toISOString();
+  }
+
+  const button = document.getElementById("expiry-button");
+  button.disabled = true;
+  try {
+    const response = await fetch("/expiry_endpoint", {
+      method: "POST",
+      headers: { "Content-Type": "application/json", "X-CSRF-Token": CSRF_TOKEN },
+      body: JSON.stringify({ hash: efd_sha256sum, admin: admin_key, duration, expiry_ts }),
+    });
+    if (response.status !== 200) {
+      throw new Error(await response.text());
+    }
+    const result = await response.json();
+    document.getElementById("dl-expiry-pretty").textContent = result.expiry_ts_pretty;
+    document.getElementById("dl-expiry-ts").textContent = result.expiry_ts_formatted;
+    updateInfoBox("success", "The expiry has been changed");
+  } catch (e) {
+    updateInfoBox("error", e.message);
+  } finally {
+    button.disabled = false;
+  }
+});
+
+document.getElementById("report-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+
+  const button = document.getElementById("report-button");
+  button.disabled = true;
+  try {
+    const response = await fetch("/report_endpoint", {
+      method: "POST",
+      headers: { "Content-Type": "application/json", "X-CSRF-Token": CSRF_TOKEN },
+      body: JSON.stringify({
+        hash: efd_sha256sum,
+        category: document.getElementById("report-category").value,
+        description: document.getElementById("report-description").value,
+      }),
+    });
+    if (response.status !== 201) {
+      throw new Error(await response.text());
+    }
+    document.getElementById("report-form").reset();
+    updateInfoBox("success", "Thank you, the report has been sent to the administrators");
+  } catch (e) {
+    updateInfoBox("error", e.message);
+  } finally {
+    button.disabled = false;
+  }
+});
+
 document.getElementById("delete-button").addEventListener("click", () => {
   let xhr = new XMLHttpRequest();
 
   xhr.open('POST', '/delete_endpoint');
   xhr.setRequestHeader('Content-Type', 'application/json');
+  xhr.setRequestHeader('X-CSRF-Token', CSRF_TOKEN);
 
   xhr.onload = () => {
     if (xhr.status === 200) {
@@ -153,12 +523,12 @@ document.getElementById("delete-button").addEventListener("click", () => {
       document.getElementById('dl-box').style.display = 'none';
     } else {
       updateInfoBox('error', xhr.responseText);
-      document.getElementById('download-button').disabled = false;
+      setDownloadButtonsDisabled(false);
       document.getElementById('delete-button').disabled = false;
     }
   }
 
-  document.getElementById('download-button').disabled = true;
+  setDownloadButtonsDisabled(true);
   document.getElementById('delete-button').disabled = true;
 
   xhr.send(JSON.stringify({
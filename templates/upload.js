@@ -1,4 +1,219 @@
-let selected_file;
+let selected_files = [];
+
+// Number of encrypted segments sent to the server in a single PATCH request.
+// Should the connection drop, at most this much data has to be sent again.
+const SEGMENTS_PER_REQUEST = 16;
+
+// How often a failing request is retried before the upload is given up on.
+const MAX_RETRIES = 8;
+
+// Maximum number of files that can be uploaded together as a bundle.
+const MAX_BUNDLE_MEMBERS = 256;
+
+// PBKDF2 iterations for deriving the key that wraps the file key from a passphrase.
+// Follows the current OWASP recommendation for PBKDF2-HMAC-SHA256.
+const KDF_ITERATIONS = 600000;
+
+// Error raised when a request fails in a way that might resolve itself on retry.
+class RetryableError extends Error {}
+
+// Send a single chunk of the encrypted file to the upload session at `upload_url`.
+// Resolves to the new offset and, if the upload has completed, the resulting file hash.
+function patchChunk(upload_url, offset, chunk, upload_length, prefix) {
+  // I'd love to use fetch for modern posting,
+  // but if we want a regularly updating progress indicator we're stuck with XHR.
+  let xhr = new XMLHttpRequest();
+  xhr.open("PATCH", upload_url);
+  xhr.setRequestHeader("Tus-Resumable", "1.0.0");
+  xhr.setRequestHeader("Upload-Offset", offset.toString());
+  xhr.setRequestHeader("Content-Type", "application/offset+octet-stream");
+
+  xhr.upload.onprogress = (event) => {
+    let loaded = offset + event.loaded;
+    let progress = (loaded / upload_length) * 100;
+    document.getElementById("infobox-pbar-inner").style.width = progress.toString() + "%";
+    if (loaded < upload_length) {
+      updateInfoBox("inprogress", `${prefix}Uploading ${(loaded / 1000000).toFixed(2)} / ${(upload_length / 1000000).toFixed(2)} MB (${progress.toFixed(0)}%)`);
+    } else {
+      updateInfoBox("inprogress", `${prefix}Processing`);
+    }
+  }
+
+  return new Promise((resolve, reject) => {
+    xhr.onerror = () => {
+      reject(new RetryableError("Connection lost"));
+    }
+
+    xhr.onload = () => {
+      if (xhr.status === 204) {
+        resolve({
+          offset: parseInt(xhr.getResponseHeader("Upload-Offset")),
+          efd_sha256sum: xhr.getResponseHeader("FerriShare-Efd-Sha256sum"),
+        });
+      } else if ([408, 409, 429, 502, 503, 504].includes(xhr.status)) {
+        reject(new RetryableError(xhr.responseText));
+      } else {
+        reject(new Error(xhr.responseText));
+      }
+    }
+
+    xhr.send(chunk);
+  });
+}
+
+// Ask the server how much of the file it has already received.
+async function fetchOffset(upload_url) {
+  let response;
+  try {
+    response = await fetch(upload_url, {
+      method: "HEAD",
+      headers: { "Tus-Resumable": "1.0.0" },
+    });
+  } catch (e) {
+    throw new RetryableError("Connection lost");
+  }
+  if (!response.ok) {
+    throw new Error("The upload session could not be found anymore");
+  }
+  return {
+    offset: parseInt(response.headers.get("Upload-Offset")),
+    efd_sha256sum: response.headers.get("FerriShare-Efd-Sha256sum"),
+  };
+}
+
+// Encrypt and upload a single file, resolving to its hash and admin key.
+//
+// All files of a bundle are encrypted with the same key, so every file needs IVs of its own.
+// Bytes 1-2 of both base IVs hold the file's index within the bundle for that reason.
+// Files uploaded into a bundle are managed through the bundle's admin key and don't get one.
+async function uploadMember(file, index, key, settings, bundle, prefix) {
+  // Encode the filename. The filedata is read and encrypted segment by segment further below.
+  let encoder = new TextEncoder();
+  let filename = encoder.encode(file.name);
+
+  // Generate deterministic IVs (always exactly 96 bits)
+  // For AES-GCM, deterministic IVs with an atomic counter are recommended over random IVs:
+  // https://crypto.stackexchange.com/a/84359
+  // The filedata's IV only serves as the base from which each segment's IV is derived.
+  let iv_filename = new Uint8Array([0, index >> 8, index & 255, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+  let iv_filedata = new Uint8Array([1, index >> 8, index & 255, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+  // Encrypt the filename.
+  let e_filename = await window.crypto.subtle.encrypt(
+    {
+      name: "AES-GCM",
+      iv: iv_filename
+    },
+    key,
+    filename
+  );
+
+  // The filedata is encrypted in fixed-size segments (format version 2).
+  // This way only a few segments have to be held in memory at any given time
+  // and the WebCrypto-API's limit on the message length no longer applies.
+  const segment_count = segmentCount(file.size);
+  const e_segment_size = SEGMENT_SIZE + 16;
+  const upload_length = file.size + 16 * segment_count;
+
+  // Produce the bytes [start, end) of the encrypted filedata.
+  // Since encryption is deterministic, resuming at any offset yields the very same bytes.
+  async function encryptRange(start, end) {
+    const first = Math.floor(start / e_segment_size);
+    const last = Math.min(segment_count - 1, Math.floor(Math.max(end - 1, 0) / e_segment_size));
+    let e_segments = [];
+    for (let i = first; i <= last; i++) {
+      let segment = await file.slice(i * SEGMENT_SIZE, (i + 1) * SEGMENT_SIZE).arrayBuffer();
+      e_segments.push(await window.crypto.subtle.encrypt(
+        {
+          name: "AES-GCM",
+          iv: segmentIv(iv_filedata, i, i === segment_count - 1)
+        },
+        key,
+        segment
+      ));
+    }
+    return new Blob(e_segments).slice(start - first * e_segment_size, end - first * e_segment_size);
+  }
+
+  // All the metadata that's supposed to go to the server, base64-encoded as required by tus.
+  let upload_metadata = [
+    `e_filename ${b64_encBytes(new Uint8Array(e_filename))}`,
+    `iv_fd ${b64_encBytes(iv_filedata)}`,
+    `iv_fn ${b64_encBytes(iv_filename)}`,
+    `format_version ${b64_encString("2")}`,
+  ];
+  if (settings.duration !== null) {
+    upload_metadata.push(`duration ${b64_encString(settings.duration)}`);
+  } else {
+    upload_metadata.push(`expiry_ts ${b64_encString(settings.expiry_ts)}`);
+  }
+  if (settings.max_downloads !== null) {
+    upload_metadata.push(`max_downloads ${b64_encString(settings.max_downloads.toString())}`);
+  }
+  if (settings.kdf_salt !== null) {
+    upload_metadata.push(
+      `kdf_salt ${b64_encBytes(settings.kdf_salt)}`,
+      `kdf_iterations ${b64_encString(settings.kdf_iterations.toString())}`,
+    );
+  }
+  if (bundle) {
+    upload_metadata.push(
+      `bundle ${b64_encString(bundle.efd_sha256sum)}`,
+      `bundle_admin ${b64_encString(bundle.admin_key)}`,
+    );
+  }
+
+  // Create the resumable upload session.
+  let response;
+  try {
+    response = await fetch("/tus", {
+      method: "POST",
+      headers: {
+        "Tus-Resumable": "1.0.0",
+        "Upload-Length": upload_length.toString(),
+        "Upload-Metadata": upload_metadata.join(","),
+      },
+    });
+  } catch (e) {
+    console.log(e);
+    throw new Error("Error during file upload");
+  }
+  if (response.status !== 201) {
+    throw new Error(await response.text());
+  }
+  const upload_url = response.headers.get("Location");
+  const admin_key = response.headers.get("FerriShare-Admin-Key");
+
+  // Send the encrypted file one chunk at a time until the server reports the upload as complete.
+  // Whenever the connection drops, wait a little, ask the server where to pick up and resume.
+  let offset = 0;
+  let efd_sha256sum = null;
+  let retries = 0;
+  while (!efd_sha256sum) {
+    try {
+      if (retries > 0) {
+        ({ offset, efd_sha256sum } = await fetchOffset(upload_url));
+        if (efd_sha256sum) {
+          break;
+        }
+      }
+      const end = Math.min(upload_length, offset + SEGMENTS_PER_REQUEST * e_segment_size);
+      const chunk = await encryptRange(offset, end);
+      ({ offset, efd_sha256sum } = await patchChunk(upload_url, offset, chunk, upload_length, prefix));
+      retries = 0;
+    } catch (e) {
+      console.log(e);
+      if (!(e instanceof RetryableError) || retries >= MAX_RETRIES) {
+        throw e instanceof RetryableError ? new Error("Error during file upload") : e;
+      }
+      retries++;
+      updateInfoBox("inprogress", `${prefix}Connection problems, retrying (attempt ${retries} of ${MAX_RETRIES})`);
+      await new Promise((r) => setTimeout(r, Math.min(30000, 1000 * 2 ** retries)));
+    }
+  }
+
+  return { efd_sha256sum, admin_key };
+}
 
 async function uploadFile() {
   // Determine the expiry time.
@@ -9,41 +224,58 @@ async function uploadFile() {
 
   let duration = document.querySelector("input[type='radio'][name='expires']:checked").value;
 
-  // Grab the file selected by the user.
-  let formData = new FormData();
+  // Either send one of the predefined durations or an exact timestamp.
+  let expiry_ts = null;
+  if (duration === "custom") {
+    const expiry_date = new Date(document.getElementById("fs-expiry-custom").value);
+    const remaining = expiry_date.getTime() - Date.now();
+    if (isNaN(remaining) || remaining <= 0 || remaining > max_expiry_ms) {
+      updateInfoBox("error", "Please choose an expiry time in the future, but no later than {{ maximum_expiry }} from now.");
+      return;
+    }
+    duration = null;
+    expiry_ts = expiry_date.toISOString();
+  }
 
-  if (!selected_file) {
+  // Determine the download limit, if any.
+  let max_downloads = null;
+  const max_downloads_input = document.getElementById("fs-max-downloads").value.trim();
+  if (max_downloads_input !== "") {
+    max_downloads = parseInt(max_downloads_input);
+    if (!(max_downloads >= 1)) {
+      updateInfoBox("error", "Please choose a download limit of at least 1, or leave it empty.");
+      return;
+    }
+  }
+
+  const settings = {
+    duration: duration,
+    expiry_ts: expiry_ts,
+    max_downloads: max_downloads,
+    kdf_salt: null,
+    kdf_iterations: null,
+  };
+
+  if (selected_files.length === 0) {
     updateInfoBox("error", "No file selected");
     return;
   }
 
   // Disable the form from here on out.
   document.getElementById("fs-expiry-fieldset").disabled = true;
+  document.getElementById("fs-limit-fieldset").disabled = true;
+  document.getElementById("fs-passphrase-fieldset").disabled = true;
   document.getElementById("fs-filebutton").disabled = true;
   document.getElementById("fs-submit").disabled = true;
 
   updateInfoBox("inprogress", "Encrypting");
 
-  // Extract and encode the raw file data and its filename.
-  let encoder = new TextEncoder();
-  let filedata = await selected_file.arrayBuffer();
-  let filename = encoder.encode(selected_file.name);
-
-  let iv_filename;
-  let iv_filedata;
-  let e_filename;
-  let e_filedata;
+  let key;
   let key_b64url;
 
   try {
-    // Generate deterministic IVs (always exactly 96 bits)
-    // For AES-GCM, deterministic IVs with an atomic counter are recommended over random IVs:
-    // https://crypto.stackexchange.com/a/84359
-    iv_filename = new Uint8Array([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
-    iv_filedata = new Uint8Array([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
-
     // Generate a random AES key to use for encryption.
-    let key = await window.crypto.subtle.generateKey(
+    key = await window.crypto.subtle.generateKey(
       {
         name: "AES-GCM",
         length: 256,
@@ -52,92 +284,81 @@ async function uploadFile() {
       ["encrypt", "decrypt"],
     );
 
-    // Encrypt the filename.
-    e_filename = await window.crypto.subtle.encrypt(
-      {
-        name: "AES-GCM",
-        iv: iv_filename
-      },
-      key,
-      filename
-    );
-
-    // Encrypt the filedata.
-    e_filedata = await window.crypto.subtle.encrypt(
-      {
-        name: "AES-GCM",
-        iv: iv_filedata
-      },
-      key,
-      filedata
-    );
-
     // Export the AES-GCM key to base64url.
-    key_b64url = b64u_encBytes(new Uint8Array(
-      await window.crypto.subtle.exportKey("raw", key)
-    ));
-
+    const raw_key = new Uint8Array(await window.crypto.subtle.exportKey("raw", key));
+    const passphrase = document.getElementById("fs-passphrase").value;
+    if (passphrase === "") {
+      key_b64url = b64u_encBytes(raw_key);
+    } else {
+      // With a passphrase, the link only holds the key wrapped with one derived from it.
+      settings.kdf_salt = window.crypto.getRandomValues(new Uint8Array(16));
+      settings.kdf_iterations = KDF_ITERATIONS;
+      const wrapping = await passphraseKey(passphrase, settings.kdf_salt, settings.kdf_iterations);
+      key_b64url = b64u_encBytes(new Uint8Array(await window.crypto.subtle.encrypt(
+        {
+          name: "AES-GCM",
+          iv: wrapping.iv,
+        },
+        wrapping.key,
+        raw_key,
+      )));
+    }
   } catch (e) {
     updateInfoBox("error", "Failed to encrypt file, upload cancelled");
     console.log(e);
     return;
   }
 
-  // Append all the data that's supposed to go to the server.
-  formData.append("e_filedata", new Blob([e_filedata]));
-  formData.append("e_filename", new Blob([e_filename]));
-  formData.append("iv_fd", new Blob([iv_filedata]));
-  formData.append("iv_fn", new Blob([iv_filename]));
-  formData.append("duration", duration);
+  // The share's hash and admin key, either those of the single file or those of the bundle.
+  let result;
 
-  // I'd love to use fetch for modern posting,
-  // but if we want a regularly updating progress indicator we're stuck with XHR.
-  let xhr = new XMLHttpRequest();
-  xhr.open("POST", "/upload_endpoint");
-
-  xhr.onerror = () => {
-    updateInfoBox("error", "Error during file upload")
+  try {
+    if (selected_files.length === 1) {
+      result = await uploadMember(selected_files[0], 0, key, settings, null, "");
+    } else {
+      // Several files are shared as a bundle, which has to be created first.
+      let response = await fetch("/bundle_endpoint", {
+        method: "POST",
+        headers: { "Content-Type": "application/json", "X-CSRF-Token": CSRF_TOKEN },
+        body: JSON.stringify({
+          duration: settings.duration,
+          expiry_ts: settings.expiry_ts,
+          max_downloads: settings.max_downloads,
+          kdf_salt: settings.kdf_salt === null ? null : b64u_encBytes(settings.kdf_salt),
+          kdf_iterations: settings.kdf_iterations,
+        }),
+      });
+      if (response.status !== 201) {
+        throw new Error(await response.text());
+      }
+      result = await response.json();
+
+      for (let [i, file] of selected_files.entries()) {
+        await uploadMember(file, i, key, settings, result, `File ${i + 1} of ${selected_files.length}: `);
+      }
+    }
+  } catch (e) {
+    console.log(e);
+    updateInfoBox("error", e.message);
+    return;
   }
 
-  xhr.onload = () => {
-    if (xhr.status == 201) {
-      // Close the normal display box and transition to the successbox.
-      // document.getElementById("infobox").style.display = 'none';
-      // document.getElementById("successbox").style.display = 'flex';
-      updateInfoBox('success', "Upload successful!");
-
-      let response = JSON.parse(xhr.response);
-
-      // Construct the download and admin links.
-      const dl_link = `${location.protocol}//${location.host}/file?hash=${response.efd_sha256sum}#key=${key_b64url}`;
-      const adm_link = `${location.protocol}//${location.host}/file?hash=${response.efd_sha256sum}&admin=${response.admin_key}#key=${key_b64url}`;
+  updateInfoBox('success', "Upload successful!");
 
-      // Set them up in the result boxes.
-      document.getElementById("fs-success-download-input").value = dl_link;
-      document.getElementById("fs-success-download-link").href = dl_link;
+  // Construct the download and admin links.
+  const dl_link = `${location.protocol}//${location.host}/file?hash=${result.efd_sha256sum}#key=${key_b64url}`;
+  const adm_link = `${location.protocol}//${location.host}/file?hash=${result.efd_sha256sum}&admin=${result.admin_key}#key=${key_b64url}`;
 
-      document.getElementById("fs-success-admin-input").value = adm_link;
-      document.getElementById("fs-success-admin-link").href = adm_link;
-
-      // And make those boxes visible.
-      document.getElementById("fs-success-download-box").style.display = "flex";
-      document.getElementById("fs-success-admin-box").style.display = "flex";
-    } else {
-      updateInfoBox("error", xhr.responseText);
-    }
-  }
+  // Set them up in the result boxes.
+  document.getElementById("fs-success-download-input").value = dl_link;
+  document.getElementById("fs-success-download-link").href = dl_link;
 
-  xhr.upload.onprogress = (event) => {
-    let progress = (event.loaded / event.total) * 100;
-    document.getElementById("infobox-pbar-inner").style.width = progress.toString() + "%";
-    if (event.loaded < event.total) {
-      updateInfoBox("inprogress", `Uploading ${(event.loaded / 1000000).toFixed(2)} / ${(event.total / 1000000).toFixed(2)} MB (${progress.toFixed(0)}%)`);
-    } else {
-      updateInfoBox("inprogress", `Processing`);
-    }
-  }
+  document.getElementById("fs-success-admin-input").value = adm_link;
+  document.getElementById("fs-success-admin-link").href = adm_link;
 
-  xhr.send(formData);
+  // And make those boxes visible.
+  document.getElementById("fs-success-download-box").style.display = "flex";
+  document.getElementById("fs-success-admin-box").style.display = "flex";
 }
 
 document.getElementById("filesubmit").addEventListener("submit", (event) => {
@@ -152,31 +373,60 @@ document.getElementById("fs-filebutton").addEventListener("click", (_) => {
   document.getElementById("fs-file").click();
 });
 
-// Process a selected file (either from the file-dialog or a drag-event).
-function processFile(arg_file) {
+// Process the selected files (either from the file-dialog or a drag-event).
+function processFiles(arg_files) {
   document.getElementById("filesubmit-details").style.display = "flex";
-  document.getElementById("fs-filename").textContent = arg_file.name;
-  document.getElementById("fs-filesize").textContent = (arg_file.size / 1048576).toFixed(2) + " MiB";
-  // Subtract 32 from the maximum filesize here for two reasons:
-  // - The WebCrypto-API appends a 16 byte authentication tag to the ciphertext.
-  //   This could cause files to pass the check here but fail the size check on the backend.
-  // - Near the 2GiB limit filesizes of 2GiB - 16B cause issues while 2GiB - 32B work fine.
-  if (arg_file.size > max_filesize - 32) {
+  if (arg_files.length === 1) {
+    document.getElementById("fs-filename-label").textContent = "Filename";
+    document.getElementById("fs-filename").textContent = arg_files[0].name;
+  } else {
+    document.getElementById("fs-filename-label").textContent = `${arg_files.length} Files`;
+    document.getElementById("fs-filename").textContent = arg_files.map((f) => f.name).join(", ");
+  }
+  const total_size = arg_files.reduce((sum, f) => sum + f.size, 0);
+  document.getElementById("fs-filesize").textContent = (total_size / 1048576).toFixed(2) + " MiB";
+  // The WebCrypto-API appends a 16 byte authentication tag to every encrypted segment.
+  // Account for them here, or files could pass this check but fail the one on the backend.
+  // Several files shared as a bundle have to fit into the maximum filesize together.
+  const e_total_size = arg_files.reduce((sum, f) => sum + f.size + 16 * segmentCount(f.size), 0);
+  let error;
+  if (arg_files.length > MAX_BUNDLE_MEMBERS) {
+    error = `Too many files! At most ${MAX_BUNDLE_MEMBERS} files can be shared at once.`;
+  } else if (e_total_size > max_filesize) {
+    error = arg_files.length === 1
+      ? "File too large! The maximum supported filesize is {{ max_filesize }}. Please choose a smaller file."
+      : "Files too large! Together, they may not exceed {{ max_filesize }}. Please choose fewer or smaller files.";
+  }
+  if (error) {
     document.getElementById('fs-expiry-fieldset').style.display = 'none';
+    document.getElementById('fs-limit-fieldset').style.display = 'none';
+    document.getElementById('fs-passphrase-fieldset').style.display = 'none';
     document.getElementById('fs-submit').style.display = 'none';
-    updateInfoBox('error', "File too large! The maximum supported filesize is {{ max_filesize }}. Please choose a smaller file.");
+    updateInfoBox('error', error);
   } else {
     document.getElementById('fs-expiry-fieldset').style.display = 'block';
+    document.getElementById('fs-limit-fieldset').style.display = 'flex';
+    document.getElementById('fs-passphrase-fieldset').style.display = 'flex';
     document.getElementById('fs-submit').style.display = 'block';
-    selected_file = arg_file;
+    selected_files = arg_files;
     updateInfoBox('invisible');
   }
 }
 
+// Only show the date picker while the exact expiry time is selected.
+for (let radio of document.querySelectorAll("input[type='radio'][name='expires']")) {
+  radio.addEventListener("change", (_) => {
+    const custom_box = document.getElementById("fs-expiry-custom-box");
+    if (custom_box) {
+      custom_box.style.display = radio.value === "custom" ? "flex" : "none";
+    }
+  });
+}
+
 document.getElementById("fs-file").addEventListener("change", (e) => {
-  // Process the selected file if it exists.
-  if (e.target.files[0]) {
-    processFile(e.target.files[0]);
+  // Process the selected files if there are any.
+  if (e.target.files.length > 0) {
+    processFiles(Array.from(e.target.files));
   }
 });
 
@@ -189,9 +439,12 @@ document.querySelector("body").addEventListener("drop", (e) => {
     return;
   }
 
-  // Process the selected file if it exists.
-  if (e.dataTransfer.items[0] && e.dataTransfer.items[0].kind === "file") {
-    processFile(e.dataTransfer.items[0].getAsFile());
+  // Process the dropped files if there are any.
+  let files = Array.from(e.dataTransfer.items)
+    .filter((item) => item.kind === "file")
+    .map((item) => item.getAsFile());
+  if (files.length > 0) {
+    processFiles(files);
   }
 });
 